@@ -0,0 +1,12 @@
+; edge_draw.ch8t written as assembly: draw a single pixel sprite at 0,5
+; then wait for a key.
+
+SPRITE = 0x400
+
+    LD I, SPRITE
+    ADD V0, 0xFF        ; V0 = 0xFF, the sprite row
+    LD [I], V0          ; store it as the sprite
+    LD V0, 0
+    LD V1, 5
+    DRW V0, V1, 1
+    LD V0, K
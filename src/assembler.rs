@@ -0,0 +1,858 @@
+//! Two pass assembler for CHIP-8 sources, in the syntax `Instruction`'s
+//! `Display` writes and the disassembler lists.
+//!
+//! The first pass reads every line, following `include`s, and gives each
+//! statement its address so labels are known. The second pass evaluates the
+//! operands and encodes the statements into an image loaded at 0x200.
+//!
+//! ```text
+//! SPEED = 3               ; constants, also `SPEED equ 3`
+//!     org 0x200
+//! start:
+//!     LD I, sprite
+//!     ADD V0, SPEED * 2
+//!     JP start
+//! sprite:
+//!     db 0x3C, 0b01000010, "AB"
+//!     dw sprite + 1, $    ; $ is the address of the statement
+//!     include "more.asm"
+//! ```
+//!
+//! Mnemonics and the register names are case insensitive, symbols are not.
+//! `I`, `DT`, `ST`, `K`, `F`, `HF`, `B`, `R`, `LONG` and `V0` to `VF` are
+//! reserved. Comments start with `;`.
+
+use crate::instructions::Instruction;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Programs are loaded here and the image starts here.
+pub const ORIGIN: u16 = 0x200;
+
+/// The end of the 64 KiB XO-CHIP address space.
+const MEMORY_END: u32 = 0x10000;
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// How deep constants may refer to other constants, deeper is taken as a cycle.
+const MAX_CONSTANT_DEPTH: usize = 64;
+
+const MNEMONICS: &[&str] = &[
+    "CLS", "RET", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH", "JP", "CALL", "SE", "SNE",
+    "LD", "ADD", "OR", "AND", "XOR", "SUB", "SUBN", "SHR", "SHL", "RND", "DRW", "SKP", "SKNP",
+    "SAVE", "LOAD", "PLANE", "AUDIO", "PITCH",
+];
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+    pub file: String,
+    /// 1 based.
+    pub line: usize,
+    /// 1 based.
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+
+/// An image with where the lines of the main file went, for debuggers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assembled {
+    pub image: Vec<u8>,
+    /// The address of the statement on each line of the main file, `None`
+    /// for lines without code or data.
+    pub lines: Vec<Option<u16>>,
+}
+
+
+/// Assemble the file at `path`, includes are read relative to it.
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, Vec<AsmError>> {
+    assemble_file_with_lines(path).map(|assembled| assembled.image)
+}
+
+/// `assemble_file`, keeping the address of each line.
+pub fn assemble_file_with_lines(path: &Path) -> Result<Assembled, Vec<AsmError>> {
+    let name = path.to_string_lossy().into_owned();
+    let source = fs::read_to_string(path).map_err(|err| vec![AsmError {
+        file: name.clone(),
+        line: 0,
+        column: 0,
+        message: err.to_string(),
+    }])?;
+
+    assemble_with_lines(&name, &source, |path| fs::read_to_string(path))
+}
+
+/// Assemble `source`, calling `load` for each included file.
+pub fn assemble_with<F>(name: &str, source: &str, load: F) -> Result<Vec<u8>, Vec<AsmError>>
+    where F: FnMut(&Path) -> io::Result<String>
+{
+    assemble_with_lines(name, source, load).map(|assembled| assembled.image)
+}
+
+/// `assemble_with`, keeping the address of each line.
+pub fn assemble_with_lines<F>(name: &str, source: &str, mut load: F) -> Result<Assembled, Vec<AsmError>>
+    where F: FnMut(&Path) -> io::Result<String>
+{
+    let mut assembler = Assembler::default();
+    assembler.read(name, source, 0, &mut load);
+
+    if assembler.errors.is_empty() {
+        assembler.emit();
+    }
+
+    if !assembler.errors.is_empty() {
+        return Err(assembler.errors);
+    }
+
+    // the main file is the first one read, includes come after
+    let mut lines = vec![None; source.lines().count()];
+    for statement in assembler.statements.iter().filter(|statement| statement.loc.file == 0) {
+        lines[statement.loc.line - 1] = Some(statement.addr as u16);
+    }
+
+    Ok(Assembled { image: assembler.image, lines })
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Str(Vec<u8>),
+    Punct(&'static str),
+}
+
+#[derive(Clone, Debug)]
+struct Spanned {
+    token: Token,
+    column: usize,
+}
+
+/// An error at a column of the current line.
+type LineError = (usize, String);
+
+const PUNCTUATION: &[&str] = &[
+    "<<", ">>", ",", ":", "(", ")", "[", "]", "+", "-", "*", "/", "%", "&", "|", "^", "~", "=", "$",
+];
+
+fn tokenize(line: &str) -> Result<Vec<Spanned>, LineError> {
+    let bytes = line.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        let column = i + 1;
+
+        if c == ';' {
+            break;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let token = if c.is_ascii_alphabetic() || c == '_' || c == '.' {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_' || bytes[i] == b'.') {
+                i += 1;
+            }
+            Token::Ident(line[start..i].to_string())
+        }
+        else if c.is_ascii_digit() {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            Token::Number(parse_number(&line[start..i]).ok_or_else(|| (column, format!("invalid number '{}'", &line[start..i])))?)
+        }
+        else if c == '"' {
+            i += 1;
+            let mut text = Vec::new();
+            loop {
+                match bytes.get(i) {
+                    None => return Err((column, "unterminated string".to_string())),
+                    Some(b'"') => break,
+                    Some(b'\\') => {
+                        let escaped = match bytes.get(i + 1) {
+                            Some(b'n') => b'\n',
+                            Some(b'0') => 0,
+                            Some(b'\\') => b'\\',
+                            Some(b'"') => b'"',
+                            _ => return Err((i + 1, "unknown escape in string".to_string())),
+                        };
+                        text.push(escaped);
+                        i += 2;
+                    },
+                    Some(byte) => {
+                        text.push(*byte);
+                        i += 1;
+                    },
+                }
+            }
+            i += 1;
+            Token::Str(text)
+        }
+        else {
+            let punct = PUNCTUATION.iter().find(|p| line[i..].starts_with(**p))
+                .ok_or_else(|| (column, format!("unexpected character '{}'", c)))?;
+            i += punct.len();
+            Token::Punct(punct)
+        };
+
+        tokens.push(Spanned { token, column });
+    }
+
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let text = text.replace('_', "");
+    let lower = text.to_ascii_lowercase();
+
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    }
+    else if let Some(bin) = lower.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()
+    }
+    else {
+        lower.parse().ok()
+    }
+}
+
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(i64),
+    Symbol(String, usize),
+    /// `$`, the address of the statement.
+    Here,
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+fn precedence(op: &str) -> Option<u8> {
+    match op {
+        "|" => Some(1),
+        "^" => Some(2),
+        "&" => Some(3),
+        "<<" | ">>" => Some(4),
+        "+" | "-" => Some(5),
+        "*" | "/" | "%" => Some(6),
+        _ => None,
+    }
+}
+
+/// Parse an expression from the whole of `tokens`.
+fn parse_expr(tokens: &[Spanned], end_column: usize) -> Result<Expr, LineError> {
+    let mut pos = 0;
+    let expr = parse_binary(tokens, &mut pos, 1, end_column)?;
+
+    match tokens.get(pos) {
+        Some(extra) => Err((extra.column, "unexpected token after expression".to_string())),
+        None => Ok(expr),
+    }
+}
+
+fn parse_binary(tokens: &[Spanned], pos: &mut usize, min_prec: u8, end_column: usize) -> Result<Expr, LineError> {
+    let mut lhs = parse_unary(tokens, pos, end_column)?;
+
+    while let Some(Spanned { token: Token::Punct(op), .. }) = tokens.get(*pos) {
+        let prec = match precedence(op) {
+            Some(prec) if prec >= min_prec => prec,
+            _ => break,
+        };
+        *pos += 1;
+
+        let rhs = parse_binary(tokens, pos, prec + 1, end_column)?;
+        lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+
+    Ok(lhs)
+}
+
+fn parse_unary(tokens: &[Spanned], pos: &mut usize, end_column: usize) -> Result<Expr, LineError> {
+    let spanned = tokens.get(*pos).ok_or((end_column, "expected a value".to_string()))?;
+    *pos += 1;
+
+    match &spanned.token {
+        Token::Number(n) => Ok(Expr::Number(*n)),
+        Token::Ident(name) => Ok(Expr::Symbol(name.clone(), spanned.column)),
+        Token::Punct("$") => Ok(Expr::Here),
+        Token::Punct(op @ ("-" | "~" | "+")) => Ok(Expr::Unary(op, Box::new(parse_unary(tokens, pos, end_column)?))),
+        Token::Punct("(") => {
+            let expr = parse_binary(tokens, pos, 1, end_column)?;
+            match tokens.get(*pos) {
+                Some(Spanned { token: Token::Punct(")"), .. }) => {
+                    *pos += 1;
+                    Ok(expr)
+                },
+                Some(other) => Err((other.column, "expected ')'".to_string())),
+                None => Err((end_column, "expected ')'".to_string())),
+            }
+        },
+        _ => Err((spanned.column, "expected a value".to_string())),
+    }
+}
+
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Reg(u8),
+    I,
+    /// `[I]`
+    IndirectI,
+    DT,
+    ST,
+    K,
+    F,
+    HF,
+    B,
+    R,
+    /// `LONG expr`, the 16 bit address of `LD I, LONG`.
+    Long(Expr),
+    Value(Expr),
+}
+
+fn parse_operand(tokens: &[Spanned], end_column: usize) -> Result<Operand, LineError> {
+    let first = tokens.first().ok_or((end_column, "missing operand".to_string()))?;
+
+    if let [Spanned { token: Token::Punct("["), .. }, Spanned { token: Token::Ident(name), .. }, Spanned { token: Token::Punct("]"), .. }] = tokens {
+        if name.eq_ignore_ascii_case("i") {
+            return Ok(Operand::IndirectI);
+        }
+    }
+
+    if let Token::Ident(name) = &first.token {
+        if name.eq_ignore_ascii_case("long") {
+            return Ok(Operand::Long(parse_expr(&tokens[1..], end_column)?));
+        }
+
+        if tokens.len() == 1 {
+            let lower = name.to_ascii_lowercase();
+            let reserved = match lower.as_str() {
+                "i" => Some(Operand::I),
+                "dt" => Some(Operand::DT),
+                "st" => Some(Operand::ST),
+                "k" => Some(Operand::K),
+                "f" => Some(Operand::F),
+                "hf" => Some(Operand::HF),
+                "b" => Some(Operand::B),
+                "r" => Some(Operand::R),
+                _ => lower.strip_prefix('v')
+                    .filter(|digit| digit.len() == 1)
+                    .and_then(|digit| u8::from_str_radix(digit, 16).ok())
+                    .map(Operand::Reg),
+            };
+
+            if let Some(operand) = reserved {
+                return Ok(operand);
+            }
+        }
+    }
+
+    Ok(Operand::Value(parse_expr(tokens, end_column)?))
+}
+
+/// Split at top level commas.
+fn split_commas(tokens: &[Spanned]) -> Vec<&[Spanned]> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    tokens.split(|t| t.token == Token::Punct(",")).collect()
+}
+
+
+#[derive(Clone, Debug)]
+enum DataItem {
+    Value(Expr, usize),
+    Str(Vec<u8>),
+}
+
+#[derive(Clone, Debug)]
+enum Item {
+    Instruction { mnemonic: String, operands: Vec<(Operand, usize)> },
+    Bytes(Vec<DataItem>),
+    Words(Vec<(Expr, usize)>),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Loc {
+    file: usize,
+    line: usize,
+    column: usize,
+}
+
+struct Statement {
+    loc: Loc,
+    addr: u32,
+    item: Item,
+}
+
+enum Symbol {
+    Label(u32),
+    /// The expression and the address of the line defining it, for `$`.
+    Const(Expr, u32),
+}
+
+
+#[derive(Default)]
+struct Assembler {
+    files: Vec<String>,
+    statements: Vec<Statement>,
+    symbols: HashMap<String, Symbol>,
+    addr: u32,
+    errors: Vec<AsmError>,
+    image: Vec<u8>,
+}
+
+impl Assembler {
+
+    fn error(&mut self, file: usize, line: usize, (column, message): LineError) {
+        self.errors.push(AsmError { file: self.files[file].clone(), line, column, message });
+    }
+
+    /// First pass over one file.
+    fn read(&mut self, name: &str, source: &str, depth: usize, load: &mut dyn FnMut(&Path) -> io::Result<String>) {
+        if self.files.is_empty() {
+            self.addr = ORIGIN as u32;
+        }
+
+        let file = self.files.len();
+        self.files.push(name.to_string());
+
+        for (i, text) in source.lines().enumerate() {
+            let line = i + 1;
+            if let Err(err) = self.read_line(file, line, text, depth, load) {
+                self.error(file, line, err);
+            }
+        }
+    }
+
+    fn read_line(&mut self, file: usize, line: usize, text: &str, depth: usize, load: &mut dyn FnMut(&Path) -> io::Result<String>) -> Result<(), LineError> {
+        let tokens = tokenize(text)?;
+        let end_column = text.len() + 1;
+        let mut rest = &tokens[..];
+
+        // label:
+        if let [Spanned { token: Token::Ident(name), column }, Spanned { token: Token::Punct(":"), .. }, ..] = rest {
+            self.define(name, *column, Symbol::Label(self.addr))?;
+            rest = &rest[2..];
+        }
+
+        let (first, column) = match rest.first() {
+            Some(Spanned { token: Token::Ident(name), column }) => (name.clone(), *column),
+            Some(other) => return Err((other.column, "expected a label, instruction or directive".to_string())),
+            None => return Ok(()),
+        };
+
+        // name = expr, name equ expr
+        let is_const = match rest.get(1) {
+            Some(Spanned { token: Token::Punct("="), .. }) => true,
+            Some(Spanned { token: Token::Ident(word), .. }) => word.eq_ignore_ascii_case("equ"),
+            _ => false,
+        };
+        if is_const {
+            let expr = parse_expr(&rest[2..], end_column)?;
+            return self.define(&first, column, Symbol::Const(expr, self.addr));
+        }
+
+        let args = &rest[1..];
+        let loc = Loc { file, line, column };
+
+        match first.to_ascii_lowercase().as_str() {
+            "org" => {
+                let expr = parse_expr(args, end_column)?;
+                let addr = self.eval(&expr, self.addr, 0)?;
+                if addr < ORIGIN as i64 || addr >= MEMORY_END as i64 {
+                    return Err((column, format!("org {:#X} is outside 0x200 to 0xFFFF", addr)));
+                }
+                self.addr = addr as u32;
+            },
+            "include" => {
+                let path = match args {
+                    [Spanned { token: Token::Str(path), .. }] => String::from_utf8_lossy(path).into_owned(),
+                    _ => return Err((column, "include needs a file name in quotes".to_string())),
+                };
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err((column, "includes nested too deep".to_string()));
+                }
+
+                let full: PathBuf = Path::new(&self.files[file]).parent().unwrap_or_else(|| Path::new("")).join(&path);
+                let source = load(&full).map_err(|err| (column, format!("could not include {}: {}", path, err)))?;
+                self.read(&full.to_string_lossy(), &source, depth + 1, load);
+            },
+            "db" => {
+                let mut items = Vec::new();
+                for operand in split_commas(args) {
+                    match operand {
+                        [Spanned { token: Token::Str(text), .. }] => items.push(DataItem::Str(text.clone())),
+                        _ => {
+                            let column = operand.first().map_or(end_column, |t| t.column);
+                            items.push(DataItem::Value(parse_expr(operand, end_column)?, column));
+                        },
+                    }
+                }
+                self.push(loc, Item::Bytes(items))?;
+            },
+            "dw" => {
+                let mut items = Vec::new();
+                for operand in split_commas(args) {
+                    let column = operand.first().map_or(end_column, |t| t.column);
+                    items.push((parse_expr(operand, end_column)?, column));
+                }
+                self.push(loc, Item::Words(items))?;
+            },
+            _ => {
+                let mut operands = Vec::new();
+                for operand in split_commas(args) {
+                    let column = operand.first().map_or(end_column, |t| t.column);
+                    operands.push((parse_operand(operand, end_column)?, column));
+                }
+
+                self.push(loc, Item::Instruction { mnemonic: first, operands })?;
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Add a statement at the current address and move past it.
+    fn push(&mut self, loc: Loc, item: Item) -> Result<(), LineError> {
+        let size = match &item {
+            Item::Instruction { operands, .. } => match operands.as_slice() {
+                [(Operand::I, _), (Operand::Long(_), _)] => 4,
+                _ => 2,
+            },
+            Item::Bytes(items) => items.iter().map(|item| match item {
+                DataItem::Str(text) => text.len() as u32,
+                DataItem::Value(..) => 1,
+            }).sum(),
+            Item::Words(items) => items.len() as u32 * 2,
+        };
+
+        if self.addr + size > MEMORY_END {
+            return Err((loc.column, "program does not fit in memory".to_string()));
+        }
+
+        self.statements.push(Statement { loc, addr: self.addr, item });
+        self.addr += size;
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, column: usize, symbol: Symbol) -> Result<(), LineError> {
+        if self.symbols.contains_key(name) {
+            return Err((column, format!("'{}' is already defined", name)));
+        }
+        self.symbols.insert(name.to_string(), symbol);
+        Ok(())
+    }
+
+    fn eval(&self, expr: &Expr, here: u32, depth: usize) -> Result<i64, LineError> {
+        Ok(match expr {
+            Expr::Number(n) => *n,
+            Expr::Here => here as i64,
+            Expr::Symbol(name, column) => match self.symbols.get(name) {
+                Some(Symbol::Label(addr)) => *addr as i64,
+                Some(Symbol::Const(expr, defined_at)) => {
+                    if depth >= MAX_CONSTANT_DEPTH {
+                        return Err((*column, format!("'{}' refers to itself", name)));
+                    }
+                    self.eval(expr, *defined_at, depth + 1).map_err(|(_, message)| (*column, message))?
+                },
+                None => return Err((*column, format!("undefined symbol '{}'", name))),
+            },
+            Expr::Unary(op, value) => {
+                let value = self.eval(value, here, depth)?;
+                match *op {
+                    "-" => value.wrapping_neg(),
+                    "~" => !value,
+                    _ => value,
+                }
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs, here, depth)?;
+                let rhs = self.eval(rhs, here, depth)?;
+                match *op {
+                    "+" => lhs.wrapping_add(rhs),
+                    "-" => lhs.wrapping_sub(rhs),
+                    "*" => lhs.wrapping_mul(rhs),
+                    "/" | "%" if rhs == 0 => return Err((0, "division by zero".to_string())),
+                    "/" => lhs.wrapping_div(rhs),
+                    "%" => lhs.wrapping_rem(rhs),
+                    "&" => lhs & rhs,
+                    "|" => lhs | rhs,
+                    "^" => lhs ^ rhs,
+                    "<<" => lhs.wrapping_shl(rhs as u32),
+                    _ => lhs.wrapping_shr(rhs as u32),
+                }
+            },
+        })
+    }
+
+    /// Evaluate and check the value is in `min..=max`.
+    fn value(&self, expr: &Expr, column: usize, here: u32, min: i64, max: i64, what: &str) -> Result<i64, LineError> {
+        let value = self.eval(expr, here, 0).map_err(|(col, message)| (if col == 0 { column } else { col }, message))?;
+        if value < min || value > max {
+            return Err((column, format!("{} {} is out of range", what, value)));
+        }
+        Ok(value)
+    }
+
+    /// Second pass, encode every statement into the image.
+    fn emit(&mut self) {
+        let mut used = Vec::new();
+        let statements = std::mem::take(&mut self.statements);
+
+        for statement in statements.iter() {
+            let bytes = match self.encode(statement) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    self.error(statement.loc.file, statement.loc.line, err);
+                    continue;
+                },
+            };
+
+            let start = (statement.addr - ORIGIN as u32) as usize;
+            let end = start + bytes.len();
+            if self.image.len() < end {
+                self.image.resize(end, 0);
+                used.resize(end, false);
+            }
+
+            if used[start..end].iter().any(|used| *used) {
+                let column = statement.loc.column;
+                self.error(statement.loc.file, statement.loc.line, (column, format!("overlaps code already at {:03X}", statement.addr)));
+                continue;
+            }
+
+            self.image[start..end].copy_from_slice(&bytes);
+            used[start..end].iter_mut().for_each(|used| *used = true);
+        }
+
+        self.statements = statements;
+    }
+
+    fn encode(&self, statement: &Statement) -> Result<Vec<u8>, LineError> {
+        let here = statement.addr;
+
+        match &statement.item {
+            Item::Bytes(items) => {
+                let mut bytes = Vec::new();
+                for item in items {
+                    match item {
+                        DataItem::Str(text) => bytes.extend_from_slice(text),
+                        DataItem::Value(expr, column) => bytes.push(self.value(expr, *column, here, -128, 255, "byte")? as u8),
+                    }
+                }
+                Ok(bytes)
+            },
+            Item::Words(items) => {
+                let mut bytes = Vec::new();
+                for (expr, column) in items {
+                    let word = self.value(expr, *column, here, -0x8000, 0xFFFF, "word")? as u16;
+                    bytes.extend_from_slice(&word.to_be_bytes());
+                }
+                Ok(bytes)
+            },
+            Item::Instruction { mnemonic, operands } => {
+                Ok(self.instruction(mnemonic, operands, statement.loc.column, here)?.encode())
+            },
+        }
+    }
+
+    fn instruction(&self, mnemonic: &str, ops: &[(Operand, usize)], column: usize, here: u32) -> Result<Instruction, LineError> {
+        use Instruction::*;
+        use Operand::{Reg, Value};
+
+        let value = |i: usize, min: i64, max: i64, what: &str| -> Result<i64, LineError> {
+            match &ops[i] {
+                (Value(expr), column) | (Operand::Long(expr), column) => self.value(expr, *column, here, min, max, what),
+                (_, column) => Err((*column, format!("expected a {}", what))),
+            }
+        };
+        let byte = |i: usize| value(i, -128, 255, "byte").map(|v| v as u8);
+        let addr = |i: usize| value(i, 0, 0xFFF, "address").map(|v| v as u16);
+        let nibble = |i: usize| value(i, 0, 15, "nibble").map(|v| v as u8);
+
+        let upper = mnemonic.to_ascii_uppercase();
+        let kinds: Vec<&Operand> = ops.iter().map(|(op, _)| op).collect();
+
+        let instr = match (upper.as_str(), kinds.as_slice()) {
+            ("CLS", []) => Cls,
+            ("RET", []) => Ret,
+            ("SCD", [Value(_)]) => ScrollDown(nibble(0)?),
+            ("SCU", [Value(_)]) => ScrollUp(nibble(0)?),
+            ("SCR", []) => ScrollRight,
+            ("SCL", []) => ScrollLeft,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => LowRes,
+            ("HIGH", []) => HighRes,
+
+            ("JP", [Value(_)]) => Jump(addr(0)?),
+            ("JP", [Reg(0), Value(_)]) => JumpOffset(addr(1)?),
+            ("CALL", [Value(_)]) => Call(addr(0)?),
+            ("SE", [Reg(x), Reg(y)]) => SkipEqReg(*x, *y),
+            ("SE", [Reg(x), Value(_)]) => SkipEqConst(*x, byte(1)?),
+            ("SNE", [Reg(x), Reg(y)]) => SkipNotEqReg(*x, *y),
+            ("SNE", [Reg(x), Value(_)]) => SkipNotEqConst(*x, byte(1)?),
+
+            ("LD", [Reg(x), Reg(y)]) => LoadReg(*x, *y),
+            ("LD", [Reg(x), Value(_)]) => LoadConst(*x, byte(1)?),
+            ("LD", [Reg(x), Operand::DT]) => LoadDelay(*x),
+            ("LD", [Reg(x), Operand::K]) => WaitKeyPress(*x),
+            ("LD", [Reg(x), Operand::IndirectI]) => Load(*x),
+            ("LD", [Reg(x), Operand::R]) => LoadFlags(*x),
+            ("LD", [Operand::I, Value(_)]) => LoadAddr(addr(1)?),
+            ("LD", [Operand::I, Operand::Long(_)]) => LoadLongAddr(value(1, 0, 0xFFFF, "address")? as u16),
+            ("LD", [Operand::DT, Reg(x)]) => SetDelay(*x),
+            ("LD", [Operand::ST, Reg(x)]) => SetSound(*x),
+            ("LD", [Operand::F, Reg(x)]) => SetSpriteAddr(*x),
+            ("LD", [Operand::HF, Reg(x)]) => SetBigSpriteAddr(*x),
+            ("LD", [Operand::B, Reg(x)]) => BCD(*x),
+            ("LD", [Operand::IndirectI, Reg(x)]) => Store(*x),
+            ("LD", [Operand::R, Reg(x)]) => StoreFlags(*x),
+
+            ("ADD", [Reg(x), Reg(y)]) => Add(*x, *y),
+            ("ADD", [Reg(x), Value(_)]) => AddConst(*x, byte(1)?),
+            ("ADD", [Operand::I, Reg(x)]) => AddAddr(*x),
+            ("OR", [Reg(x), Reg(y)]) => Or(*x, *y),
+            ("AND", [Reg(x), Reg(y)]) => And(*x, *y),
+            ("XOR", [Reg(x), Reg(y)]) => Xor(*x, *y),
+            ("SUB", [Reg(x), Reg(y)]) => Sub(*x, *y),
+            ("SUBN", [Reg(x), Reg(y)]) => SubN(*x, *y),
+            // without a source register the result is the same with or without the shift quirk
+            ("SHR", [Reg(x)]) => ShiftRight(*x, *x),
+            ("SHR", [Reg(x), Reg(y)]) => ShiftRight(*x, *y),
+            ("SHL", [Reg(x)]) => ShiftLeft(*x, *x),
+            ("SHL", [Reg(x), Reg(y)]) => ShiftLeft(*x, *y),
+            ("RND", [Reg(x), Value(_)]) => Rand(*x, byte(1)?),
+            ("DRW", [Reg(x), Reg(y), Value(_)]) => Draw(*x, *y, nibble(2)?),
+            ("SKP", [Reg(x)]) => SkipOnKeyPressed(*x),
+            ("SKNP", [Reg(x)]) => SkipKeyNotPressed(*x),
+
+            ("SAVE", [Reg(x), Reg(y)]) => SaveRange(*x, *y),
+            ("LOAD", [Reg(x), Reg(y)]) => LoadRange(*x, *y),
+            ("PLANE", [Value(_)]) => SelectPlanes(value(0, 0, 3, "plane mask")? as u8),
+            ("AUDIO", []) => LoadAudio,
+            ("PITCH", [Reg(x)]) => SetPitch(*x),
+
+            (name, _) if MNEMONICS.contains(&name) => return Err((column, format!("invalid operands for {}", name))),
+            _ => return Err((column, format!("unknown instruction '{}'", mnemonic))),
+        };
+
+        Ok(instr)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::Disassembly;
+
+    fn assemble(source: &str) -> Result<Vec<u8>, Vec<AsmError>> {
+        assemble_with("test.asm", source, |path| Err(io::Error::new(io::ErrorKind::NotFound, path.display().to_string())))
+    }
+
+    fn messages(source: &str) -> Vec<String> {
+        assemble(source).unwrap_err().iter().map(|err| err.to_string()).collect()
+    }
+
+    #[test]
+    fn labels_and_forward_references() {
+        let rom = assemble("
+start:  CALL sub
+        JP start
+sub:    LD I, sprite
+        RET
+sprite: db 0x3C, 0b01000010
+").unwrap();
+
+        assert_eq!(rom, [0x22, 0x04, 0x12, 0x00, 0xA2, 0x08, 0x00, 0xEE, 0x3C, 0x42]);
+    }
+
+    #[test]
+    fn constants_expressions_and_data() {
+        let rom = assemble("
+SPEED = 3
+TOP equ SPEED * 2 + (1 << 4)
+    ADD V0, TOP
+    LD V1, -1
+    dw $, end - $
+    db \"AB\", ~0 & 0xF
+end:
+").unwrap();
+
+        assert_eq!(rom, [0x70, 0x16, 0x61, 0xFF, 0x02, 0x04, 0x00, 0x07, b'A', b'B', 0x0F]);
+    }
+
+    #[test]
+    fn arithmetic_wraps() {
+        let rom = assemble("M = -9223372036854775807 - 1\ndb M / -1 - M + 1, M % -1").unwrap();
+
+        assert_eq!(rom, [0x01, 0x00]);
+    }
+
+    #[test]
+    fn org_leaves_a_gap() {
+        let rom = assemble("CLS\norg 0x206\nEXIT").unwrap();
+
+        assert_eq!(rom, [0x00, 0xE0, 0, 0, 0, 0, 0x00, 0xFD]);
+    }
+
+    #[test]
+    fn include_relative_to_file() {
+        let rom = assemble_with("src/main.asm", "include \"lib.asm\"\nJP routine", |path| {
+            assert_eq!(path, Path::new("src/lib.asm"));
+            Ok("routine: RET".to_string())
+        }).unwrap();
+
+        assert_eq!(rom, [0x00, 0xEE, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn line_addresses_of_the_main_file() {
+        let assembled = assemble_with_lines("main.asm", "; start\nstart: CLS\nSPEED = 2\ninclude \"lib.asm\"\n  db 1, 2\nJP start", |_| {
+            Ok("RET\nRET".to_string())
+        }).unwrap();
+
+        assert_eq!(assembled.lines, [None, Some(0x200), None, None, Some(0x206), Some(0x208)]);
+        assert_eq!(assembled.image.len(), 10);
+    }
+
+    #[test]
+    fn reports_file_line_and_column() {
+        assert_eq!(messages("CLS\n  LD V0, missing"), ["test.asm:2:10: undefined symbol 'missing'"]);
+        assert_eq!(messages("  MOV V0, 1"), ["test.asm:1:3: unknown instruction 'MOV'"]);
+        assert_eq!(messages("LD DT, 5"), ["test.asm:1:1: invalid operands for LD"]);
+        assert_eq!(messages("JP 0x1000"), ["test.asm:1:4: address 4096 is out of range"]);
+        assert_eq!(messages("a: CLS\na: CLS"), ["test.asm:2:1: 'a' is already defined"]);
+        assert_eq!(messages("org 0x202\nCLS\norg 0x202\nCLS"), ["test.asm:4:1: overlaps code already at 202"]);
+        assert_eq!(messages("x = x + 1\nLD V0, x")[0], "test.asm:2:8: 'x' refers to itself");
+    }
+
+    #[test]
+    fn reassembles_disassembly() {
+        let rom = [
+            0x22, 0x08, 0x6A, 0x05, 0xF0, 0x00, 0x12, 0x34, 0xA2, 0x0E, 0xDA, 0xB5, 0x00, 0xEE,
+            0x3C, 0x42, 0xFF,
+        ];
+
+        let listing = Disassembly::new(&rom, 0x200).listing();
+        assert_eq!(assemble(&listing).unwrap(), rom);
+    }
+}
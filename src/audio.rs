@@ -0,0 +1,129 @@
+use sdl2::AudioSubsystem;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+/// How the beeper sounds.
+#[derive(Clone, Copy, Debug)]
+pub struct AudioSettings {
+    /// Tone frequency in Hz.
+    pub pitch: f32,
+    /// 0.0 is silent, 1.0 is full scale.
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            pitch: 440.0,
+            volume: 0.25,
+            muted: false,
+        }
+    }
+}
+
+/// Square wave played while the sound timer is running, or the XO-CHIP
+/// audio pattern once a program loaded one.
+pub struct Beeper {
+    device: AudioDevice<SquareWave>,
+}
+
+impl Beeper {
+
+    pub fn new(audio: &AudioSubsystem, settings: AudioSettings) -> Result<Self, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(44_100),
+            channels: Some(1),
+            samples: Some(512),
+        };
+
+        let device = audio.open_playback(None, &desired, |spec| {
+            SquareWave {
+                phase: 0.0,
+                phase_inc: settings.pitch / spec.freq as f32,
+                sample_rate: spec.freq as f32,
+                pattern: None,
+                position: 0.0,
+                position_inc: 0.0,
+                volume: settings.volume,
+                amplitude: 0.0,
+                // reach full volume in 5 ms, so starting and stopping does not click
+                ramp: 1.0 / (spec.freq as f32 * 0.005),
+                on: false,
+                muted: settings.muted,
+            }
+        })?;
+
+        // the device runs all the time, silence is produced by ramping down
+        device.resume();
+
+        Ok(Self { device })
+    }
+
+    /// Start or stop the tone, call with `Chip8::is_beeping` once per frame.
+    pub fn set_beeping(&mut self, on: bool) {
+        self.device.lock().on = on;
+    }
+
+    /// Play `pattern` at `rate` samples per second instead of the tone, call
+    /// with `Chip8::audio_pattern` and `Chip8::audio_rate` once per frame.
+    pub fn set_pattern(&mut self, pattern: Option<[u8; 16]>, rate: f32) {
+        let mut wave = self.device.lock();
+        wave.pattern = pattern;
+        wave.position_inc = rate / wave.sample_rate;
+    }
+
+    pub fn toggle_mute(&mut self) -> bool {
+        let mut wave = self.device.lock();
+        wave.muted = !wave.muted;
+        wave.muted
+    }
+}
+
+
+struct SquareWave {
+    phase: f32,
+    phase_inc: f32,
+    sample_rate: f32,
+    /// 128 1-bit samples played in a loop instead of the square wave.
+    pattern: Option<[u8; 16]>,
+    /// The sample of `pattern` playing, moves `position_inc` per output sample.
+    position: f32,
+    position_inc: f32,
+    volume: f32,
+    /// Current amplitude, moves towards `volume` or 0 by `ramp` per sample.
+    amplitude: f32,
+    ramp: f32,
+    on: bool,
+    muted: bool,
+}
+
+impl AudioCallback for SquareWave {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        let target = if self.on && !self.muted { self.volume } else { 0.0 };
+
+        for sample in out.iter_mut() {
+            if self.amplitude < target {
+                self.amplitude = (self.amplitude + self.ramp * self.volume).min(target);
+            }
+            else if self.amplitude > target {
+                self.amplitude = (self.amplitude - self.ramp * self.volume).max(target);
+            }
+
+            let high = match self.pattern {
+                Some(pattern) => {
+                    let bit = self.position as usize;
+                    pattern[bit / 8] & (0x80 >> (bit % 8)) != 0
+                },
+                None => self.phase < 0.5,
+            };
+            *sample = if high { self.amplitude } else { -self.amplitude };
+
+            // keep the phase running while silent, so the next beep starts
+            // from wherever the wave is instead of jumping
+            self.phase = (self.phase + self.phase_inc) % 1.0;
+            self.position = (self.position + self.position_inc) % 128.0;
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Debug Adapter Protocol server, talks DAP over stdio or, with
+//! `--port <n>`, to the first client connecting to that local TCP port.
+
+use chip8::dap::{self, Session};
+use std::env;
+use std::io::{self, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "Usage: chip8-dap [--port <n>]";
+
+/// Frames per second while the program runs, as in the SDL frontend.
+const FRAME_RATE: f64 = 60.0;
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let port = match args.as_slice() {
+        [] => None,
+        [option, port] if option == "--port" => match port.parse::<u16>() {
+            Ok(port) => Some(port),
+            Err(_) => {
+                eprintln!("Invalid port '{}'\n{}", port, USAGE);
+                return Ok(());
+            },
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            return Ok(());
+        },
+    };
+
+    let (reader, writer): (Box<dyn io::Read + Send>, Box<dyn Write>) = match port {
+        Some(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("Waiting for a debugger on port {}", port);
+            let (stream, _) = listener.accept()?;
+            (Box::new(stream.try_clone()?), Box::new(stream))
+        },
+        None => (Box::new(io::stdin()), Box::new(io::stdout())),
+    };
+
+    serve(reader, writer)
+}
+
+
+fn serve(reader: Box<dyn io::Read + Send>, mut writer: Box<dyn Write>) -> io::Result<()> {
+
+    // requests are read on their own thread so a running program can still
+    // be paused
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        loop {
+            match dap::read_message(&mut reader) {
+                Ok(Some(message)) => if sender.send(message).is_err() { break },
+                Ok(None) => break,
+                Err(err) => {
+                    eprintln!("Bad message: {}", err);
+                    break;
+                },
+            }
+        }
+    });
+
+    let mut session = Session::new();
+    let frame_duration = Duration::from_secs_f64(1.0 / FRAME_RATE);
+    let mut next_frame = Instant::now();
+
+    while !session.is_finished() {
+        if session.is_running() {
+            loop {
+                match requests.try_recv() {
+                    Ok(request) => session.handle(&request),
+                    Err(TryRecvError::Empty) => break,
+                    // the client is gone, nobody is left to run the program for
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+
+            session.run_frame();
+
+            next_frame += frame_duration;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            }
+            else {
+                next_frame = now;
+            }
+        }
+        else {
+            match requests.recv() {
+                Ok(request) => session.handle(&request),
+                Err(_) => break,
+            }
+            next_frame = Instant::now();
+        }
+
+        for message in session.take_messages() {
+            dap::write_message(&mut writer, &message)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,246 @@
+//! The `.ch8t` text format: a program as hex digits.
+//!
+//! Each whitespace separated word is one or more bytes written as pairs of
+//! hex digits, `6005 F015` or `60 05 F0 15`. `//` starts a comment running to
+//! the end of the line and `@addr` moves the following bytes to `addr`, the
+//! bytes skipped over are zero.
+
+use crate::disasm::Disassembly;
+use crate::instructions;
+use std::fmt;
+use std::fmt::Write;
+
+/// Where programs are loaded, the address of the first byte.
+const ORIGIN: u16 = 0x200;
+/// End of the address space a `.ch8t` program can fill, the XO-CHIP's 64 KiB.
+const END: u32 = 0x10000;
+
+/// Runs of this many zero bytes that aren't code are written as an `@addr`.
+const MIN_ZERO_RUN: usize = 16;
+/// Data bytes per line written.
+const DATA_PER_LINE: usize = 8;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TextErrorKind {
+    /// Neither a hex digit, whitespace nor a comment.
+    InvalidCharacter(char),
+    /// A word with half a byte in it.
+    OddDigits,
+    /// `@` not followed by a hex address inside the program area.
+    InvalidOrigin(String),
+    /// A byte written twice, after an `@` moving back.
+    Overlap(u16),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextError {
+    /// 1 based.
+    pub line: usize,
+    /// 1 based, in characters.
+    pub column: usize,
+    pub kind: TextErrorKind,
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.column)?;
+        match &self.kind {
+            TextErrorKind::InvalidCharacter(c) => write!(f, "invalid character '{}', expected hex digits", c),
+            TextErrorKind::OddDigits => write!(f, "odd number of hex digits, bytes are two digits"),
+            TextErrorKind::InvalidOrigin(origin) => write!(f, "invalid origin '{}', expected @200 to @FFFF", origin),
+            TextErrorKind::Overlap(addr) => write!(f, "{:03X} is written twice", addr),
+        }
+    }
+}
+
+impl std::error::Error for TextError {}
+
+
+/// A parsed `.ch8t` program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextProgram {
+    /// The program image, starting at 0x200.
+    pub rom: Vec<u8>,
+    /// The address of the first byte on each line, `None` for lines without
+    /// any.
+    pub lines: Vec<Option<u16>>,
+}
+
+/// Parse a `.ch8t` program.
+pub fn parse(text: &str) -> Result<TextProgram, TextError> {
+    let mut rom = Vec::new();
+    let mut written = Vec::new();
+    let mut lines = Vec::new();
+    let mut addr = ORIGIN as u32;
+
+    for (i, line) in text.lines().enumerate() {
+        let code = match line.find("//") {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut first = None;
+
+        for (column, word) in words(code) {
+            let error = |column, kind| TextError { line: i + 1, column, kind };
+
+            if let Some(origin) = word.strip_prefix('@') {
+                let digits = origin.strip_prefix("0x").or_else(|| origin.strip_prefix("0X")).unwrap_or(origin);
+                addr = match u32::from_str_radix(digits, 16) {
+                    Ok(value) if (ORIGIN as u32..END).contains(&value) => value,
+                    _ => return Err(error(column, TextErrorKind::InvalidOrigin(origin.to_string()))),
+                };
+                continue;
+            }
+
+            if let Some((offset, c)) = word.chars().enumerate().find(|(_, c)| !c.is_ascii_hexdigit()) {
+                return Err(error(column + offset, TextErrorKind::InvalidCharacter(c)));
+            }
+            if word.len() % 2 != 0 {
+                return Err(error(column, TextErrorKind::OddDigits));
+            }
+
+            for pair in 0..word.len() / 2 {
+                if addr >= END {
+                    return Err(error(column + pair * 2, TextErrorKind::InvalidOrigin(format!("{:X}", addr))));
+                }
+
+                let index = (addr - ORIGIN as u32) as usize;
+                if rom.len() <= index {
+                    rom.resize(index + 1, 0);
+                    written.resize(index + 1, false);
+                }
+                if written[index] {
+                    return Err(error(column + pair * 2, TextErrorKind::Overlap(addr as u16)));
+                }
+
+                rom[index] = u8::from_str_radix(&word[pair * 2..pair * 2 + 2], 16).unwrap_or(0);
+                written[index] = true;
+                first.get_or_insert(addr as u16);
+                addr += 1;
+            }
+        }
+
+        lines.push(first);
+    }
+
+    Ok(TextProgram { rom, lines })
+}
+
+/// The words of a line with their 1 based columns.
+fn words(line: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut start = None;
+    let mut words = Vec::new();
+
+    for (column, (index, c)) in line.char_indices().enumerate() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some((column, index)),
+            (true, Some((first, from))) => {
+                words.push((first + 1, &line[from..index]));
+                start = None;
+            },
+            _ => {},
+        }
+    }
+    if let Some((first, from)) = start {
+        words.push((first + 1, &line[from..]));
+    }
+
+    words.into_iter()
+}
+
+
+/// Write a program starting at 0x200 as `.ch8t`, one instruction a line with
+/// its address and mnemonic in a comment. Bytes no path through the program
+/// reaches are written as data, long runs of zeros are skipped with `@addr`
+/// and trailing zeros are left out.
+pub fn write(rom: &[u8]) -> String {
+    let rom = &rom[..rom.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1)];
+    let disassembly = Disassembly::new(rom, ORIGIN);
+    let mut out = String::new();
+    let mut offset = 0;
+
+    let zero_run = |offset: usize| rom[offset..].iter()
+        .enumerate()
+        .take_while(|(i, byte)| **byte == 0 && !disassembly.is_code((ORIGIN as usize + offset + i) as u16))
+        .count();
+
+    while offset < rom.len() {
+        let addr = ORIGIN as usize + offset;
+
+        if disassembly.is_code(addr as u16) {
+            if let Ok(instr) = instructions::decode(rom, offset) {
+                let size = instr.size() as usize;
+                let hex: String = rom[offset..offset + size].iter().map(|byte| format!("{:02X}", byte)).collect();
+                let _ = writeln!(out, "{:<24}// {:03X}: {}", hex, addr, instr);
+                offset += size;
+                continue;
+            }
+        }
+
+        let zeros = zero_run(offset);
+        if zeros >= MIN_ZERO_RUN {
+            offset += zeros;
+            let _ = writeln!(out, "@{:03X}", ORIGIN as usize + offset);
+            continue;
+        }
+
+        let mut len = 1;
+        while len < DATA_PER_LINE && offset + len < rom.len()
+            && !disassembly.is_code((addr + len) as u16)
+            && zero_run(offset + len) < MIN_ZERO_RUN {
+            len += 1;
+        }
+        let hex: Vec<String> = rom[offset..offset + len].iter().map(|byte| format!("{:02X}", byte)).collect();
+        let _ = writeln!(out, "{:<24}// {:03X}: data", hex.join(" "), addr);
+        offset += len;
+    }
+
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn bytes_comments_and_lines() {
+        let program = parse("// header\n6001 61 02 // trailing\n\n7001\n").unwrap();
+
+        assert_eq!(program.rom, [0x60, 0x01, 0x61, 0x02, 0x70, 0x01]);
+        assert_eq!(program.lines, [None, Some(0x200), None, Some(0x204)]);
+    }
+
+    #[test]
+    fn origin_moves_and_pads() {
+        let program = parse("1204\n@204 00E0\n@0x202 00EE").unwrap();
+        assert_eq!(program.rom, [0x12, 0x04, 0x00, 0xEE, 0x00, 0xE0]);
+    }
+
+    #[test]
+    fn errors_have_line_and_column() {
+        assert_eq!(error("6001\n  60G1"), "2:5: invalid character 'G', expected hex digits");
+        assert_eq!(error("600 1"), "1:1: odd number of hex digits, bytes are two digits");
+        assert_eq!(error("@100"), "1:1: invalid origin '100', expected @200 to @FFFF");
+        assert_eq!(error("6001\n@200 00E0"), "2:6: 200 is written twice");
+        assert_eq!(parse("3XNN").unwrap_err().kind, TextErrorKind::InvalidCharacter('X'));
+    }
+
+    #[test]
+    fn written_text_parses_back() {
+        let mut rom = vec![0xA2, 0x06, 0xD0, 0x15, 0x12, 0x04, 0x3C, 0x42];
+        rom.extend([0; 20]);
+        rom.extend([0xFF]);
+        rom.extend([0; 100]);
+
+        let text = write(&rom);
+        assert!(text.starts_with("A206                    // 200: LD I, 0x206\n"));
+        assert!(text.contains("3C 42                   // 206: data\n@21C\nFF                      // 21C: data\n"));
+        assert_eq!(parse(&text).unwrap().rom, rom[..rom.len() - 100]);
+    }
+}
@@ -1,108 +1,328 @@
 use crate::registers::*;
 use crate::keyboard::*;
 use crate::display::*;
+use crate::quirks::Quirks;
+use crate::random::Rng;
+use crate::cpu::{self, Fault};
+use crate::ch8t::{self, TextError};
+use std::fmt;
+
+/// Where the 4x5 font is stored, FX29 points I into it.
+pub const FONT_ADDR: u16 = 0x000;
+/// Where the SUPER-CHIP 8x10 font is stored, FX30 points I into it.
+pub const BIG_FONT_ADDR: u16 = 0x050;
+
+/* Standard 4x5 font, one sprite per hex digit */
+const FONT: [u8; 5 * 16] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+/* SUPER-CHIP 8x10 font, digits 0-9 from SCHIP 1.1 and A-F as used by Octo */
+const BIG_FONT: [u8; 10 * 16] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+/// The machine a program was written for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Platform {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+impl Platform {
+
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::SuperChip => 0x1000,
+            Platform::XoChip => 0x10000,
+        }
+    }
+
+    /// The quirks programs for this platform usually expect.
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks::default(),
+            Platform::SuperChip => Quirks::schip(),
+            Platform::XoChip => Quirks::xo_chip(),
+        }
+    }
+
+    /// Look up a platform by the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "chip8" => Some(Platform::Chip8),
+            "schip" => Some(Platform::SuperChip),
+            "xochip" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+}
 
+#[derive(Clone)]
 pub struct Chip8 {
-    pub memory: [u8; 4096],
+    pub platform: Platform,
+    /// 4 KiB, or 64 KiB for XO-CHIP.
+    pub memory: Vec<u8>,
     pub stack: [u16; 16],
     pub registers: Registers,
     pub keyboard: Keyboard,
     pub pc: u16,
     pub sp: u8,
-    pub display: Display
+    pub display: Display,
+    /// Set when an instruction faults, the machine does not run while halted.
+    pub fault: Option<Fault>,
+    pub quirks: Quirks,
+    /// Source of CXNN random numbers, seeded with 0 unless `seed_rng` is called.
+    pub rng: Rng,
+    /// Set by a draw when `quirks.draw_waits_for_vblank` is on, cleared by `vblank`.
+    pub waiting_for_vblank: bool,
+    /// Set by the SUPER-CHIP 00FD exit instruction.
+    pub exited: bool,
+    /// SUPER-CHIP "RPL user flags", saved and restored by FX75 and FX85.
+    pub rpl_flags: [u8; 16],
+    /// XO-CHIP 1-bit audio sample, 128 samples loaded by F002. Until then
+    /// the sound timer plays a plain tone.
+    pub audio_pattern: Option<[u8; 16]>,
+    /// XO-CHIP playback rate of `audio_pattern`, set by FX3A.
+    pub audio_pitch: u8,
 }
 
 
 impl Chip8 {
     pub fn new() -> Self {
+        Self::for_platform(Platform::Chip8, Quirks::default())
+    }
+
+    pub fn with_quirks(quirks: Quirks) -> Self {
+        Self::for_platform(Platform::Chip8, quirks)
+    }
+
+    pub fn for_platform(platform: Platform, quirks: Quirks) -> Self {
         let mut res = Self {
-            memory : [0; 4096],
+            platform,
+            memory : vec![0; platform.memory_size()],
             stack : [0; 16],
             registers: Registers::new(),
             keyboard: Keyboard::new(),
-            pc: 0x200,
+            pc: PROGRAM_START,
             sp: 0,
-            display: Display::new()
+            display: Display::new(),
+            fault: None,
+            quirks,
+            rng: Rng::default(),
+            waiting_for_vblank: false,
+            exited: false,
+            rpl_flags: [0; 16],
+            audio_pattern: None,
+            audio_pitch: 64,
         };
 
 
-        // initialize the char sprites, store them from 0 to 80 (5*16)
-        /* Standard 4x5 font */
-        /* '0' */ res.memory[0] = 0xF0; res.memory[0 + 1] = 0x90; res.memory[0 + 2] = 0x90; res.memory[0 + 3] = 0x90; res.memory[0 + 4] = 0xF0;
-        /* '1' */ res.memory[5] = 0x20; res.memory[5 + 1] = 0x60; res.memory[5 + 2] = 0x20; res.memory[5 + 3] = 0x20; res.memory[5 + 4] = 0x70;
-        /* '2' */ res.memory[10] = 0xF0; res.memory[10 + 1] = 0x10; res.memory[10 + 2] = 0xF0; res.memory[10 + 3] = 0x80; res.memory[10 + 4] = 0xF0;
-        /* '3' */ res.memory[15] = 0xF0; res.memory[15 + 1] = 0x10; res.memory[15 + 2] = 0xF0; res.memory[15 + 3] = 0x10; res.memory[15 + 4] = 0xF0;
-        /* '4' */ res.memory[20] = 0x90; res.memory[20 + 1] = 0x90; res.memory[20 + 2] = 0xF0; res.memory[20 + 3] = 0x10; res.memory[20 + 4] = 0x10;
-        /* '5' */ res.memory[25] = 0xF0; res.memory[25 + 1] = 0x80; res.memory[25 + 2] = 0xF0; res.memory[25 + 3] = 0x10; res.memory[25 + 4] = 0xF0;
-        /* '6' */ res.memory[30] = 0xF0; res.memory[30 + 1] = 0x80; res.memory[30 + 2] = 0xF0; res.memory[30 + 3] = 0x90; res.memory[30 + 4] = 0xF0;
-        /* '7' */ res.memory[35] = 0xF0; res.memory[35 + 1] = 0x10; res.memory[35 + 2] = 0x20; res.memory[35 + 3] = 0x40; res.memory[35 + 4] = 0x40;
-        /* '8' */ res.memory[40] = 0xF0; res.memory[40 + 1] = 0x90; res.memory[40 + 2] = 0xF0; res.memory[40 + 3] = 0x90; res.memory[40 + 4] = 0xF0;
-        /* '9' */ res.memory[45] = 0xF0;  res.memory[45 + 1] = 0x90;  res.memory[45 + 2] = 0xF0;  res.memory[45 + 3] = 0x10; res.memory[45 + 4] =  0xF0;
-        /* 'A' */ res.memory[50] = 0xF0; res.memory[50 + 1] = 0x90; res.memory[50 + 2] = 0xF0; res.memory[50 + 3] = 0x90; res.memory[50 + 4] = 0x90;
-        /* 'B' */ res.memory[55] = 0xE0; res.memory[55 + 1] = 0x90; res.memory[55 + 2] = 0xE0; res.memory[55 + 3] = 0x90; res.memory[55 + 4] = 0xE0;
-        /* 'C' */ res.memory[60] = 0xF0; res.memory[60 + 1] = 0x80; res.memory[60 + 2] = 0x80; res.memory[60 + 3] = 0x80; res.memory[60 + 4] = 0xF0;
-        /* 'D' */ res.memory[65] = 0xE0; res.memory[65 + 1] = 0x80; res.memory[65 + 2] = 0x80; res.memory[65 + 3] = 0x80; res.memory[65 + 4] = 0xE0;
-        /* 'E' */ res.memory[70] = 0xF0; res.memory[70 + 1] = 0x80; res.memory[70 + 2] = 0xF0; res.memory[70 + 3] = 0x80; res.memory[70 + 4] = 0xF0;
-        /* 'F' */ res.memory[75] = 0xF0; res.memory[75 + 1] = 0x80; res.memory[75 + 2] = 0xF0; res.memory[75 + 3] = 0x80; res.memory[75 + 4] = 0x80;
+        // initialize the char sprites, store them from 0 to 80 (5*16) and the
+        // big ones right after from 80 to 240 (10*16)
+        let font = FONT_ADDR as usize;
+        let big_font = BIG_FONT_ADDR as usize;
+        res.memory[font..font + FONT.len()].copy_from_slice(&FONT);
+        res.memory[big_font..big_font + BIG_FONT.len()].copy_from_slice(&BIG_FONT);
 
         res
     }
 
+    /// Restart the random number generator, the same seed gives the same
+    /// CXNN results.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Mask for wrapping an address around the end of memory.
+    #[inline]
+    pub fn address_mask(&self) -> u16 {
+        (self.memory.len() - 1) as u16
+    }
+
     pub fn set_instruction(&mut self, start: usize, instr: u16) {
         self.memory[start] = (instr >> 8) as u8;
         self.memory[start + 1] =  instr as u8;
     }
 
-    pub fn load_program(&mut self, program: &Program) {
+    /// Copy the program into memory and start running it from its first
+    /// byte.
+    pub fn load_program(&mut self, program: &Program) -> Result<(), LoadError> {
+        let data = program.get_binary_data()?;
+        let start = program.load_address() as usize;
+        let end = start + data.len();
+
+        if end > self.memory.len() {
+            return Err(LoadError::TooBig { end, memory: self.memory.len() });
+        }
+
+        self.memory[start..end].copy_from_slice(&data);
+        self.pc = program.load_address();
+        Ok(())
+    }
 
-        for (i,byte) in program.get_binary_data().iter().enumerate() {
-            self.memory[0x200 + i] = *byte;
+    /// Execute a single instruction.
+    pub fn step(&mut self) -> Result<(), Fault> {
+        cpu::cycle(self)
+    }
 
+    /// Execute `instructions` instructions followed by one 60 Hz timer tick.
+    /// Stops at the first fault, without ticking the timers.
+    pub fn run_frame(&mut self, instructions: u32) -> Result<(), Fault> {
+        self.keyboard.begin_frame();
+        for _ in 0..instructions {
+            self.step()?;
         }
+
+        self.vblank();
+        Ok(())
+    }
+
+    /// The 60 Hz vertical blank, ticks the delay and sound timers.
+    pub fn vblank(&mut self) {
+        self.registers.tick();
+        self.waiting_for_vblank = false;
+    }
+
+    /// True while the sound timer is running, or like on the COSMAC VIP
+    /// while a key is held for FX0A, frontends should play a tone.
+    #[inline]
+    pub fn is_beeping(&self) -> bool {
+        (self.registers.get_sound() > 0 || self.keyboard.wait_key_held()) && !self.is_halted()
+    }
+
+    /// The rate `audio_pattern` plays at while beeping, in samples per
+    /// second: 4000 at the default pitch of 64, doubling every 48 steps.
+    pub fn audio_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.audio_pitch as f32 - 64.0) / 48.0)
+    }
+
+    /// True after a fault or a SUPER-CHIP exit.
+    #[inline]
+    pub fn is_halted(&self) -> bool {
+        self.fault.is_some() || self.exited
+    }
+
+    /// The display as rows of `display.width()` pixels, each a colour index
+    /// where 0 is the background and 1 a lit CHIP-8 pixel.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.display.read_pixels()
+    }
+
+    /// Set hex key `key` (0x0 - 0xF) as held down or released.
+    pub fn set_key(&mut self, key: u8, down: bool) {
+        self.keyboard.set_key(key, down);
+    }
+}
+
+impl Default for Chip8 {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+/// Where programs are loaded unless they say otherwise.
+pub const PROGRAM_START: u16 = 0x200;
+
 pub enum Program {
+    /// `.ch8t` hex digits, see `ch8t`, loaded at 0x200.
     Text(String),
-    Binary([u8;0xDFF]) //  programs start at 0x200 and ends at 0xFFF
+    /// A binary image and the address its first byte is loaded at.
+    Binary { data: Vec<u8>, load_address: u16 },
 }
 
 impl Program {
 
-    pub fn get_binary_data(&self) -> Vec::<u8> {
+    /// A binary image loaded at 0x200.
+    pub fn binary(data: Vec<u8>) -> Self {
+        Program::Binary { data, load_address: PROGRAM_START }
+    }
 
-        match self {
-            Program::Binary(ref data) => data.to_vec(),
-            Program::Text(t) => {
+    /// Tell a `.ch8t` text program from a binary rom by looking at it: text
+    /// is UTF-8 without control characters other than whitespace, which a
+    /// rom practically never is as it's full of zeros and small numbers.
+    pub fn detect(data: Vec<u8>) -> Self {
+        let is_text = std::str::from_utf8(&data).is_ok_and(|text| {
+            text.chars().all(|c| !c.is_control() || c.is_whitespace()) && text.chars().any(|c| c.is_ascii_hexdigit())
+        });
+
+        match String::from_utf8(data) {
+            Ok(text) if is_text => Program::Text(text),
+            Ok(text) => Program::binary(text.into_bytes()),
+            Err(err) => Program::binary(err.into_bytes()),
+        }
+    }
 
-                let mut text = t.clone().to_lowercase();
-                // remove lines starting with '//' that is comment
-                text = text.split('\n').filter(|line| !line.starts_with("//")).collect();
+    pub fn load_address(&self) -> u16 {
+        match self {
+            Program::Text(_) => PROGRAM_START,
+            Program::Binary { load_address, .. } => *load_address,
+        }
+    }
 
-                // replace whitespace with nothing
-                text.retain(|c| (c  >= 'a'  && c <= 'f')
-                            || (c >= '0' && c <= '9'));
+    /// The program image starting at `load_address`, parsing text programs.
+    pub fn get_binary_data(&self) -> Result<Vec<u8>, TextError> {
+        match self {
+            Program::Binary { data, .. } => Ok(data.clone()),
+            Program::Text(text) => ch8t::parse(text).map(|program| program.rom),
+        }
+    }
+}
 
-                let t_values: Vec<u8> = text.chars().map(|c| {
-                    if c  >= 'a'  && c <= 'f' {
-                        return (c as u8) - 87;
-                    }
 
-                    (c as u8) - 48
-                }).collect();
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    Text(TextError),
+    /// The program runs past the end of the platform's memory.
+    TooBig { end: usize, memory: usize },
+}
 
-                println!("{:?}", t_values);
-                let mut res = Vec::new();
-                for i in (0..t_values.len()).step_by(2) {
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Text(err) => write!(f, "{}", err),
+            LoadError::TooBig { end, memory } =>
+                write!(f, "the program ends at {:#X} but the platform only has {:#X} bytes of memory", end, memory),
+        }
+    }
+}
 
-                    let upper = t_values[i] << 4;
-                    let val = upper + t_values[i + 1];
+impl std::error::Error for LoadError {}
 
-                    res.push(val);
-                }
-                res
-            },
-        }
+impl From<TextError> for LoadError {
+    fn from(err: TextError) -> Self {
+        LoadError::Text(err)
     }
 }
 
@@ -115,13 +335,76 @@ impl Program {
 mod tests {
     use super::*;
 
+    #[test]
+    fn run_frame_steps_and_ticks() {
+        let mut chip = Chip8::new();
+
+        // 6005 F015 1204: V0 = 5, delay = V0, loop forever
+        chip.load_program(&Program::Text("6005 F015 1204".to_string())).unwrap();
+        chip.run_frame(3).unwrap();
+
+        assert_eq!(chip.pc, 0x204);
+        assert_eq!(chip.registers.get_delay(), 4);
+    }
+
+    #[test]
+    fn set_key_is_seen_by_program() {
+        let mut chip = Chip8::new();
+
+        // V0 = 7, skip next if key V0 is pressed
+        chip.load_program(&Program::Text("6007 E09E".to_string())).unwrap();
+        chip.set_key(7, true);
+        chip.step().unwrap();
+        chip.step().unwrap();
+
+        assert_eq!(chip.pc, 0x206);
+    }
+
+    #[test]
+    fn seeded_rand_is_reproducible() {
+        let run = |seed| {
+            let mut chip = Chip8::new();
+            chip.seed_rng(seed);
+
+            // V0 = rand & FF, V1 = rand & FF
+            chip.load_program(&Program::Text("C0FF C1FF".to_string())).unwrap();
+            chip.run_frame(2).unwrap();
+            (chip.registers.get_value(0), chip.registers.get_value(1))
+        };
+
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
+
+    #[test]
+    fn beeps_while_sound_timer_runs() {
+        let mut chip = Chip8::new();
+
+        // V0 = 2, sound = V0
+        chip.load_program(&Program::Text("6002 F018".to_string())).unwrap();
+        chip.run_frame(2).unwrap();
+        assert!(chip.is_beeping());
+
+        chip.vblank();
+        assert!(!chip.is_beeping());
+    }
+
+    #[test]
+    fn audio_rate_follows_pitch() {
+        let mut chip = Chip8::new();
+        assert_eq!(chip.audio_rate(), 4000.0);
+
+        chip.audio_pitch = 112;
+        assert_eq!(chip.audio_rate(), 8000.0);
+    }
+
     #[test]
     fn program_from_text() {
         let input = "60FF F015 6000 6900 6E00 6000 3001 3000 1392 7E01 6001 4001 4000 1392 7E01 6101 6200 5020 5010 1392 7E01";
 
         let program = Program::Text(input.to_string());
 
-        let binary = program.get_binary_data();
+        let binary = program.get_binary_data().unwrap();
 
         let expected = [0x60, 0xFF, 0xF0, 0x15, 0x60, 0x00, 0x69, 0x00, 0x6E, 0x00, 0x60, 0x00, 0x30, 0x01, 0x30, 0x00, 0x13, 0x92, 0x7E, 0x01, 0x60, 0x01, 0x40, 0x01, 0x40, 0x00, 0x13, 0x92, 0x7E, 0x01, 0x61, 0x01, 0x62, 0x00, 0x50, 0x20, 0x50, 0x10, 0x13, 0x92, 0x7E, 0x01];
 
@@ -134,10 +417,36 @@ mod tests {
 
         let program = Program::Text(input.to_string());
 
-        let binary = program.get_binary_data();
+        let binary = program.get_binary_data().unwrap();
 
         let expected = [0x60, 0xFF, 0xF0, 0x15, 0x60, 0x00, 0x69, 0x00, 0x6E, 0x00, 0x60, 0x00, 0x30, 0x01, 0x30, 0x00, 0x13, 0x92, 0x7E, 0x01, 0x60, 0x01, 0x40, 0x01, 0x40, 0x00, 0x13, 0x92, 0x7E, 0x01, 0x61, 0x01, 0x62, 0x00, 0x50, 0x20, 0x50, 0x10, 0x13, 0x92, 0x7E, 0x01];
 
         assert_eq!(binary, expected);
     }
+
+    #[test]
+    fn program_at_load_address() {
+        let mut chip = Chip8::new();
+        chip.load_program(&Program::Binary { data: vec![0x60, 0x05], load_address: 0x600 }).unwrap();
+
+        assert_eq!(chip.pc, 0x600);
+        assert_eq!(chip.memory[0x600..0x602], [0x60, 0x05]);
+    }
+
+    #[test]
+    fn program_too_big_for_memory() {
+        let mut chip = Chip8::new();
+        let err = chip.load_program(&Program::binary(vec![0; 0xE01])).unwrap_err();
+        assert_eq!(err, LoadError::TooBig { end: 0x1001, memory: 0x1000 });
+
+        let mut chip = Chip8::for_platform(Platform::XoChip, Quirks::default());
+        assert!(chip.load_program(&Program::binary(vec![0; 0xE01])).is_ok());
+    }
+
+    #[test]
+    fn detect_text_or_binary() {
+        assert!(matches!(Program::detect(b"// comment\n6005 F015\n".to_vec()), Program::Text(_)));
+        assert!(matches!(Program::detect(vec![0x60, 0x05, 0xF0, 0x15]), Program::Binary { .. }));
+        assert!(matches!(Program::detect(vec![0x12, 0xFF, 0x00]), Program::Binary { .. }));
+    }
 }
@@ -0,0 +1,433 @@
+//! The settings file, `rust-chip-8/config.toml` in the XDG config directory.
+//!
+//! Every setting is optional, missing ones keep their default. Sections
+//! named `[rom."<file name or sha1>"]` hold the same settings for one rom,
+//! the hash section wins over the file name one. Unknown keys are errors so
+//! typos don't go unnoticed.
+//!
+//! ```toml
+//! platform = "chip8"
+//!
+//! [video]
+//! width = 832
+//! height = 416
+//! colors = ["#000000", "#ffd200"]
+//!
+//! [rom.PONG2.timing]
+//! cycles_per_frame = 20
+//! ```
+
+use crate::chip::Platform;
+use crate::database::parse_color;
+use crate::gamepad::PadMap;
+use crate::keyboard::LatchPolicy;
+use crate::keymap::{KeyMap, Layout};
+use crate::quirks::Quirks;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{env, fmt, fs, io};
+
+const APP_DIR: &str = "rust-chip-8";
+const FILE_NAME: &str = "config.toml";
+
+type Colors = Vec<(u8, u8, u8)>;
+
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    #[serde(deserialize_with = "platform")]
+    pub platform: Option<Platform>,
+    pub video: Video,
+    pub audio: Audio,
+    pub input: Input,
+    pub timing: Timing,
+    pub quirks: QuirkSettings,
+    /// Per rom sections, only at the top level.
+    rom: BTreeMap<String, Settings>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Video {
+    /// Window size in pixels.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// "#rrggbb" from the background on, for each XO-CHIP plane combination.
+    #[serde(deserialize_with = "colors")]
+    pub colors: Option<Colors>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Audio {
+    /// Tone frequency in Hz.
+    pub pitch: Option<f32>,
+    /// 0 to 100.
+    pub volume: Option<f32>,
+    pub muted: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Input {
+    /// The preset the keys start from, named as on the command line.
+    #[serde(deserialize_with = "layout")]
+    pub layout: Option<Layout>,
+    /// Host key names replacing a hex key's, `0 = "X"` or
+    /// `5 = ["W", "Up"]`, the names the frontend knows.
+    #[serde(deserialize_with = "hex_keys")]
+    pub keys: BTreeMap<u8, Vec<String>>,
+    pub gamepad: Gamepad,
+    /// How long a key press is seen, `"frame"` or `"off"`.
+    #[serde(deserialize_with = "latch_policy")]
+    pub latch: Option<LatchPolicy>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Gamepad {
+    /// Percent of the way a stick is pushed before it counts.
+    pub deadzone: Option<u8>,
+    /// Controller inputs replacing a hex key's, `5 = ["dpup", "lefty-"]`,
+    /// for the first and second controller.
+    #[serde(deserialize_with = "hex_keys")]
+    pub player1: BTreeMap<u8, Vec<String>>,
+    #[serde(deserialize_with = "hex_keys")]
+    pub player2: BTreeMap<u8, Vec<String>>,
+}
+
+impl Gamepad {
+
+    /// `base`, the rom's buttons or the default, with the inputs set here
+    /// replaced.
+    pub fn pad_map(&self, base: PadMap) -> PadMap {
+        let mut map = base;
+        if let Some(deadzone) = self.deadzone {
+            map.deadzone = deadzone;
+        }
+        for (player, keys) in [&self.player1, &self.player2].iter().enumerate() {
+            for (key, inputs) in keys.iter() {
+                map.set(player, *key, inputs);
+            }
+        }
+        map
+    }
+}
+
+impl Input {
+
+    /// The layout's keys with the ones set here replaced.
+    pub fn key_map(&self) -> KeyMap {
+        let mut map = KeyMap::new(self.layout.unwrap_or_default());
+        for (key, names) in self.keys.iter() {
+            map.set(*key, names);
+        }
+        map
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timing {
+    /// Instructions per 60 Hz frame.
+    pub cycles_per_frame: Option<u32>,
+    pub seed: Option<u64>,
+}
+
+/// A quirks profile with single quirks changed.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuirkSettings {
+    /// Replaces the platform's quirks, named as on the command line.
+    #[serde(deserialize_with = "quirks_profile")]
+    pub profile: Option<Quirks>,
+    pub shift_uses_vy: Option<bool>,
+    pub load_store_increments_i: Option<bool>,
+    pub jump_uses_vx: Option<bool>,
+    pub logic_resets_vf: Option<bool>,
+    pub clip_sprites: Option<bool>,
+    pub draw_waits_for_vblank: Option<bool>,
+    pub wait_key_on_press: Option<bool>,
+}
+
+impl QuirkSettings {
+
+    /// The profile, or `platform` without one, with the single quirks set.
+    pub fn resolve(&self, platform: Quirks) -> Quirks {
+        let mut quirks = self.profile.unwrap_or(platform);
+        let flags = [
+            (&mut quirks.shift_uses_vy, self.shift_uses_vy),
+            (&mut quirks.load_store_increments_i, self.load_store_increments_i),
+            (&mut quirks.jump_uses_vx, self.jump_uses_vx),
+            (&mut quirks.logic_resets_vf, self.logic_resets_vf),
+            (&mut quirks.clip_sprites, self.clip_sprites),
+            (&mut quirks.draw_waits_for_vblank, self.draw_waits_for_vblank),
+            (&mut quirks.wait_key_on_press, self.wait_key_on_press),
+        ];
+        for (quirk, value) in flags {
+            if let Some(value) = value {
+                *quirk = value;
+            }
+        }
+        quirks
+    }
+}
+
+impl Settings {
+
+    /// Take every setting `other` has, keeping the rest.
+    pub fn merge(&mut self, other: &Settings) {
+        fn set<T: Clone>(setting: &mut Option<T>, other: &Option<T>) {
+            if other.is_some() {
+                *setting = other.clone();
+            }
+        }
+
+        set(&mut self.platform, &other.platform);
+
+        set(&mut self.video.width, &other.video.width);
+        set(&mut self.video.height, &other.video.height);
+        set(&mut self.video.colors, &other.video.colors);
+
+        set(&mut self.audio.pitch, &other.audio.pitch);
+        set(&mut self.audio.volume, &other.audio.volume);
+        set(&mut self.audio.muted, &other.audio.muted);
+
+        set(&mut self.input.layout, &other.input.layout);
+        set(&mut self.input.latch, &other.input.latch);
+        self.input.keys.extend(other.input.keys.iter().map(|(key, names)| (*key, names.clone())));
+        let (gamepad, other_gamepad) = (&mut self.input.gamepad, &other.input.gamepad);
+        set(&mut gamepad.deadzone, &other_gamepad.deadzone);
+        gamepad.player1.extend(other_gamepad.player1.iter().map(|(key, inputs)| (*key, inputs.clone())));
+        gamepad.player2.extend(other_gamepad.player2.iter().map(|(key, inputs)| (*key, inputs.clone())));
+
+        set(&mut self.timing.cycles_per_frame, &other.timing.cycles_per_frame);
+        set(&mut self.timing.seed, &other.timing.seed);
+
+        let (quirks, other_quirks) = (&mut self.quirks, &other.quirks);
+        set(&mut quirks.profile, &other_quirks.profile);
+        set(&mut quirks.shift_uses_vy, &other_quirks.shift_uses_vy);
+        set(&mut quirks.load_store_increments_i, &other_quirks.load_store_increments_i);
+        set(&mut quirks.jump_uses_vx, &other_quirks.jump_uses_vx);
+        set(&mut quirks.logic_resets_vf, &other_quirks.logic_resets_vf);
+        set(&mut quirks.clip_sprites, &other_quirks.clip_sprites);
+        set(&mut quirks.draw_waits_for_vblank, &other_quirks.draw_waits_for_vblank);
+        set(&mut quirks.wait_key_on_press, &other_quirks.wait_key_on_press);
+    }
+}
+
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    settings: Settings,
+}
+
+impl Config {
+
+    /// `$XDG_CONFIG_HOME/rust-chip-8/config.toml`, or under `~/.config`
+    /// when it isn't set.
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+        Some(dir.join(APP_DIR).join(FILE_NAME))
+    }
+
+    /// Read a settings file, no file is the same as an empty one.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).map_err(|err| ConfigError(format!("{}: {}", path.display(), err))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(ConfigError(format!("{}: {}", path.display(), err))),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let settings: Settings = toml::from_str(text).map_err(|err| ConfigError(err.to_string()))?;
+
+        if let Some((name, _)) = settings.rom.iter().find(|(_, rom)| !rom.rom.is_empty()) {
+            return Err(ConfigError(format!("rom section '{}' can't have rom sections", name)));
+        }
+
+        Ok(Self { settings })
+    }
+
+    /// The settings outside the rom sections.
+    pub fn global(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// The settings of the rom sections for a rom's file name and SHA-1, only
+    /// what they set.
+    pub fn rom(&self, file_name: &str, sha1: &str) -> Settings {
+        let mut settings = Settings::default();
+
+        for key in [file_name, sha1] {
+            if let Some(rom) = self.settings.rom.get(key) {
+                settings.merge(rom);
+            }
+        }
+        settings
+    }
+}
+
+
+fn platform<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Platform>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    Platform::from_name(&name).map(Some).ok_or_else(|| D::Error::custom(format!("unknown platform '{}'", name)))
+}
+
+fn layout<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Layout>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    Layout::from_name(&name).map(Some).ok_or_else(|| D::Error::custom(format!("unknown keyboard layout '{}'", name)))
+}
+
+fn latch_policy<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<LatchPolicy>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    LatchPolicy::from_name(&name).map(Some).ok_or_else(|| D::Error::custom(format!("unknown latch policy '{}'", name)))
+}
+
+fn quirks_profile<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Quirks>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    Quirks::from_name(&name).map(Some).ok_or_else(|| D::Error::custom(format!("unknown quirks profile '{}'", name)))
+}
+
+fn colors<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Colors>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|color| parse_color(color).ok_or_else(|| D::Error::custom(format!("invalid colour '{}', expected \"#rrggbb\"", color))))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// One host key name or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyNames {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn hex_keys<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<u8, Vec<String>>, D::Error> {
+    BTreeMap::<String, KeyNames>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, names)| match u8::from_str_radix(&key, 16) {
+            Ok(key) if key < 16 => Ok((key, match names {
+                KeyNames::One(name) => vec![name],
+                KeyNames::Many(names) => names,
+            })),
+            _ => Err(D::Error::custom(format!("'{}' is not a hex key, expected 0 to F", key))),
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r##"
+        platform = "schip"
+
+        [video]
+        colors = ["#000000", "#ffffff"]
+
+        [input]
+        layout = "azerty"
+        latch = "off"
+        keys = { 5 = "W", a = ["Z", "Space"] }
+
+        [timing]
+        cycles_per_frame = 20
+
+        [quirks]
+        profile = "vip"
+        clip_sprites = false
+
+        [rom.PONG.timing]
+        cycles_per_frame = 8
+
+        [rom.PONG.input.gamepad]
+        deadzone = 50
+        player2 = { c = ["dpup", "y"] }
+
+        [rom.b232ef880bd6060fb45fa6effed7edf0ae95670e]
+        platform = "chip8"
+        timing = { cycles_per_frame = 9 }
+    "##;
+
+    #[test]
+    fn global_settings() {
+        let config = Config::parse(CONFIG).unwrap();
+        let global = config.global();
+
+        assert_eq!(global.platform, Some(Platform::SuperChip));
+        assert_eq!(global.video.colors, Some(vec![(0, 0, 0), (255, 255, 255)]));
+        let keys = global.input.key_map();
+        assert_eq!(keys.hex_key("W"), Some(0x5));
+        assert_eq!(keys.host_keys(0xA), ["Z", "Space"]);
+        assert_eq!(keys.hex_key("A"), Some(0x4));
+        assert_eq!(global.input.latch, Some(LatchPolicy::Off));
+        assert_eq!(global.timing.cycles_per_frame, Some(20));
+
+        let mut quirks = Quirks::vip();
+        quirks.clip_sprites = false;
+        assert_eq!(global.quirks.resolve(Quirks::default()), quirks);
+    }
+
+    #[test]
+    fn rom_sections_override() {
+        let config = Config::parse(CONFIG).unwrap();
+
+        let mut settings = config.global().clone();
+        settings.merge(&config.rom("PONG", "0000"));
+        assert_eq!(settings.timing.cycles_per_frame, Some(8));
+        assert_eq!(settings.platform, Some(Platform::SuperChip));
+
+        // the hash wins over the file name
+        let rom = config.rom("PONG", "b232ef880bd6060fb45fa6effed7edf0ae95670e");
+        assert_eq!(rom.timing.cycles_per_frame, Some(9));
+        assert_eq!(rom.platform, Some(Platform::Chip8));
+        assert_eq!(rom.video.colors, None);
+
+        let pads = settings.input.gamepad.pad_map(PadMap::default());
+        assert_eq!(pads.deadzone, 50);
+        assert_eq!(pads.hex_key(0, "dpup"), Some(0x5));
+        assert_eq!(pads.hex_key(1, "y"), Some(0xC));
+
+        assert_eq!(config.rom("BRIX", "0000"), Settings::default());
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        let err = Config::parse("[video]\nwidht = 800\n").unwrap_err().to_string();
+        assert!(err.contains("unknown field `widht`"), "{}", err);
+
+        assert!(Config::parse("platform = \"nes\"").unwrap_err().to_string().contains("unknown platform 'nes'"));
+        assert!(Config::parse("[input.keys]\n10 = \"A\"").unwrap_err().to_string().contains("'10' is not a hex key"));
+        assert!(Config::parse("[input]\nlayout = \"colemak\"").unwrap_err().to_string().contains("unknown keyboard layout"));
+        assert!(Config::parse("[rom.PONG.rom.BRIX]\nplatform = \"chip8\"").is_err());
+    }
+
+    #[test]
+    fn missing_file_is_empty() {
+        assert_eq!(Config::load(Path::new("/nonexistent/config.toml")).unwrap(), Config::default());
+    }
+}
@@ -0,0 +1,1014 @@
+use crate::instructions;
+use crate::instructions::{Instruction, DecodeError};
+use crate::chip::*;
+use crate::display::{Sprite, Resolution, PLANES};
+use std::fmt;
+
+/// Why the machine halted. `pc` is always the address of the faulting
+/// instruction, which is left in place so it can be inspected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// `Call` with all 16 stack entries in use.
+    StackOverflow { pc: u16 },
+    /// `Ret` with an empty stack.
+    StackUnderflow { pc: u16 },
+    /// The instruction would read or write `memory[addr]`, past the end of memory.
+    MemoryOutOfBounds { pc: u16, addr: usize },
+    /// `pc` does not point at a whole instruction inside memory.
+    PcOutOfBounds { pc: u16 },
+    InvalidOpcode(DecodeError),
+}
+
+impl Fault {
+    pub fn pc(&self) -> u16 {
+        match *self {
+            Fault::StackOverflow { pc }
+            | Fault::StackUnderflow { pc }
+            | Fault::MemoryOutOfBounds { pc, .. }
+            | Fault::PcOutOfBounds { pc } => pc,
+            Fault::InvalidOpcode(err) => err.addr,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::StackOverflow { pc } => write!(f, "stack overflow at {:#05X}", pc),
+            Fault::StackUnderflow { pc } => write!(f, "stack underflow at {:#05X}", pc),
+            Fault::MemoryOutOfBounds { pc, addr } => write!(f, "memory access {:#X} out of bounds at {:#05X}", addr, pc),
+            Fault::PcOutOfBounds { pc } => write!(f, "program counter out of bounds at {:#X}", pc),
+            Fault::InvalidOpcode(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
+
+impl From<DecodeError> for Fault {
+    fn from(err: DecodeError) -> Self {
+        Fault::InvalidOpcode(err)
+    }
+}
+
+/// Fetch, decode and execute the instruction at `pc`. On a fault the machine
+/// is left untouched and halted, every later cycle returns the same fault.
+/// While waiting for vblank or after 00FD exit nothing is executed.
+pub fn cycle(chip: &mut Chip8) -> Result<(), Fault> {
+
+    if let Some(fault) = chip.fault {
+        return Err(fault);
+    }
+
+    if chip.waiting_for_vblank || chip.exited {
+        return Ok(());
+    }
+
+    let res = fetch(chip).and_then(|instr| execute(instr, chip));
+
+    match res {
+        Ok(ExecuteRes::SetPc(addr)) => {
+            chip.pc = addr;
+        },
+        Ok(ExecuteRes::Wait) => {},
+        Err(fault) => {
+            chip.fault = Some(fault);
+            return Err(fault);
+        }
+    };
+
+    Ok(())
+}
+
+
+fn fetch(chip: &Chip8) -> Result<Instruction, Fault> {
+    let pc = chip.pc as usize;
+    if pc + 1 >= chip.memory.len() {
+        return Err(Fault::PcOutOfBounds { pc: chip.pc });
+    }
+
+    Ok(instructions::decode(&chip.memory, pc)?)
+}
+
+
+/// How far a skip moves past the instruction at `pc`, which is 4 bytes for
+/// XO-CHIP F000 NNNN.
+fn skip_size(chip: &Chip8, pc: u16) -> u16 {
+    let pc = pc as usize;
+    match chip.memory.get(pc..pc + 2) {
+        Some([0xF0, 0x00]) => 4,
+        _ => 2,
+    }
+}
+
+
+/// Check that `len` bytes starting at `addr` are inside memory.
+fn check_memory(chip: &Chip8, addr: usize, len: usize) -> Result<(), Fault> {
+    if addr + len > chip.memory.len() {
+        let addr = addr.max(chip.memory.len());
+        return Err(Fault::MemoryOutOfBounds { pc: chip.pc, addr });
+    }
+
+    Ok(())
+}
+
+
+enum ExecuteRes {
+    SetPc(u16),
+    Wait
+}
+
+
+fn execute(instr: Instruction, chip: &mut Chip8) -> Result<ExecuteRes, Fault> {
+    use ExecuteRes::*;
+    let mut new_pc = chip.pc.wrapping_add(instr.size());
+    let res = match instr {
+        Instruction::Cls => {
+            chip.display.clear();
+            SetPc(new_pc)
+        },
+        Instruction::Ret => {
+            if chip.sp == 0 {
+                return Err(Fault::StackUnderflow { pc: chip.pc });
+            }
+            chip.sp -= 1;
+            new_pc = chip.stack[chip.sp as usize];
+            SetPc(new_pc)
+        },
+        Instruction::ScrollDown(n) => {
+            chip.display.scroll_down(n as usize);
+            SetPc(new_pc)
+        },
+        Instruction::ScrollUp(n) => {
+            chip.display.scroll_up(n as usize);
+            SetPc(new_pc)
+        },
+        Instruction::ScrollRight => {
+            chip.display.scroll_right(4);
+            SetPc(new_pc)
+        },
+        Instruction::ScrollLeft => {
+            chip.display.scroll_left(4);
+            SetPc(new_pc)
+        },
+        Instruction::Exit => {
+            chip.exited = true;
+            Wait
+        },
+        Instruction::LowRes => {
+            chip.display.set_resolution(Resolution::Low);
+            SetPc(new_pc)
+        },
+        Instruction::HighRes => {
+            chip.display.set_resolution(Resolution::High);
+            SetPc(new_pc)
+        },
+        Instruction::Jump(addr) => {
+            new_pc = addr;
+            SetPc(new_pc)
+        },
+        Instruction::Call(addr) => {
+            if chip.sp as usize >= chip.stack.len() {
+                return Err(Fault::StackOverflow { pc: chip.pc });
+            }
+
+            chip.stack[chip.sp as usize] = new_pc;
+            chip.sp += 1;
+            new_pc = addr;
+            SetPc(new_pc)
+        },
+        Instruction::SkipEqConst(reg, byte) => {
+
+            // get reg value by reg
+            let reg_val = chip.registers.get_value(reg);
+            if reg_val == byte {
+                new_pc = new_pc.wrapping_add(skip_size(chip, new_pc));
+            }
+
+            SetPc(new_pc)
+        },
+        Instruction::SkipNotEqConst(reg, byte) => {
+
+            // get reg value by reg
+            let reg_val = chip.registers.get_value(reg);
+            if reg_val != byte {
+                new_pc = new_pc.wrapping_add(skip_size(chip, new_pc));
+            }
+
+            SetPc(new_pc)
+        },
+        Instruction::SkipEqReg(reg_x, reg_y) => {
+
+            // get reg value by reg
+            let x_val = chip.registers.get_value(reg_x);
+            let y_val = chip.registers.get_value(reg_y);
+
+            if x_val == y_val {
+                new_pc = new_pc.wrapping_add(skip_size(chip, new_pc));
+            }
+            SetPc(new_pc)
+        },
+
+        Instruction::LoadConst(reg, byte) => {
+            chip.registers.set_value(reg, byte);
+            SetPc(new_pc)
+        },
+        Instruction::AddConst(reg, byte) => {
+            let cur = chip.registers.get_value(reg) as u16;
+            let res = (byte as u16 + cur) as u8;
+            //println!("cur={:?} byte ={}, res={}", cur, byte, res);
+            chip.registers.set_value(reg, res);
+            SetPc(new_pc)
+        },
+
+        Instruction::LoadReg(reg_x, reg_y) => {
+            let y_val = chip.registers.get_value(reg_y);
+            chip.registers.set_value(reg_x, y_val);
+            SetPc(new_pc)
+        },
+
+        Instruction::Or(reg_x, reg_y) => {
+            chip.registers.bitwise(reg_x, reg_y, |x,y| x | y);
+            if chip.quirks.logic_resets_vf {
+                chip.registers.set_value(0xF, 0);
+            }
+            SetPc(new_pc)
+        },
+
+        Instruction::And(reg_x, reg_y) => {
+            chip.registers.bitwise(reg_x, reg_y, |x,y| x & y);
+            if chip.quirks.logic_resets_vf {
+                chip.registers.set_value(0xF, 0);
+            }
+            SetPc(new_pc)
+        },
+
+        Instruction::Xor(reg_x, reg_y) => {
+            chip.registers.bitwise(reg_x, reg_y, |x,y| x ^ y);
+            if chip.quirks.logic_resets_vf {
+                chip.registers.set_value(0xF, 0);
+            }
+            SetPc(new_pc)
+        },
+
+        Instruction::Add(reg_x, reg_y) => {
+            let x = chip.registers.get_value(reg_x);
+            let y = chip.registers.get_value(reg_y);
+
+            let val = (x  as u16) + (y as u16);
+            // CHeck val > 255 then set v_f
+
+
+            if val > 255 {
+                chip.registers.set_value(0xF, 1);
+            }
+            else {
+                chip.registers.set_value(0xF, 0);
+            }
+
+            chip.registers.set_value(reg_x, val as u8);
+            SetPc(new_pc)
+        },
+
+
+        Instruction::Sub(reg_x, reg_y) => {
+            let mut x = chip.registers.get_value(reg_x) as u16;
+            let y = chip.registers.get_value(reg_y) as u16;
+
+
+            // CHeck val > 255 then set v_f
+
+            if x > y {
+                chip.registers.set_value(0xF, 1);
+            }
+            else {
+                chip.registers.set_value(0xF, 0);
+                x += 256;
+            }
+
+            let val = x - y;
+            chip.registers.set_value(reg_x, val as u8);
+            SetPc(new_pc)
+        },
+
+        Instruction::ShiftRight(reg_x, reg_y) => {
+            let x = shift_source(chip, reg_x, reg_y);
+
+            chip.registers.set_value(0xF, x & 1);
+
+            chip.registers.set_value(reg_x, x >> 1);
+            SetPc(new_pc)
+        },
+
+        Instruction::SubN(reg_x, reg_y) => {
+            let x = chip.registers.get_value(reg_x) as u16;
+            let mut y = chip.registers.get_value(reg_y) as u16;
+
+
+            // CHeck val > 255 then set v_f
+
+            if y > x {
+                chip.registers.set_value(0xF, 1);
+            }
+            else {
+                chip.registers.set_value(0xF, 0);
+                y += 256;
+            }
+
+            let val = y - x;
+            chip.registers.set_value(reg_x, val as u8);
+            SetPc(new_pc)
+        },
+
+        Instruction::ShiftLeft(reg_x, reg_y) => {
+            let x = shift_source(chip, reg_x, reg_y);
+
+            chip.registers.set_value(0xF, (x & 0x80) >> 7);
+
+            chip.registers.set_value(reg_x, x << 1);
+            SetPc(new_pc)
+        },
+
+        Instruction::SkipNotEqReg(reg_x, reg_y) => {
+            let x = chip.registers.get_value(reg_x);
+            let y = chip.registers.get_value(reg_y);
+
+            if x != y {
+                new_pc = new_pc.wrapping_add(skip_size(chip, new_pc));
+            }
+
+            SetPc(new_pc)
+        },
+
+        Instruction::LoadAddr(addr) => {
+            chip.registers.set_i(addr);
+            SetPc(new_pc)
+        },
+
+        Instruction::JumpOffset(addr) => {
+
+            let reg = if chip.quirks.jump_uses_vx { (addr >> 8) as u8 } else { 0 };
+            let offset = chip.registers.get_value(reg) as u16;
+
+            new_pc = offset + addr;
+            SetPc(new_pc)
+        },
+
+        Instruction::Rand(reg_x, data) => {
+
+            let val = data & chip.rng.next_byte();
+
+            chip.registers.set_value(reg_x, val);
+
+            SetPc(new_pc)
+        },
+
+        Instruction::Draw(reg_x, reg_y, n) => {
+            // get the data and send that to the display to draw
+
+
+            let x = chip.registers.get_value(reg_x) as usize;
+            let y = chip.registers.get_value(reg_y) as usize;
+
+            // DXY0 is a 16x16 sprite, two bytes per row
+            let wide = n == 0;
+            let length = if wide { 16 } else { n };
+            let bytes = if wide { 32 } else { n as usize };
+
+            // with several XO-CHIP planes selected the sprite for each plane
+            // follows the previous one in memory
+            let planes = chip.display.selected_planes();
+            let mut addr = chip.registers.get_i() as usize;
+            check_memory(chip, addr, bytes * planes.count_ones() as usize)?;
+
+            let mut vf = 0;
+            for plane in (0..PLANES).map(|p| 1 << p).filter(|p| planes & p != 0) {
+                let mut sprite = Sprite {
+                    data: [0; 32],
+                    length,
+                    wide,
+                    plane,
+                    x,
+                    y
+                };
+
+                sprite.data[..bytes].copy_from_slice(&chip.memory[addr..addr + bytes]);
+                addr += bytes;
+
+                vf = vf.max(chip.display.draw_sprite(&sprite, &chip.quirks));
+            }
+
+            chip.registers.set_value(0xf, vf);
+
+            chip.waiting_for_vblank = chip.quirks.draw_waits_for_vblank;
+
+            SetPc(new_pc)
+
+        },
+
+
+        Instruction::SkipOnKeyPressed(reg_x) => {
+
+            let x = chip.registers.get_value(reg_x);
+            if chip.keyboard.key_pressed(x) {
+                new_pc = new_pc.wrapping_add(skip_size(chip, new_pc));
+            }
+
+            SetPc(new_pc)
+        },
+
+        Instruction::SkipKeyNotPressed(reg_x) => {
+            let x = chip.registers.get_value(reg_x);
+            if !chip.keyboard.key_pressed(x) {
+                new_pc = new_pc.wrapping_add(skip_size(chip, new_pc));
+            }
+            SetPc(new_pc)
+        },
+
+        Instruction::LoadDelay(reg_x) => {
+            chip.registers.set_value(reg_x, chip.registers.get_delay() );
+            SetPc(new_pc)
+        },
+
+        Instruction::WaitKeyPress(reg_x) => {
+            let key = if chip.quirks.wait_key_on_press {
+                chip.keyboard.next_key()
+            }
+            else {
+                chip.keyboard.wait_release()
+            };
+            match key {
+                None => Wait,
+                Some(key) => {
+                    chip.registers.set_value(reg_x, key);
+                    SetPc(new_pc)
+                }
+            }
+        },
+
+
+        Instruction::SetDelay(reg_x) => {
+            let x = chip.registers.get_value(reg_x);
+            chip.registers.set_delay(x);
+            SetPc(new_pc)
+        },
+
+        Instruction::SetSound(reg_x) => {
+            let x = chip.registers.get_value(reg_x);
+            chip.registers.set_sound(x);
+            SetPc(new_pc)
+        },
+
+        Instruction::AddAddr(reg_x) => {
+            let x = chip.registers.get_value(reg_x);
+            chip.registers.increment_i(x as u16, chip.address_mask());
+            SetPc(new_pc)
+        },
+
+        Instruction::SetSpriteAddr(reg_x) => {
+            let x = chip.registers.get_value(reg_x);
+            chip.registers.set_i(FONT_ADDR + (x & 0xF) as u16 * 5);
+
+            SetPc(new_pc)
+        },
+
+        Instruction::SetBigSpriteAddr(reg_x) => {
+            let x = chip.registers.get_value(reg_x);
+            chip.registers.set_i(BIG_FONT_ADDR + (x & 0xF) as u16 * 10);
+
+            SetPc(new_pc)
+        },
+
+        Instruction::StoreFlags(reg_x) => {
+            for i in 0..=reg_x {
+                chip.rpl_flags[i as usize] = chip.registers.get_value(i);
+            }
+            SetPc(new_pc)
+        },
+
+        Instruction::LoadFlags(reg_x) => {
+            for i in 0..=reg_x {
+                chip.registers.set_value(i, chip.rpl_flags[i as usize]);
+            }
+            SetPc(new_pc)
+        },
+
+        Instruction::SaveRange(reg_x, reg_y) => {
+            let regs = register_range(reg_x, reg_y);
+            let addr = chip.registers.get_i() as usize;
+            check_memory(chip, addr, regs.len())?;
+
+            for (i, reg) in regs.enumerate() {
+                chip.memory[addr + i] = chip.registers.get_value(reg);
+            }
+            SetPc(new_pc)
+        },
+
+        Instruction::LoadRange(reg_x, reg_y) => {
+            let regs = register_range(reg_x, reg_y);
+            let addr = chip.registers.get_i() as usize;
+            check_memory(chip, addr, regs.len())?;
+
+            for (i, reg) in regs.enumerate() {
+                chip.registers.set_value(reg, chip.memory[addr + i]);
+            }
+            SetPc(new_pc)
+        },
+
+        Instruction::LoadLongAddr(addr) => {
+            chip.registers.set_i(addr);
+            SetPc(new_pc)
+        },
+
+        Instruction::SelectPlanes(planes) => {
+            chip.display.select_planes(planes);
+            SetPc(new_pc)
+        },
+
+        Instruction::LoadAudio => {
+            let addr = chip.registers.get_i() as usize;
+            let mut pattern = [0; 16];
+            let len = pattern.len();
+            check_memory(chip, addr, len)?;
+
+            pattern.copy_from_slice(&chip.memory[addr..addr + len]);
+            chip.audio_pattern = Some(pattern);
+            SetPc(new_pc)
+        },
+
+        Instruction::SetPitch(reg_x) => {
+            chip.audio_pitch = chip.registers.get_value(reg_x);
+            SetPc(new_pc)
+        },
+
+        Instruction::BCD(reg_x) => {
+            let x = chip.registers.get_value(reg_x);
+
+            let i_0 = x / 100;
+            let i_1 = (x % 100) / 10;
+            let i_2 = x % 10;
+
+            let addr = chip.registers.get_i() as usize;
+            check_memory(chip, addr, 3)?;
+
+            chip.memory[addr] = i_0;
+            chip.memory[addr + 1] = i_1;
+            chip.memory[addr + 2] = i_2;
+
+            SetPc(new_pc)
+        },
+
+        Instruction::Store(reg_x) => {
+
+            let addr = chip.registers.get_i() as usize;
+            check_memory(chip, addr, reg_x as usize + 1)?;
+            for i in 0..=reg_x {
+                chip.memory[addr + i as usize] = chip.registers.get_value(i);
+            }
+
+            if chip.quirks.load_store_increments_i {
+                chip.registers.increment_i(reg_x as u16 + 1, chip.address_mask());
+            }
+
+            SetPc(new_pc)
+        },
+
+        Instruction::Load(reg_x) => {
+
+            let addr = chip.registers.get_i() as usize;
+            check_memory(chip, addr, reg_x as usize + 1)?;
+            for i in 0..=reg_x {
+                chip.registers.set_value(i, chip.memory[addr + i as usize]);
+            }
+
+            if chip.quirks.load_store_increments_i {
+                chip.registers.increment_i(reg_x as u16 + 1, chip.address_mask());
+            }
+            SetPc(new_pc)
+        },
+    };
+
+    Ok(res)
+}
+
+
+/// Registers VX to VY for 5XY2 and 5XY3, in reverse order when X > Y.
+fn register_range(reg_x: u8, reg_y: u8) -> Box<dyn ExactSizeIterator<Item = u8>> {
+    if reg_x <= reg_y {
+        Box::new(reg_x..=reg_y)
+    }
+    else {
+        Box::new((reg_y..=reg_x).rev())
+    }
+}
+
+
+/// The value 8XY6 and 8XYE shift, which depends on the shift quirk.
+fn shift_source(chip: &Chip8, reg_x: u8, reg_y: u8) -> u8 {
+    if chip.quirks.shift_uses_vy {
+        chip.registers.get_value(reg_y)
+    }
+    else {
+        chip.registers.get_value(reg_x)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use crate::chip::*;
+    use crate::keyboard::LatchPolicy;
+    use crate::quirks::Quirks;
+    use super::*;
+
+
+    #[test]
+    fn sub_underflow() {
+
+        let mut chip = Chip8::new();
+
+        // set register 1 to 1
+        chip.registers.set_value(1,1);
+
+
+        chip.set_instruction(0x200, 0x8015);
+        cycle(&mut chip).unwrap();
+
+        // check that register 0 has the value of 255, since that is 0 - 1 with borrow
+        assert_eq!(chip.registers.get_value(0), 255);
+        assert_eq!(chip.registers.get_value(0xF), 0);
+    }
+
+
+    #[test]
+    fn add_overflow() {
+
+        let mut chip = Chip8::new();
+
+        // set register 0 and 1 to 200
+        chip.registers.set_value(0,200);
+        chip.registers.set_value(1,200);
+
+        chip.set_instruction(0x200, 0x8014);
+
+        cycle(&mut chip).unwrap();
+
+        assert_eq!(chip.registers.get_value(0), 0x90);
+        assert_eq!(chip.registers.get_value(0xF), 1);
+
+
+
+
+    }
+
+
+    #[test]
+    fn invalid_opcode_is_reported() {
+
+        let mut chip = Chip8::new();
+
+        chip.set_instruction(0x200, 0x0123);
+
+        let err = cycle(&mut chip).unwrap_err();
+
+        assert_eq!(err, Fault::InvalidOpcode(DecodeError { opcode: 0x0123, addr: 0x200 }));
+        assert_eq!(chip.pc, 0x200);
+    }
+
+    #[test]
+    fn ret_on_empty_stack_halts() {
+
+        let mut chip = Chip8::new();
+
+        chip.set_instruction(0x200, 0x00EE);
+
+        assert_eq!(cycle(&mut chip), Err(Fault::StackUnderflow { pc: 0x200 }));
+        assert_eq!(chip.sp, 0);
+        assert!(chip.is_halted());
+
+        // stays halted with the same fault
+        assert_eq!(cycle(&mut chip), Err(Fault::StackUnderflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn call_past_stack_size_halts() {
+
+        let mut chip = Chip8::new();
+
+        // call itself forever
+        chip.set_instruction(0x200, 0x2200);
+
+        for _ in 0..16 {
+            cycle(&mut chip).unwrap();
+        }
+
+        assert_eq!(cycle(&mut chip), Err(Fault::StackOverflow { pc: 0x200 }));
+        assert_eq!(chip.sp, 16);
+    }
+
+    #[test]
+    fn store_past_memory_end_halts() {
+
+        let mut chip = Chip8::new();
+
+        chip.registers.set_i(0xFFE);
+        chip.set_instruction(0x200, 0xF255);
+
+        assert_eq!(cycle(&mut chip), Err(Fault::MemoryOutOfBounds { pc: 0x200, addr: 0x1000 }));
+        assert_eq!(chip.memory[0xFFE], 0);
+    }
+
+    #[test]
+    fn pc_at_memory_end_halts() {
+
+        let mut chip = Chip8::new();
+
+        chip.pc = 0xFFF;
+
+        assert_eq!(cycle(&mut chip), Err(Fault::PcOutOfBounds { pc: 0xFFF }));
+    }
+
+    #[test]
+    fn shift_quirk() {
+
+        let mut chip = Chip8::new();
+        chip.registers.set_value(0, 0x01);
+        chip.registers.set_value(1, 0x82);
+        chip.set_instruction(0x200, 0x8016);
+
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.registers.get_value(0), 0x00);
+        assert_eq!(chip.registers.get_value(0xF), 1);
+
+        let mut chip = Chip8::with_quirks(Quirks::vip());
+        chip.registers.set_value(0, 0x01);
+        chip.registers.set_value(1, 0x82);
+        chip.set_instruction(0x200, 0x8016);
+
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.registers.get_value(0), 0x41);
+        assert_eq!(chip.registers.get_value(0xF), 0);
+    }
+
+    #[test]
+    fn store_increments_i_quirk() {
+
+        let mut chip = Chip8::with_quirks(Quirks::vip());
+        chip.registers.set_i(0x300);
+        chip.set_instruction(0x200, 0xF255);
+
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.registers.get_i(), 0x303);
+    }
+
+    #[test]
+    fn jump_offset_quirk() {
+
+        let mut chip = Chip8::with_quirks(Quirks::schip());
+        chip.registers.set_value(0, 0x10);
+        chip.registers.set_value(3, 0x20);
+        chip.set_instruction(0x200, 0xB300);
+
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.pc, 0x320);
+    }
+
+    #[test]
+    fn logic_resets_vf_quirk() {
+
+        let mut chip = Chip8::with_quirks(Quirks::vip());
+        chip.registers.set_value(0xF, 1);
+        chip.set_instruction(0x200, 0x8011);
+
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.registers.get_value(0xF), 0);
+    }
+
+    #[test]
+    fn draw_waits_for_vblank_quirk() {
+
+        let mut chip = Chip8::with_quirks(Quirks::vip());
+        chip.set_instruction(0x200, 0xD001);
+        chip.set_instruction(0x202, 0x6001);
+
+        cycle(&mut chip).unwrap();
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.pc, 0x202);
+
+        chip.vblank();
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.pc, 0x204);
+    }
+
+    #[test]
+    fn wait_key_completes_on_release() {
+
+        let mut chip = Chip8::new();
+        chip.keyboard.set_latch(LatchPolicy::Off);
+        chip.set_instruction(0x200, 0xF30A);
+        chip.set_key(0x1, true);
+
+        // the key held from before doesn't count
+        cycle(&mut chip).unwrap();
+        chip.set_key(0x1, false);
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.pc, 0x200);
+
+        chip.set_key(0x7, true);
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.pc, 0x200);
+        assert!(chip.is_beeping());
+
+        chip.set_key(0x7, false);
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.pc, 0x202);
+        assert_eq!(chip.registers.get_value(3), 0x7);
+        assert!(!chip.is_beeping());
+    }
+
+    #[test]
+    fn wait_key_on_press_quirk() {
+
+        let mut chip = Chip8::with_quirks(Quirks { wait_key_on_press: true, ..Quirks::default() });
+        chip.set_instruction(0x200, 0xF30A);
+        chip.set_key(0x9, true);
+        chip.set_key(0x4, true);
+
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.pc, 0x202);
+        assert_eq!(chip.registers.get_value(3), 0x4);
+        assert!(!chip.is_beeping());
+    }
+
+    #[test]
+    fn exit_stops_the_machine() {
+
+        let mut chip = Chip8::new();
+        chip.set_instruction(0x200, 0x00FD);
+
+        cycle(&mut chip).unwrap();
+        cycle(&mut chip).unwrap();
+
+        assert!(chip.exited);
+        assert!(chip.is_halted());
+        assert_eq!(chip.pc, 0x200);
+    }
+
+    #[test]
+    fn high_res_wide_sprite() {
+
+        let mut chip = Chip8::new();
+        chip.set_instruction(0x200, 0x00FF);
+        chip.set_instruction(0x202, 0xD010);
+        chip.registers.set_value(0, 120);
+        chip.registers.set_value(1, 60);
+        chip.registers.set_i(0x300);
+        for addr in 0x300..0x320 {
+            chip.memory[addr] = 0xFF;
+        }
+
+        cycle(&mut chip).unwrap();
+        cycle(&mut chip).unwrap();
+
+        assert_eq!(chip.display.read_pixels().len(), 128 * 64);
+        assert_eq!(chip.display.read_pixels()[60 * 128 + 120], 1);
+        // wraps around both edges
+        assert_eq!(chip.display.read_pixels()[3 * 128 + 7], 1);
+    }
+
+    #[test]
+    fn rpl_flags_round_trip() {
+
+        let mut chip = Chip8::new();
+        chip.registers.set_value(0, 1);
+        chip.registers.set_value(3, 4);
+        chip.set_instruction(0x200, 0xF375);
+        chip.set_instruction(0x202, 0x6000);
+        chip.set_instruction(0x204, 0xF385);
+
+        for _ in 0..3 {
+            cycle(&mut chip).unwrap();
+        }
+
+        assert_eq!(chip.registers.get_value(0), 1);
+        assert_eq!(chip.registers.get_value(3), 4);
+    }
+
+    #[test]
+    fn big_font_address() {
+
+        let mut chip = Chip8::new();
+        chip.registers.set_value(2, 3);
+        chip.set_instruction(0x200, 0xF230);
+
+        cycle(&mut chip).unwrap();
+
+        assert_eq!(chip.registers.get_i(), BIG_FONT_ADDR + 30);
+        assert_eq!(chip.memory[chip.registers.get_i() as usize], 0x3C);
+    }
+
+    fn xo_chip() -> Chip8 {
+        let platform = Platform::XoChip;
+        Chip8::for_platform(platform, platform.quirks())
+    }
+
+    #[test]
+    fn long_load_addresses_all_memory() {
+
+        let mut chip = xo_chip();
+        chip.set_instruction(0x200, 0xF000);
+        chip.set_instruction(0x202, 0xFFF0);
+
+        cycle(&mut chip).unwrap();
+
+        assert_eq!(chip.registers.get_i(), 0xFFF0);
+        assert_eq!(chip.pc, 0x204);
+    }
+
+    #[test]
+    fn skip_steps_over_long_load() {
+
+        let mut chip = xo_chip();
+        chip.set_instruction(0x200, 0x3000);
+        chip.set_instruction(0x202, 0xF000);
+        chip.set_instruction(0x204, 0x1234);
+
+        cycle(&mut chip).unwrap();
+
+        assert_eq!(chip.pc, 0x206);
+    }
+
+    #[test]
+    fn skip_wraps_around_the_end_of_memory() {
+
+        let mut chip = xo_chip();
+        chip.pc = 0xFFFC;
+        chip.set_instruction(0xFFFC, 0x3000);
+        chip.set_instruction(0xFFFE, 0x00E0);
+
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.pc, 0x0000);
+
+        // a long load at the end is skipped into the start of memory
+        chip.pc = 0xFFFC;
+        chip.set_instruction(0xFFFE, 0xF000);
+
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.pc, 0x0002);
+    }
+
+    #[test]
+    fn save_and_load_register_range() {
+
+        let mut chip = xo_chip();
+        chip.registers.set_i(0x400);
+        chip.registers.set_value(2, 0x22);
+        chip.registers.set_value(3, 0x33);
+        chip.set_instruction(0x200, 0x5232);
+        chip.set_instruction(0x202, 0x5323);
+
+        cycle(&mut chip).unwrap();
+        assert_eq!(&chip.memory[0x400..0x402], &[0x22, 0x33]);
+        assert_eq!(chip.registers.get_i(), 0x400);
+
+        // reversed, V3 gets the first byte
+        cycle(&mut chip).unwrap();
+        assert_eq!(chip.registers.get_value(3), 0x22);
+        assert_eq!(chip.registers.get_value(2), 0x33);
+    }
+
+    #[test]
+    fn draw_to_both_planes() {
+
+        let mut chip = xo_chip();
+        chip.registers.set_i(0x300);
+        chip.memory[0x300] = 0x80;
+        chip.memory[0x301] = 0xC0;
+        chip.set_instruction(0x200, 0xF301);
+        chip.set_instruction(0x202, 0xD001);
+
+        cycle(&mut chip).unwrap();
+        cycle(&mut chip).unwrap();
+
+        assert_eq!(&chip.display.read_pixels()[..3], &[3, 2, 0]);
+    }
+
+    #[test]
+    fn audio_pattern_and_pitch() {
+
+        let mut chip = xo_chip();
+        chip.registers.set_i(0x300);
+        chip.memory[0x30F] = 0xAA;
+        chip.registers.set_value(1, 100);
+        chip.set_instruction(0x200, 0xF002);
+        chip.set_instruction(0x202, 0xF13A);
+
+        cycle(&mut chip).unwrap();
+        cycle(&mut chip).unwrap();
+
+        assert_eq!(chip.audio_pattern.unwrap()[15], 0xAA);
+        assert_eq!(chip.audio_pitch, 100);
+    }
+}
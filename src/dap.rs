@@ -0,0 +1,658 @@
+//! Debug Adapter Protocol support, so roms can be debugged from an editor.
+//!
+//! `Session` turns DAP requests into `Debugger` calls on a `Chip8` and queues
+//! the responses and events to send back. It knows nothing about the
+//! transport, `read_message` and `write_message` do the framing for any
+//! reader and writer, and the `chip8-dap` binary connects them to stdio or a
+//! TCP port.
+//!
+//! There is one thread, the machine. Breakpoints can be set by address, or by
+//! line when the program is a `.ch8t` text file or `.asm` source. `.8o`
+//! sources are compiled too, but only take breakpoints by address.
+
+use crate::assembler;
+use crate::ch8t;
+use crate::chip::{Chip8, Platform, Program};
+use crate::debugger::{Debugger, Stop};
+use crate::instructions;
+use crate::octo;
+use crate::quirks::Quirks;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+const THREAD_ID: u64 = 1;
+const REGISTERS_REF: u64 = 1;
+const TIMERS_REF: u64 = 2;
+
+/// Instructions per frame when the launch configuration doesn't say.
+pub const DEFAULT_CYCLES_PER_FRAME: u32 = 13;
+
+
+/// Read one message, None at the end of the stream.
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+
+/// A `.ch8t` or `.asm` program being debugged, for line breakpoints.
+struct Source {
+    path: String,
+    /// The address each line starts at, None for lines without code.
+    lines: Vec<Option<u16>>,
+}
+
+impl Source {
+
+    fn line_address(&self, line: usize) -> Option<u16> {
+        // a breakpoint on a line without code moves to the next line with code
+        self.lines.iter().skip(line.checked_sub(1)?).find_map(|addr| *addr)
+    }
+
+    /// The 1 based line holding the instruction at `addr`, the closest line
+    /// starting before it as `@addr` can move lines out of order.
+    fn address_line(&self, addr: u16) -> Option<usize> {
+        self.lines.iter().enumerate()
+            .filter_map(|(i, start)| start.map(|start| (i, start)))
+            .filter(|(_, start)| *start <= addr)
+            .max_by_key(|(_, start)| *start)
+            .map(|(i, _)| i + 1)
+    }
+}
+
+
+pub struct Session {
+    chip: Option<Chip8>,
+    debugger: Debugger,
+    source: Option<Source>,
+    line_breakpoints: BTreeSet<u16>,
+    instruction_breakpoints: BTreeSet<u16>,
+    stop_on_entry: bool,
+    seq: u64,
+    outgoing: Vec<Value>,
+    finished: bool,
+}
+
+impl Session {
+
+    pub fn new() -> Self {
+        Self {
+            chip: None,
+            debugger: Debugger::new(DEFAULT_CYCLES_PER_FRAME),
+            source: None,
+            line_breakpoints: BTreeSet::new(),
+            instruction_breakpoints: BTreeSet::new(),
+            stop_on_entry: false,
+            seq: 0,
+            outgoing: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// True while the program runs and `run_frame` should be called at 60 Hz.
+    pub fn is_running(&self) -> bool {
+        self.chip.is_some() && !self.debugger.paused && !self.finished
+    }
+
+    /// The client disconnected or the program exited.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Responses and events queued since the last call.
+    pub fn take_messages(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Run one frame, or until a breakpoint or step stops it.
+    pub fn run_frame(&mut self) {
+        let chip = match self.chip.as_mut() {
+            Some(chip) => chip,
+            None => return,
+        };
+
+        match self.debugger.run_frame(chip) {
+            Ok(Some(Stop::Breakpoint(_))) => self.stopped("breakpoint", None),
+            Ok(Some(Stop::Step)) => self.stopped("step", None),
+            Ok(None) => {},
+            Err(fault) => self.stopped("exception", Some(fault.to_string())),
+        }
+
+        if self.chip.as_ref().is_some_and(|chip| chip.exited) {
+            self.event("exited", json!({ "exitCode": 0 }));
+            self.event("terminated", json!({}));
+            self.finished = true;
+        }
+    }
+
+    pub fn handle(&mut self, request: &Value) {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsReadMemoryRequest": true,
+                "supportsSteppingGranularity": false,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => Ok(json!({})),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => self.chip().map(|chip| self.stack_trace(chip)),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Timers", "variablesReference": TIMERS_REF, "expensive": false },
+            ]})),
+            "variables" => self.chip().map(|chip| variables(chip, args["variablesReference"].as_u64().unwrap_or(0))),
+            "readMemory" => self.chip().and_then(|chip| read_memory(chip, args)),
+            "continue" => self.chip().map(|_| json!({ "allThreadsContinued": true })),
+            "next" | "stepIn" | "stepOut" | "pause" => self.chip().map(|_| json!({})),
+            "disconnect" => {
+                self.finished = true;
+                Ok(json!({}))
+            },
+            _ => Err(format!("unsupported request '{}'", command)),
+        };
+
+        match result {
+            Ok(body) => self.respond(request, true, None, body),
+            Err(message) => {
+                self.respond(request, false, Some(message), json!({}));
+                return;
+            },
+        }
+
+        // events go out after the response to the request causing them
+        match command {
+            "initialize" => self.event("initialized", json!({})),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                }
+                else {
+                    // not resuming, so a breakpoint on the first instruction stops it
+                    self.debugger.paused = false;
+                }
+            },
+            "continue" => self.debugger.resume(),
+            "pause" => {
+                self.debugger.pause();
+                self.stopped("pause", None);
+            },
+            "next" => {
+                let chip = self.chip.as_mut().unwrap();
+                match self.debugger.step_over(chip) {
+                    Ok(Some(_)) => self.stopped("step", None),
+                    Ok(None) => {},
+                    Err(fault) => self.stopped("exception", Some(fault.to_string())),
+                }
+            },
+            "stepIn" => {
+                let chip = self.chip.as_mut().unwrap();
+                self.debugger.pause();
+                match self.debugger.step(chip) {
+                    Ok(()) => self.stopped("step", None),
+                    Err(fault) => self.stopped("exception", Some(fault.to_string())),
+                }
+            },
+            "stepOut" => self.debugger.step_out(self.chip.as_ref().unwrap()),
+            _ => {},
+        }
+    }
+
+    fn chip(&self) -> Result<&Chip8, String> {
+        self.chip.as_ref().ok_or_else(|| "no program launched".to_string())
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["program"].as_str().ok_or("launch needs a program")?;
+
+        let platform = match args["platform"].as_str() {
+            Some(name) => Platform::from_name(name).ok_or_else(|| format!("unknown platform '{}'", name))?,
+            None => Platform::Chip8,
+        };
+        let quirks = match args["quirks"].as_str() {
+            Some(name) => Quirks::from_name(name).ok_or_else(|| format!("unknown quirks profile '{}'", name))?,
+            None => platform.quirks(),
+        };
+        let cycles = args["cycles"].as_u64().map_or(DEFAULT_CYCLES_PER_FRAME, |cycles| cycles as u32);
+
+        let (program, source) = load_program(path)?;
+        self.source = source;
+
+        let mut chip = Chip8::for_platform(platform, quirks);
+        chip.seed_rng(args["seed"].as_u64().unwrap_or(0));
+        chip.load_program(&program).map_err(|err| format!("{}: {}", path, err))?;
+
+        let breakpoints = std::mem::take(&mut self.debugger.breakpoints);
+        self.debugger = Debugger::new(cycles);
+        self.debugger.breakpoints = breakpoints;
+
+        self.chip = Some(chip);
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let requested: Vec<usize> = args["breakpoints"].as_array()
+            .map(|list| list.iter().filter_map(|bp| bp["line"].as_u64()).map(|line| line as usize).collect())
+            .unwrap_or_default();
+
+        // only the launched text or assembler program has lines we know
+        // addresses for
+        let path = args["source"]["path"].as_str().unwrap_or("");
+        let source = self.source.as_ref().filter(|source| source.path == path);
+
+        self.line_breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for line in requested {
+            match source.and_then(|source| source.line_address(line).map(|addr| (source, addr))) {
+                Some((source, addr)) => {
+                    self.line_breakpoints.insert(addr);
+                    breakpoints.push(json!({
+                        "verified": true,
+                        "line": source.address_line(addr),
+                        "instructionReference": address_reference(addr),
+                    }));
+                },
+                None => breakpoints.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "no code at this line",
+                })),
+            }
+        }
+
+        self.update_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        self.instruction_breakpoints.clear();
+        let mut breakpoints = Vec::new();
+
+        for bp in args["breakpoints"].as_array().into_iter().flatten() {
+            let addr = bp["instructionReference"].as_str().and_then(parse_reference)
+                .map(|addr| addr as i64 + bp["offset"].as_i64().unwrap_or(0))
+                .filter(|addr| (0..=0xFFFF).contains(addr));
+
+            match addr {
+                Some(addr) => {
+                    self.instruction_breakpoints.insert(addr as u16);
+                    breakpoints.push(json!({ "verified": true, "instructionReference": address_reference(addr as u16) }));
+                },
+                None => breakpoints.push(json!({ "verified": false, "message": "invalid address" })),
+            }
+        }
+
+        self.update_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn update_breakpoints(&mut self) {
+        self.debugger.breakpoints = self.line_breakpoints.union(&self.instruction_breakpoints).copied().collect();
+    }
+
+    /// The current instruction, then the `Call` of every return address on
+    /// the stack.
+    fn stack_trace(&self, chip: &Chip8) -> Value {
+        let calls = chip.stack[..chip.sp as usize].iter().rev().map(|addr| addr.wrapping_sub(2));
+
+        let frames: Vec<Value> = std::iter::once(chip.pc).chain(calls).enumerate()
+            .map(|(id, addr)| {
+                let name = match instructions::decode(&chip.memory, addr as usize) {
+                    Ok(instr) => format!("{:03X} {}", addr, instr),
+                    Err(_) => format!("{:03X}", addr),
+                };
+
+                let mut frame = json!({
+                    "id": id,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": address_reference(addr),
+                });
+
+                if let Some(source) = self.source.as_ref() {
+                    if let Some(line) = source.address_line(addr) {
+                        frame["source"] = json!({ "path": source.path });
+                        frame["line"] = json!(line);
+                        frame["column"] = json!(1);
+                    }
+                }
+
+                frame
+            })
+            .collect();
+
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) {
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+            body["text"] = json!(description);
+        }
+        self.event("stopped", body);
+    }
+
+    fn respond(&mut self, request: &Value, success: bool, message: Option<String>, body: Value) {
+        self.seq += 1;
+        let mut response = json!({
+            "seq": self.seq,
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": success,
+            "body": body,
+        });
+        if let Some(message) = message {
+            response["message"] = json!(message);
+        }
+        self.outgoing.push(response);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.seq += 1;
+        self.outgoing.push(json!({ "seq": self.seq, "type": "event", "event": event, "body": body }));
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// The program at `path`, `.asm` and `.8o` sources compiled the way the
+/// emulator runs them, with its lines when they are known.
+fn load_program(path: &str) -> Result<(Program, Option<Source>), String> {
+    if path.ends_with(".asm") {
+        let assembled = assembler::assemble_file_with_lines(path.as_ref()).map_err(|errors| {
+            errors.iter().map(|err| err.to_string()).collect::<Vec<_>>().join("\n")
+        })?;
+        let source = Source { path: path.to_string(), lines: assembled.lines };
+        return Ok((Program::binary(assembled.image), Some(source)));
+    }
+
+    if path.ends_with(".8o") {
+        let source = fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+        let rom = octo::compile(&source).map_err(|err| format!("{}:{}", path, err))?;
+        return Ok((Program::binary(rom), None));
+    }
+
+    let data = fs::read(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+    let program = Program::detect(data);
+    let source = match &program {
+        Program::Text(text) => {
+            let text = ch8t::parse(text).map_err(|err| format!("{}:{}", path, err))?;
+            Some(Source { path: path.to_string(), lines: text.lines })
+        },
+        _ => None,
+    };
+    Ok((program, source))
+}
+
+
+fn variables(chip: &Chip8, reference: u64) -> Value {
+    let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+
+    let variables: Vec<Value> = match reference {
+        REGISTERS_REF => {
+            let mut list: Vec<Value> = (0..16)
+                .map(|reg| variable(format!("V{:X}", reg), format!("0x{:02X}", chip.registers.get_value(reg))))
+                .collect();
+
+            let mut i = variable("I".to_string(), address_reference(chip.registers.get_i()));
+            i["memoryReference"] = json!(address_reference(chip.registers.get_i()));
+            list.push(i);
+            list.push(variable("PC".to_string(), address_reference(chip.pc)));
+            list.push(variable("SP".to_string(), chip.sp.to_string()));
+            list
+        },
+        TIMERS_REF => vec![
+            variable("delay".to_string(), chip.registers.get_delay().to_string()),
+            variable("sound".to_string(), chip.registers.get_sound().to_string()),
+        ],
+        _ => Vec::new(),
+    };
+
+    json!({ "variables": variables })
+}
+
+
+fn read_memory(chip: &Chip8, args: &Value) -> Result<Value, String> {
+    let base = args["memoryReference"].as_str().and_then(parse_reference)
+        .ok_or("invalid memory reference")?;
+    let start = base as i64 + args["offset"].as_i64().unwrap_or(0);
+    let count = args["count"].as_u64().unwrap_or(0) as usize;
+
+    if start < 0 || start as usize >= chip.memory.len() {
+        return Ok(json!({ "address": format!("0x{:X}", start.max(0)), "unreadableBytes": count }));
+    }
+
+    let start = start as usize;
+    let end = (start + count).min(chip.memory.len());
+    Ok(json!({
+        "address": format!("0x{:03X}", start),
+        "data": base64(&chip.memory[start..end]),
+        "unreadableBytes": count - (end - start),
+    }))
+}
+
+
+fn address_reference(addr: u16) -> String {
+    format!("0x{:03X}", addr)
+}
+
+
+/// Hex with a 0x prefix as sent out, plain decimal is accepted too.
+fn parse_reference(reference: &str) -> Option<u32> {
+    match reference.strip_prefix("0x").or_else(|| reference.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => reference.parse().ok(),
+    }
+}
+
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            }
+            else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(session: &mut Session, command: &str, arguments: Value) -> Vec<Value> {
+        session.handle(&json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments }));
+        session.take_messages()
+    }
+
+    fn launch(name: &str, program: &str) -> (Session, String) {
+        launch_file(&format!("{}.ch8t", name), program)
+    }
+
+    fn launch_file(name: &str, program: &str) -> (Session, String) {
+        let path = std::env::temp_dir().join(format!("chip8-dap-{}-{}", std::process::id(), name));
+        fs::write(&path, program).unwrap();
+        let path = path.to_str().unwrap().to_string();
+
+        let mut session = Session::new();
+        request(&mut session, "initialize", json!({}));
+        let messages = request(&mut session, "launch", json!({ "program": path, "cycles": 10 }));
+        assert_eq!(messages[0]["success"], json!(true));
+
+        (session, path)
+    }
+
+    fn run_until_stopped(session: &mut Session) -> Value {
+        for _ in 0..100 {
+            session.run_frame();
+            if let Some(event) = session.take_messages().into_iter().find(|m| m["event"] == "stopped") {
+                return event;
+            }
+        }
+        panic!("never stopped");
+    }
+
+    #[test]
+    fn message_framing() {
+        let mut out = Vec::new();
+        write_message(&mut out, &json!({ "seq": 1 })).unwrap();
+        assert_eq!(out, b"Content-Length: 9\r\n\r\n{\"seq\":1}");
+
+        let message = read_message(&mut &out[..]).unwrap();
+        assert_eq!(message, Some(json!({ "seq": 1 })));
+        assert_eq!(read_message(&mut &b""[..]).unwrap(), None);
+    }
+
+    #[test]
+    fn line_addresses() {
+        let lines = ch8t::parse("// header\n6001 6102\n\n7001\n").unwrap().lines;
+        assert_eq!(lines, vec![None, Some(0x200), None, Some(0x204)]);
+    }
+
+    #[test]
+    fn stops_at_line_breakpoint() {
+        let (mut session, path) = launch("line", "6001\n6102\n\n7001\n1206\n");
+
+        let messages = request(&mut session, "setBreakpoints", json!({ "source": { "path": path }, "breakpoints": [{ "line": 3 }] }));
+        assert_eq!(messages[0]["body"]["breakpoints"][0]["line"], json!(4));
+
+        request(&mut session, "configurationDone", json!({}));
+        let stopped = run_until_stopped(&mut session);
+        assert_eq!(stopped["body"]["reason"], json!("breakpoint"));
+
+        let messages = request(&mut session, "stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(messages[0]["body"]["stackFrames"][0]["line"], json!(4));
+        assert_eq!(messages[0]["body"]["stackFrames"][0]["instructionPointerReference"], json!("0x204"));
+
+        let messages = request(&mut session, "variables", json!({ "variablesReference": REGISTERS_REF }));
+        assert_eq!(messages[0]["body"]["variables"][1]["value"], json!("0x02"));
+    }
+
+    #[test]
+    fn stops_at_assembler_line() {
+        let (mut session, path) = launch_file("line.asm", "; count up\nstart:\n  LD V0, 1\n\n  ADD V0, 1\n  JP start\n");
+
+        let messages = request(&mut session, "setBreakpoints", json!({ "source": { "path": path }, "breakpoints": [{ "line": 4 }] }));
+        assert_eq!(messages[0]["body"]["breakpoints"][0]["line"], json!(5));
+
+        request(&mut session, "configurationDone", json!({}));
+        run_until_stopped(&mut session);
+
+        let messages = request(&mut session, "stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(messages[0]["body"]["stackFrames"][0]["line"], json!(5));
+        assert_eq!(messages[0]["body"]["stackFrames"][0]["instructionPointerReference"], json!("0x202"));
+    }
+
+    #[test]
+    fn launches_octo_source() {
+        let (mut session, _) = launch_file("count.8o", ": main\n  v0 := 7\n  loop again\n");
+
+        request(&mut session, "configurationDone", json!({}));
+        session.run_frame();
+        let messages = request(&mut session, "variables", json!({ "variablesReference": REGISTERS_REF }));
+        assert_eq!(messages[0]["body"]["variables"][0]["value"], json!("0x07"));
+    }
+
+    #[test]
+    fn stack_trace_after_a_fault() {
+        // jump to the last byte of memory, fetching there faults
+        let (mut session, _) = launch("fault", "1FFF");
+
+        request(&mut session, "configurationDone", json!({}));
+        let stopped = run_until_stopped(&mut session);
+        assert_eq!(stopped["body"]["reason"], json!("exception"));
+
+        let messages = request(&mut session, "stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(messages[0]["body"]["stackFrames"][0]["name"], json!("FFF"));
+
+        let messages = request(&mut session, "next", json!({ "threadId": 1 }));
+        assert_eq!(messages[0]["success"], json!(true));
+        assert_eq!(messages[1]["body"]["reason"], json!("exception"));
+    }
+
+    #[test]
+    fn step_over_call_and_read_memory() {
+        // call 206, loop; at 206: V0 = 7, return
+        let (mut session, _) = launch("step", "2206 1202 0000 6007 00EE");
+
+        request(&mut session, "setInstructionBreakpoints", json!({ "breakpoints": [{ "instructionReference": "0x200" }] }));
+        request(&mut session, "configurationDone", json!({}));
+        run_until_stopped(&mut session);
+
+        request(&mut session, "next", json!({ "threadId": 1 }));
+        let stopped = run_until_stopped(&mut session);
+        assert_eq!(stopped["body"]["reason"], json!("step"));
+
+        let messages = request(&mut session, "variables", json!({ "variablesReference": REGISTERS_REF }));
+        assert_eq!(messages[0]["body"]["variables"][0]["value"], json!("0x07"));
+        assert_eq!(messages[0]["body"]["variables"][17]["value"], json!("0x202"));
+
+        let messages = request(&mut session, "readMemory", json!({ "memoryReference": "0x200", "count": 4 }));
+        assert_eq!(messages[0]["body"]["data"], json!("IgYSAg=="));
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b"M"), "TQ==");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"Man"), "TWFu");
+    }
+}
@@ -0,0 +1,275 @@
+//! ROM database picking the settings a game needs, in the layout of the
+//! community chip-8-database: `sha1-hashes.json` maps the SHA-1 of a program
+//! image to its entry in `programs.json`, and `platforms.json` has the quirks
+//! and speed of each platform a rom can list.
+//!
+//! The files in `database/` are built in. The `memoryIncrementByX` quirk has
+//! no equivalent here and is ignored.
+
+use crate::chip::Platform;
+use crate::quirks::Quirks;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+const PROGRAMS: &str = include_str!("../database/programs.json");
+const HASHES: &str = include_str!("../database/sha1-hashes.json");
+const PLATFORMS: &str = include_str!("../database/platforms.json");
+
+/// The buttons key hints are listed in, any others follow sorted by name.
+const BUTTONS: [&str; 6] = ["up", "down", "left", "right", "a", "b"];
+
+
+#[derive(Debug)]
+pub struct DatabaseError(String);
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid rom database: {}", self.0)
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+
+/// What the database knows about a rom.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    /// The first platform listed that can be emulated, None when there is
+    /// none.
+    pub platform: Option<Platform>,
+    /// The quirks of that platform with the overrides for this rom.
+    pub quirks: Option<Quirks>,
+    /// Instructions per frame, the "tickrate".
+    pub cycles_per_frame: Option<u32>,
+    /// The background then the pixel colours, as many as the rom sets.
+    pub colors: Vec<(u8, u8, u8)>,
+    /// The hex key for each button the game uses, like ("left", 4).
+    pub keys: Vec<(String, u8)>,
+}
+
+impl RomInfo {
+
+    /// The keys as text for showing to the player, "left 4, right 6".
+    pub fn key_hints(&self) -> String {
+        self.keys.iter()
+            .map(|(button, key)| format!("{} {:X}", button, key))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+
+pub struct RomDatabase {
+    hashes: HashMap<String, usize>,
+    programs: Vec<Value>,
+    platforms: HashMap<String, Value>,
+}
+
+impl RomDatabase {
+
+    /// The database shipped in `database/`.
+    pub fn builtin() -> Self {
+        Self::from_json(PROGRAMS, HASHES, PLATFORMS).expect("the built in rom database is valid")
+    }
+
+    /// A database from the contents of `programs.json`, `sha1-hashes.json`
+    /// and `platforms.json`.
+    pub fn from_json(programs: &str, hashes: &str, platforms: &str) -> Result<Self, DatabaseError> {
+        let parse = |name: &str, json: &str| -> Result<Value, DatabaseError> {
+            serde_json::from_str(json).map_err(|err| DatabaseError(format!("{}: {}", name, err)))
+        };
+
+        let programs = match parse("programs.json", programs)? {
+            Value::Array(programs) => programs,
+            _ => return Err(DatabaseError("programs.json is not a list".to_string())),
+        };
+
+        let hashes = match parse("sha1-hashes.json", hashes)? {
+            Value::Object(hashes) => hashes.into_iter()
+                .map(|(hash, index)| match index.as_u64() {
+                    Some(index) if (index as usize) < programs.len() => Ok((hash, index as usize)),
+                    _ => Err(DatabaseError(format!("sha1-hashes.json: {} has no program", hash))),
+                })
+                .collect::<Result<_, _>>()?,
+            _ => return Err(DatabaseError("sha1-hashes.json is not an object".to_string())),
+        };
+
+        let platforms = match parse("platforms.json", platforms)? {
+            Value::Array(platforms) => platforms.into_iter()
+                .filter_map(|platform| Some((platform["id"].as_str()?.to_string(), platform)))
+                .collect(),
+            _ => return Err(DatabaseError("platforms.json is not a list".to_string())),
+        };
+
+        Ok(Self { hashes, programs, platforms })
+    }
+
+    /// Look up a program image, as loaded at its load address.
+    pub fn lookup(&self, rom: &[u8]) -> Option<RomInfo> {
+        self.lookup_hash(&sha1(rom))
+    }
+
+    /// Look up a lower case hex SHA-1.
+    pub fn lookup_hash(&self, hash: &str) -> Option<RomInfo> {
+        let program = &self.programs[*self.hashes.get(hash)?];
+        let rom = &program["roms"][hash];
+
+        let platform_id = rom["platforms"].as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .find(|id| platform_from_id(id).is_some());
+        let platform = platform_id.and_then(platform_from_id);
+        let platform_info = platform_id.and_then(|id| self.platforms.get(id));
+
+        let quirks = platform.map(|platform| {
+            let mut quirks = platform.quirks();
+            if let Some(info) = platform_info {
+                apply_quirks(&mut quirks, &info["quirks"]);
+            }
+            apply_quirks(&mut quirks, &rom["quirkyPlatforms"][platform_id.unwrap_or_default()]);
+            quirks
+        });
+
+        let cycles_per_frame = rom["tickrate"].as_u64()
+            .or_else(|| platform_info?["defaultTickrate"].as_u64())
+            .map(|tickrate| tickrate as u32);
+
+        let colors = rom["colors"]["pixels"].as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|color| parse_color(color.as_str()?))
+            .collect();
+
+        let mut keys: Vec<(String, u8)> = rom["keys"].as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(button, key)| Some((button.clone(), key.as_u64().filter(|key| *key < 16)? as u8)))
+            .collect();
+        keys.sort_by_key(|(button, _)| (BUTTONS.iter().position(|b| b == button).unwrap_or(BUTTONS.len()), button.clone()));
+
+        Some(RomInfo {
+            title: program["title"].as_str().unwrap_or("Unknown").to_string(),
+            platform,
+            quirks,
+            cycles_per_frame,
+            colors,
+            keys,
+        })
+    }
+}
+
+
+/// Lower case hex SHA-1 of a program image, the database key.
+pub fn sha1(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
+
+/// The platform emulating a database platform id.
+fn platform_from_id(id: &str) -> Option<Platform> {
+    match id {
+        "originalChip8" | "hybridVIP" | "modernChip8" => Some(Platform::Chip8),
+        "chip48" | "superchip1" | "superchip" => Some(Platform::SuperChip),
+        "xochip" => Some(Platform::XoChip),
+        _ => None,
+    }
+}
+
+/// Set the quirks an object of database quirk flags mentions.
+fn apply_quirks(quirks: &mut Quirks, flags: &Value) {
+    let flag = |name: &str| flags[name].as_bool();
+
+    if let Some(shift) = flag("shift") {
+        quirks.shift_uses_vy = !shift;
+    }
+    if let Some(leave) = flag("memoryLeaveIUnchanged") {
+        quirks.load_store_increments_i = !leave;
+    }
+    if let Some(wrap) = flag("wrap") {
+        quirks.clip_sprites = !wrap;
+    }
+    if let Some(jump) = flag("jump") {
+        quirks.jump_uses_vx = jump;
+    }
+    if let Some(vblank) = flag("vblank") {
+        quirks.draw_waits_for_vblank = vblank;
+    }
+    if let Some(logic) = flag("logic") {
+        quirks.logic_resets_vf = logic;
+    }
+}
+
+/// "#rrggbb" or "#rgb".
+pub(crate) fn parse_color(text: &str) -> Option<(u8, u8, u8)> {
+    let hex = text.strip_prefix('#')?;
+    let value = u32::from_str_radix(hex, 16).ok()?;
+
+    match hex.len() {
+        6 => Some(((value >> 16) as u8, (value >> 8) as u8, value as u8)),
+        3 => {
+            let digit = |shift: u32| ((value >> shift) & 0xF) as u8 * 0x11;
+            Some((digit(8), digit(4), digit(0)))
+        },
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PONG: &str = "b232ef880bd6060fb45fa6effed7edf0ae95670e";
+
+    #[test]
+    fn sha1_of_image() {
+        assert_eq!(sha1(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn builtin_knows_the_bundled_roms() {
+        let info = RomDatabase::builtin().lookup_hash(PONG).unwrap();
+
+        assert_eq!(info.title, "Pong (1 player)");
+        assert_eq!(info.platform, Some(Platform::Chip8));
+        assert_eq!(info.quirks, Some(Quirks::vip()));
+        assert_eq!(info.cycles_per_frame, Some(15));
+        assert_eq!(info.key_hints(), "up 1, down 4");
+
+        assert_eq!(RomDatabase::builtin().lookup(&[0x12, 0x00]), None);
+    }
+
+    #[test]
+    fn rom_settings_override_the_platform() {
+        let programs = r##"[{
+            "title": "Test",
+            "roms": { "abc": {
+                "platforms": ["megachip8", "superchip"],
+                "tickrate": 50,
+                "quirkyPlatforms": { "superchip": { "wrap": true } },
+                "colors": { "pixels": ["#000000", "#ff8000", "#fff"] },
+                "keys": { "a": 10, "right": 6, "left": 4, "player2Up": 1 }
+            }}
+        }]"##;
+        let platforms = r#"[{ "id": "superchip", "defaultTickrate": 30, "quirks": { "shift": true, "logic": true } }]"#;
+        let database = RomDatabase::from_json(programs, r#"{ "abc": 0 }"#, platforms).unwrap();
+        let info = database.lookup_hash("abc").unwrap();
+
+        let mut quirks = Quirks::schip();
+        quirks.logic_resets_vf = true;
+        quirks.clip_sprites = false;
+
+        assert_eq!(info.platform, Some(Platform::SuperChip));
+        assert_eq!(info.quirks, Some(quirks));
+        assert_eq!(info.cycles_per_frame, Some(50));
+        assert_eq!(info.colors, [(0, 0, 0), (255, 128, 0), (255, 255, 255)]);
+        assert_eq!(info.key_hints(), "left 4, right 6, a A, player2Up 1");
+    }
+
+    #[test]
+    fn rejects_hashes_without_program() {
+        assert!(RomDatabase::from_json("[]", r#"{ "abc": 0 }"#, "[]").is_err());
+    }
+}
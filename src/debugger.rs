@@ -0,0 +1,489 @@
+//! A command driven debugger: breakpoints, stepping and inspecting the machine.
+//!
+//! The debugger owns the frame timing while it is attached, so a frame can be
+//! stopped half way at a breakpoint and resumed later without ticking the
+//! timers twice. Commands are parsed from text lines and answer with text, the
+//! frontend only has to feed it lines and print what comes back.
+
+use crate::chip::Chip8;
+use crate::cpu::Fault;
+use crate::instructions::{self, Instruction};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+pub const HELP: &str = "\
+break <addr>          stop before the instruction at addr
+delete <addr>         remove a breakpoint
+breakpoints           list breakpoints
+step [n]              execute n instructions, 1 when not given
+next                  step over a call
+finish                run until the current call returns
+frame                 run to the end of the current frame
+continue              run until a breakpoint
+pause                 stop running
+regs                  show V0-VF, I, timers, pc and sp
+stack                 show the call stack
+mem <addr> [len]      hex dump len bytes, 64 when not given
+set <addr> <byte>...  write bytes to memory
+disasm [addr] [n]     show n instructions from addr, pc when not given
+quit                  exit the emulator
+Numbers are hex, with or without 0x.";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Break(u16),
+    Delete(u16),
+    Breakpoints,
+    Step(u32),
+    Next,
+    Finish,
+    Frame,
+    Continue,
+    Pause,
+    Registers,
+    Stack,
+    Memory { addr: u16, len: u16 },
+    Write { addr: u16, bytes: Vec<u8> },
+    Disasm { addr: Option<u16>, count: u16 },
+    Help,
+    Quit,
+}
+
+impl Command {
+
+    /// Parse a command line, commands can be shortened to their first letter
+    /// where that is unambiguous.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or("empty command")?;
+        let args: Vec<&str> = words.collect();
+
+        let arg = |i: usize| args.get(i).map(|arg| parse_hex(arg)).transpose();
+        let required = |i: usize| arg(i)?.ok_or_else(|| format!("{} needs an address", name));
+
+        let command = match name {
+            "b" | "break" => Command::Break(required(0)?),
+            "d" | "delete" => Command::Delete(required(0)?),
+            "breakpoints" => Command::Breakpoints,
+            "s" | "step" => Command::Step(arg(0)?.unwrap_or(1) as u32),
+            "n" | "next" => Command::Next,
+            "finish" => Command::Finish,
+            "f" | "frame" => Command::Frame,
+            "c" | "continue" => Command::Continue,
+            "p" | "pause" => Command::Pause,
+            "r" | "regs" => Command::Registers,
+            "stack" => Command::Stack,
+            "m" | "mem" => Command::Memory { addr: required(0)?, len: arg(1)?.unwrap_or(64) },
+            "set" => {
+                let addr = required(0)?;
+                let bytes = args[1..].iter()
+                    .map(|arg| parse_hex(arg).and_then(|val| u8::try_from(val).map_err(|_| format!("'{}' is not a byte", arg))))
+                    .collect::<Result<Vec<u8>, String>>()?;
+
+                if bytes.is_empty() {
+                    return Err("set needs bytes to write".to_string());
+                }
+                Command::Write { addr, bytes }
+            },
+            "disasm" => Command::Disasm { addr: arg(0)?, count: arg(1)?.unwrap_or(1) },
+            "h" | "help" => Command::Help,
+            "q" | "quit" => Command::Quit,
+            _ => return Err(format!("unknown command '{}', try help", name)),
+        };
+
+        Ok(command)
+    }
+}
+
+
+fn parse_hex(arg: &str) -> Result<u16, String> {
+    let digits = arg.strip_prefix("0x").unwrap_or(arg);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("'{}' is not a hex number", arg))
+}
+
+
+/// Why running stopped before the end of a frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    Breakpoint(u16),
+    /// A step over or out of a call finished.
+    Step,
+}
+
+
+pub struct Debugger {
+    pub breakpoints: BTreeSet<u16>,
+    /// Not running, only commands change the machine.
+    pub paused: bool,
+    cycles_per_frame: u32,
+    /// Instructions already executed in the current frame.
+    frame_cycles: u32,
+    /// Resuming from a breakpoint, don't stop at it again straight away.
+    skip_breakpoint: bool,
+    /// Stepping over or out of a call, stop once the stack is back down to
+    /// this depth.
+    return_depth: Option<u8>,
+}
+
+impl Debugger {
+
+    /// A paused debugger, so breakpoints can be set before the program runs.
+    pub fn new(cycles_per_frame: u32) -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            paused: true,
+            cycles_per_frame,
+            frame_cycles: 0,
+            skip_breakpoint: false,
+            return_depth: None,
+        }
+    }
+
+    /// Execute one instruction, ending the frame when it was the last one.
+    pub fn step(&mut self, chip: &mut Chip8) -> Result<(), Fault> {
+        if self.frame_cycles == 0 {
+            chip.keyboard.begin_frame();
+        }
+        chip.step()?;
+
+        self.frame_cycles += 1;
+        if self.frame_cycles >= self.cycles_per_frame {
+            self.frame_cycles = 0;
+            chip.vblank();
+        }
+
+        Ok(())
+    }
+
+    /// Run what is left of the current frame. Returns why it stopped early,
+    /// the debugger is then paused.
+    pub fn run_frame(&mut self, chip: &mut Chip8) -> Result<Option<Stop>, Fault> {
+        loop {
+            if self.breakpoints.contains(&chip.pc) && !self.skip_breakpoint && !chip.is_halted() {
+                self.pause();
+                return Ok(Some(Stop::Breakpoint(chip.pc)));
+            }
+            self.skip_breakpoint = false;
+
+            if let Err(fault) = self.step(chip) {
+                self.pause();
+                return Err(fault);
+            }
+
+            if matches!(self.return_depth, Some(depth) if chip.sp <= depth) {
+                self.pause();
+                return Ok(Some(Stop::Step));
+            }
+
+            if self.frame_cycles == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Stop running, a step in progress is abandoned.
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.return_depth = None;
+    }
+
+    /// Run until a breakpoint.
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.skip_breakpoint = true;
+        self.return_depth = None;
+    }
+
+    /// Step one instruction, running a whole call when it is a `Call`. Returns
+    /// `Stop::Step` when done at once, otherwise the debugger now runs until
+    /// the call returns.
+    pub fn step_over(&mut self, chip: &mut Chip8) -> Result<Option<Stop>, Fault> {
+        let at_call = matches!(instructions::decode(&chip.memory, chip.pc as usize), Ok(Instruction::Call(_)));
+        if at_call && !chip.is_halted() {
+            let depth = chip.sp;
+            self.resume();
+            self.return_depth = Some(depth);
+            return Ok(None);
+        }
+
+        self.pause();
+        self.step(chip)?;
+        self.skip_breakpoint = true;
+        Ok(Some(Stop::Step))
+    }
+
+    /// Run until the current call returns, at the top level this is the same
+    /// as continuing.
+    pub fn step_out(&mut self, chip: &Chip8) {
+        self.resume();
+        self.return_depth = chip.sp.checked_sub(1);
+    }
+
+    /// Carry out `command`, returning what to print.
+    pub fn execute(&mut self, chip: &mut Chip8, command: &Command) -> String {
+        let mut out = String::new();
+
+        match command {
+            Command::Break(addr) => {
+                self.breakpoints.insert(*addr);
+                let _ = write!(out, "breakpoint at {:03X}", addr);
+            },
+            Command::Delete(addr) => {
+                if self.breakpoints.remove(addr) {
+                    let _ = write!(out, "deleted breakpoint at {:03X}", addr);
+                }
+                else {
+                    let _ = write!(out, "no breakpoint at {:03X}", addr);
+                }
+            },
+            Command::Breakpoints => {
+                if self.breakpoints.is_empty() {
+                    out.push_str("no breakpoints");
+                }
+                let list: Vec<String> = self.breakpoints.iter().map(|addr| format!("{:03X}", addr)).collect();
+                out.push_str(&list.join(" "));
+            },
+            Command::Step(count) => {
+                self.pause();
+                for _ in 0..*count {
+                    if let Err(fault) = self.step(chip) {
+                        let _ = writeln!(out, "{}", fault);
+                        break;
+                    }
+                }
+                self.skip_breakpoint = true;
+                out.push_str(&disassemble(chip, chip.pc, 1));
+            },
+            Command::Next => {
+                match self.step_over(chip) {
+                    Ok(Some(_)) => out.push_str(&disassemble(chip, chip.pc, 1)),
+                    Ok(None) => {},
+                    Err(fault) => { let _ = write!(out, "{}", fault); },
+                }
+            },
+            Command::Finish => self.step_out(chip),
+            Command::Frame => {
+                self.pause();
+                self.skip_breakpoint = true;
+                match self.run_frame(chip) {
+                    Ok(Some(Stop::Breakpoint(addr))) => { let _ = writeln!(out, "breakpoint at {:03X}", addr); },
+                    Ok(_) => {},
+                    Err(fault) => { let _ = writeln!(out, "{}", fault); },
+                }
+                self.paused = true;
+                self.skip_breakpoint = true;
+                out.push_str(&disassemble(chip, chip.pc, 1));
+            },
+            Command::Continue => self.resume(),
+            Command::Pause => {
+                self.pause();
+                self.skip_breakpoint = true;
+                out.push_str(&disassemble(chip, chip.pc, 1));
+            },
+            Command::Registers => out.push_str(&registers(chip)),
+            Command::Stack => out.push_str(&stack(chip)),
+            Command::Memory { addr, len } => out.push_str(&hex_dump(&chip.memory, *addr as usize, *len as usize)),
+            Command::Write { addr, bytes } => {
+                let start = *addr as usize;
+                match chip.memory.get_mut(start..start + bytes.len()) {
+                    Some(memory) => {
+                        memory.copy_from_slice(bytes);
+                        let _ = write!(out, "wrote {} bytes at {:03X}", bytes.len(), addr);
+                    },
+                    None => { let _ = write!(out, "{:03X} is outside memory", addr); },
+                }
+            },
+            Command::Disasm { addr, count } => out.push_str(&disassemble(chip, addr.unwrap_or(chip.pc), *count)),
+            Command::Help => out.push_str(HELP),
+            Command::Quit => {},
+        }
+
+        out
+    }
+}
+
+
+/// V0-VF on two lines followed by I, the timers, pc and sp.
+pub fn registers(chip: &Chip8) -> String {
+    let mut out = String::new();
+
+    for row in 0..2 {
+        let regs: Vec<String> = (row * 8..row * 8 + 8)
+            .map(|reg| format!("V{:X}={:02X}", reg, chip.registers.get_value(reg)))
+            .collect();
+        let _ = writeln!(out, "{}", regs.join(" "));
+    }
+
+    let _ = write!(out, "I={:03X} DT={:02X} ST={:02X} PC={:03X} SP={:X}",
+        chip.registers.get_i(), chip.registers.get_delay(), chip.registers.get_sound(), chip.pc, chip.sp);
+
+    out
+}
+
+
+/// Return addresses, innermost call first.
+pub fn stack(chip: &Chip8) -> String {
+    if chip.sp == 0 {
+        return "stack is empty".to_string();
+    }
+
+    let frames: Vec<String> = chip.stack[..chip.sp as usize].iter().enumerate().rev()
+        .map(|(depth, addr)| format!("#{:X} {:03X}", depth, addr))
+        .collect();
+
+    frames.join("\n")
+}
+
+
+/// 16 bytes a line, each line starting with its address.
+pub fn hex_dump(memory: &[u8], addr: usize, len: usize) -> String {
+    let start = addr.min(memory.len());
+    let end = addr.saturating_add(len).min(memory.len());
+
+    let lines: Vec<String> = memory[start..end].chunks(16).enumerate()
+        .map(|(i, line)| {
+            let bytes: Vec<String> = line.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!("{:03X}: {}", start + i * 16, bytes.join(" "))
+        })
+        .collect();
+
+    lines.join("\n")
+}
+
+
+/// `count` decoded instructions from `addr`, marking the one at pc.
+pub fn disassemble(chip: &Chip8, addr: u16, count: u16) -> String {
+    let mut addr = addr as usize;
+    let mut lines = Vec::new();
+
+    for _ in 0..count {
+        let Some(bytes) = chip.memory.get(addr..addr + 2) else {
+            break;
+        };
+
+        let marker = if addr == chip.pc as usize { ">" } else { " " };
+        let size = match instructions::decode(&chip.memory, addr) {
+            Ok(instr) => {
+                lines.push(format!("{}{:03X}: {:02X}{:02X}  {}", marker, addr, bytes[0], bytes[1], instr));
+                instr.size() as usize
+            },
+            Err(_) => {
+                lines.push(format!("{}{:03X}: {:02X}{:02X}  ???", marker, addr, bytes[0], bytes[1]));
+                2
+            },
+        };
+        addr += size;
+    }
+
+    lines.join("\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::Program;
+
+    fn chip(program: &str) -> Chip8 {
+        let mut chip = Chip8::new();
+        chip.load_program(&Program::Text(program.to_string())).unwrap();
+        chip
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("b 0x204"), Ok(Command::Break(0x204)));
+        assert_eq!(Command::parse("step"), Ok(Command::Step(1)));
+        assert_eq!(Command::parse("s a"), Ok(Command::Step(10)));
+        assert_eq!(Command::parse("mem 200 10"), Ok(Command::Memory { addr: 0x200, len: 0x10 }));
+        assert_eq!(Command::parse("set 300 12 ab"), Ok(Command::Write { addr: 0x300, bytes: vec![0x12, 0xAB] }));
+        assert!(Command::parse("set 300 123").is_err());
+        assert!(Command::parse("break").is_err());
+        assert!(Command::parse("jump 200").is_err());
+    }
+
+    #[test]
+    fn stops_at_breakpoint_and_resumes() {
+        // V0 += 1 three times then loop
+        let mut chip = chip("7001 7001 7001 1206");
+        let mut debugger = Debugger::new(10);
+
+        debugger.execute(&mut chip, &Command::Break(0x204));
+        debugger.execute(&mut chip, &Command::Continue);
+
+        assert_eq!(debugger.run_frame(&mut chip), Ok(Some(Stop::Breakpoint(0x204))));
+        assert!(debugger.paused);
+        assert_eq!(chip.registers.get_value(0), 2);
+
+        debugger.execute(&mut chip, &Command::Continue);
+        assert_eq!(debugger.run_frame(&mut chip), Ok(None));
+        assert_eq!(chip.registers.get_value(0), 3);
+    }
+
+    #[test]
+    fn step_over_and_out_of_calls() {
+        // call 206, V1 = 1, loop; at 206: V0 += 1, V0 += 1, return
+        let mut chip = chip("2206 6101 1204 7001 7001 00EE");
+        let mut debugger = Debugger::new(100);
+
+        assert_eq!(debugger.step_over(&mut chip), Ok(None));
+        assert_eq!(debugger.run_frame(&mut chip), Ok(Some(Stop::Step)));
+        assert_eq!(chip.pc, 0x202);
+        assert_eq!(chip.registers.get_value(0), 2);
+
+        // step into the call again and out of it
+        chip.pc = 0x200;
+        debugger.execute(&mut chip, &Command::Step(2));
+        debugger.step_out(&chip);
+        assert_eq!(debugger.run_frame(&mut chip), Ok(Some(Stop::Step)));
+        assert_eq!(chip.pc, 0x202);
+        assert_eq!(chip.sp, 0);
+    }
+
+    #[test]
+    fn frame_ends_with_timer_tick() {
+        // delay = 5, loop
+        let mut chip = chip("6005 F015 1204");
+        let mut debugger = Debugger::new(4);
+
+        debugger.execute(&mut chip, &Command::Step(2));
+        assert_eq!(chip.registers.get_delay(), 5);
+
+        debugger.execute(&mut chip, &Command::Frame);
+        assert_eq!(chip.registers.get_delay(), 4);
+    }
+
+    #[test]
+    fn step_over_at_the_end_of_memory() {
+        // jump to the last byte, the next fetch faults there
+        let mut chip = chip("1FFF");
+        let mut debugger = Debugger::new(10);
+
+        debugger.step(&mut chip).unwrap();
+        let fault = Fault::PcOutOfBounds { pc: 0xFFF };
+        assert_eq!(debugger.step(&mut chip), Err(fault));
+        assert_eq!(debugger.step_over(&mut chip), Err(fault));
+        assert_eq!(disassemble(&chip, 0xFFE, 2), " FFE: 0000  ???");
+    }
+
+    #[test]
+    fn write_and_dump_memory() {
+        let mut chip = chip("1200");
+        let mut debugger = Debugger::new(10);
+
+        debugger.execute(&mut chip, &Command::Write { addr: 0x300, bytes: vec![0xAB, 0xCD] });
+        assert_eq!(debugger.execute(&mut chip, &Command::Memory { addr: 0x300, len: 3 }), "300: AB CD 00");
+    }
+
+    #[test]
+    fn show_stack_and_disassembly() {
+        // call 206, then at 206 call 20A
+        let mut chip = chip("2206 0000 0000 220A 0000 00EE");
+        let mut debugger = Debugger::new(10);
+
+        debugger.execute(&mut chip, &Command::Step(2));
+        assert_eq!(stack(&chip), "#1 208\n#0 202");
+        assert_eq!(disassemble(&chip, 0x20A, 1), ">20A: 00EE  RET");
+    }
+}
@@ -0,0 +1,235 @@
+//! Recursive disassembler, separating code from data by following the flow
+//! of the program from its entry point.
+//!
+//! Only bytes some path through the program reaches are decoded, everything
+//! else is printed as `db` data. `JP V0, addr` is followed to `addr`, the
+//! other entries of the jump table can't be known without running it.
+
+use crate::instructions::{self, Instruction};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Data bytes per `db` line.
+const DATA_PER_LINE: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum LabelKind {
+    Data,
+    Jump,
+    Sub,
+}
+
+pub struct Disassembly<'a> {
+    rom: &'a [u8],
+    /// Address of the first rom byte, 0x200 for normal programs.
+    origin: u16,
+    /// The instruction starting at each reached rom byte.
+    code: BTreeMap<u16, Instruction>,
+    labels: BTreeMap<u16, LabelKind>,
+}
+
+impl<'a> Disassembly<'a> {
+
+    /// Walk `rom` loaded at `origin`, starting at `origin`.
+    pub fn new(rom: &'a [u8], origin: u16) -> Self {
+        let mut disassembly = Self {
+            rom,
+            origin,
+            code: BTreeMap::new(),
+            labels: BTreeMap::new(),
+        };
+
+        disassembly.walk(origin);
+        disassembly.drop_unplaceable_labels();
+        disassembly
+    }
+
+    fn contains(&self, addr: u16) -> bool {
+        addr >= self.origin && ((addr - self.origin) as usize) < self.rom.len()
+    }
+
+    fn decode(&self, addr: u16) -> Option<Instruction> {
+        let offset = (addr - self.origin) as usize;
+        if offset + 2 > self.rom.len() {
+            return None;
+        }
+        instructions::decode(self.rom, offset).ok()
+    }
+
+    fn label(&mut self, addr: u16, kind: LabelKind) {
+        if self.contains(addr) {
+            let label = self.labels.entry(addr).or_insert(kind);
+            if kind > *label {
+                *label = kind;
+            }
+        }
+    }
+
+    fn walk(&mut self, entry: u16) {
+        let mut pending = vec![entry];
+
+        while let Some(addr) = pending.pop() {
+            if !self.contains(addr) || self.code.contains_key(&addr) {
+                continue;
+            }
+
+            let instr = match self.decode(addr) {
+                Some(instr) => instr,
+                None => continue,
+            };
+            self.code.insert(addr, instr);
+
+            let next = addr.wrapping_add(instr.size());
+
+            use Instruction::*;
+            match instr {
+                Ret | Exit => {},
+                Jump(target) => {
+                    self.label(target, LabelKind::Jump);
+                    pending.push(target);
+                },
+                JumpOffset(target) => {
+                    self.label(target, LabelKind::Jump);
+                    pending.push(target);
+                },
+                Call(target) => {
+                    self.label(target, LabelKind::Sub);
+                    pending.push(target);
+                    pending.push(next);
+                },
+                SkipEqConst(..) | SkipNotEqConst(..) | SkipEqReg(..) | SkipNotEqReg(..)
+                | SkipOnKeyPressed(_) | SkipKeyNotPressed(_) => {
+                    pending.push(next);
+                    let skipped = self.decode(next).map_or(2, |instr| instr.size());
+                    pending.push(next.wrapping_add(skipped));
+                },
+                LoadAddr(target) => {
+                    self.label(target, LabelKind::Data);
+                    pending.push(next);
+                },
+                _ => pending.push(next),
+            }
+        }
+    }
+
+    /// A label pointing into the middle of an instruction has no line to go
+    /// on, the address is written as a number instead.
+    fn drop_unplaceable_labels(&mut self) {
+        let inside_code = |addr: u16| self.code.range(..addr).next_back()
+            .is_some_and(|(start, instr)| addr < start + instr.size());
+
+        let unplaceable: Vec<u16> = self.labels.keys().copied().filter(|addr| inside_code(*addr)).collect();
+        for addr in unplaceable {
+            self.labels.remove(&addr);
+        }
+    }
+
+    fn label_name(&self, addr: u16) -> Option<String> {
+        self.labels.get(&addr).map(|kind| match kind {
+            LabelKind::Sub => format!("sub_{:03X}", addr),
+            LabelKind::Jump => format!("label_{:03X}", addr),
+            LabelKind::Data => format!("data_{:03X}", addr),
+        })
+    }
+
+    /// True when `addr` was reached as code.
+    pub fn is_code(&self, addr: u16) -> bool {
+        self.code.contains_key(&addr)
+    }
+
+    /// The listing, one instruction or a run of data bytes a line with the
+    /// address and raw bytes in a comment.
+    pub fn listing(&self) -> String {
+        let mut out = String::new();
+        let end = self.origin as usize + self.rom.len();
+        let mut addr = self.origin as usize;
+
+        while addr < end {
+            if let Some(name) = self.label_name(addr as u16) {
+                let _ = writeln!(out, "{}:", name);
+            }
+
+            let offset = addr - self.origin as usize;
+
+            if let Some(instr) = self.code.get(&(addr as u16)) {
+                let size = instr.size() as usize;
+                let bytes: String = self.rom[offset..offset + size].iter().map(|byte| format!("{:02X}", byte)).collect();
+                let text = instr.with_labels(|target| self.label_name(target));
+
+                let _ = writeln!(out, "    {:<24}; {:03X}: {}", text, addr, bytes);
+                addr += size;
+                continue;
+            }
+
+            // data runs up to the next code or label
+            let mut len = 1;
+            while len < DATA_PER_LINE && addr + len < end
+                && !self.code.contains_key(&((addr + len) as u16))
+                && !self.labels.contains_key(&((addr + len) as u16)) {
+                len += 1;
+            }
+
+            let bytes: Vec<String> = self.rom[offset..offset + len].iter().map(|byte| format!("0x{:02X}", byte)).collect();
+            let _ = writeln!(out, "    {:<24}; {:03X}", format!("db {}", bytes.join(", ")), addr);
+            addr += len;
+        }
+
+        out
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_jumps_and_calls() {
+        let rom = [
+            0x22, 0x06, // 200 CALL sub_206
+            0x12, 0x0A, // 202 JP label_20A
+            0xFF, 0xFF, // 204 never reached
+            0xA2, 0x0C, // 206 LD I, data_20C
+            0x00, 0xEE, // 208 RET
+            0x12, 0x0A, // 20A JP label_20A
+            0x3C, 0x42, // 20C sprite
+        ];
+
+        let listing = Disassembly::new(&rom, 0x200).listing();
+        let expected = "    CALL sub_206            ; 200: 2206
+    JP label_20A            ; 202: 120A
+    db 0xFF, 0xFF           ; 204
+sub_206:
+    LD I, data_20C          ; 206: A20C
+    RET                     ; 208: 00EE
+label_20A:
+    JP label_20A            ; 20A: 120A
+data_20C:
+    db 0x3C, 0x42           ; 20C
+";
+        assert_eq!(listing, expected);
+    }
+
+    #[test]
+    fn skips_reach_both_paths() {
+        // SE V0, 0; JP 200; LD V1, 1; ...then data
+        let rom = [0x30, 0x00, 0x12, 0x00, 0x61, 0x01, 0x00, 0xFD, 0xAB];
+        let disassembly = Disassembly::new(&rom, 0x200);
+
+        assert!(disassembly.is_code(0x202));
+        assert!(disassembly.is_code(0x204));
+        assert!(disassembly.is_code(0x206));
+        assert!(!disassembly.is_code(0x208));
+    }
+
+    #[test]
+    fn skip_over_long_load() {
+        // SE V0, 0; LD I, LONG 1234; EXIT
+        let rom = [0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x00, 0xFD];
+        let disassembly = Disassembly::new(&rom, 0x200);
+
+        assert!(disassembly.is_code(0x202));
+        assert!(!disassembly.is_code(0x204));
+        assert!(disassembly.is_code(0x206));
+    }
+}
@@ -1,5 +1,45 @@
+use crate::quirks::Quirks;
+
+/// Size of the framebuffer in high resolution mode, low resolution uses the
+/// top left 64x32 of it.
+pub const MAX_WIDTH: usize = 128;
+pub const MAX_HEIGHT: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    /// The original 64x32 CHIP-8 display.
+    Low,
+    /// The 128x64 SUPER-CHIP display.
+    High,
+}
+
+impl Resolution {
+    pub fn width(self) -> usize {
+        match self {
+            Resolution::Low => 64,
+            Resolution::High => MAX_WIDTH,
+        }
+    }
+
+    pub fn height(self) -> usize {
+        match self {
+            Resolution::Low => 32,
+            Resolution::High => MAX_HEIGHT,
+        }
+    }
+}
+
+/// Number of XO-CHIP bitplanes, a pixel is one of `1 << PLANES` colours.
+pub const PLANES: usize = 2;
+
+/// Each pixel holds one bit per plane, bit 0 is plane 1 which is the only
+/// plane used by CHIP-8 and SUPER-CHIP programs.
+#[derive(Clone)]
 pub struct Display {
-    pixels: [bool; 64*32]
+    pub(crate) pixels: [u8; MAX_WIDTH * MAX_HEIGHT],
+    pub(crate) resolution: Resolution,
+    /// Bitmask of the planes drawn to, cleared and scrolled, set by XO-CHIP FN01.
+    pub(crate) selected_planes: u8,
 }
 
 
@@ -8,67 +48,294 @@ impl Display {
 
     pub fn new() -> Self {
         Self {
-            pixels: [false; 64*32]
+            pixels: [0; MAX_WIDTH * MAX_HEIGHT],
+            resolution: Resolution::Low,
+            selected_planes: 1,
         }
     }
 
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.resolution.width()
+    }
+
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.resolution.height()
+    }
+
+    #[inline]
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    /// Switch resolution, this clears all planes.
+    pub fn set_resolution(&mut self, resolution: Resolution) {
+        self.resolution = resolution;
+        for p in self.pixels.iter_mut() {
+            *p = 0;
+        }
+    }
+
+    #[inline]
+    pub fn selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
+
+    #[inline]
+    pub fn select_planes(&mut self, planes: u8) {
+        self.selected_planes = planes & ((1 << PLANES) - 1) as u8;
+    }
+
+    /// Clear the selected planes.
     pub fn clear(&mut self) {
+        let keep = !self.selected_planes;
         for p in self.pixels.iter_mut() {
-            *p = false;
+            *p &= keep;
         }
     }
 
 
-    pub fn draw_sprite(&mut self, sprite: &Sprite) -> u8 {
+    /// Xor the sprite onto the display. The start position always wraps, the
+    /// rest of the sprite is either clipped or wrapped depending on
+    /// `quirks.clip_sprites`.
+    ///
+    /// Only `sprite.plane` is drawn to, which must be a single plane bit.
+    ///
+    /// In low resolution the result is 1 when any lit pixel was turned off. In
+    /// high resolution it is the number of rows that turned a pixel off plus
+    /// the number of rows clipped at the bottom, like SUPER-CHIP 1.1.
+    pub fn draw_sprite(&mut self, sprite: &Sprite, quirks: &Quirks) -> u8 {
 
-        let mut flipped = false;
+        let width = self.width();
+        let height = self.height();
+
+        let start_x = sprite.x % width;
+        let start_y = sprite.y % height;
+
+        let mut collided_rows = 0;
         for row in 0..(sprite.length as usize) {
-            for (col, pixel) in sprite.data[row].bits().iter().enumerate() {
+            if quirks.clip_sprites && start_y + row >= height {
+                collided_rows += 1;
+                continue;
+            }
 
-                let x = (sprite.x + col) % 64;
-                let y = (sprite.y + row) % 64;
-                //println!("({}, {}) = {}", x,y, self.pixels[y * 64 + x] ^ *pixel);
-                let old_pixel = self.pixels[y * 64 + x] ;
-                self.pixels[y * 64 + x] ^= *pixel;
+            let bits = sprite.row(row);
+            let mut flipped = false;
+
+            for col in 0..sprite.width() {
+                if quirks.clip_sprites && start_x + col >= width {
+                    break;
+                }
+
+                if bits & (1 << (sprite.width() - 1 - col)) == 0 {
+                    continue;
+                }
+
+                let x = (start_x + col) % width;
+                let y = (start_y + row) % height;
 
                 // if any is on then not on we set flipped (v_f)
-                flipped |= old_pixel  && !self.pixels[y * 64 + x]
+                flipped |= self.pixels[y * width + x] & sprite.plane != 0;
+                self.pixels[y * width + x] ^= sprite.plane;
+            }
+
+            if flipped {
+                collided_rows += 1;
             }
         }
 
-        flipped as u8
+        match self.resolution {
+            Resolution::Low => (collided_rows > 0) as u8,
+            Resolution::High => collided_rows,
+        }
     }
 
 
-    pub fn read_pixels(&self) ->  &[bool; 64*32] {
-        &self.pixels
+    /// Move every row `n` pixels down, rows scrolled in at the top are blank.
+    pub fn scroll_down(&mut self, n: usize) {
+        let width = self.width();
+        let height = self.height();
+
+        for y in (0..height).rev() {
+            for x in 0..width {
+                let src = if y >= n { self.pixels[(y - n) * width + x] } else { 0 };
+                self.set_selected(y * width + x, src);
+            }
+        }
     }
-}
 
+    /// Move every row `n` pixels up, rows scrolled in at the bottom are blank.
+    pub fn scroll_up(&mut self, n: usize) {
+        let width = self.width();
+        let height = self.height();
 
-trait Bits {
-    fn bits(&self) -> [bool; 8];
-}
+        for y in 0..height {
+            for x in 0..width {
+                let src = if y + n < height { self.pixels[(y + n) * width + x] } else { 0 };
+                self.set_selected(y * width + x, src);
+            }
+        }
+    }
 
+    /// Move every column `n` pixels right, columns scrolled in at the left are blank.
+    pub fn scroll_right(&mut self, n: usize) {
+        let width = self.width();
+        let height = self.height();
 
-impl Bits for u8 {
-    fn bits(&self) -> [bool;8] {
+        for y in 0..height {
+            for x in (0..width).rev() {
+                let src = if x >= n { self.pixels[y * width + x - n] } else { 0 };
+                self.set_selected(y * width + x, src);
+            }
+        }
+    }
 
-        [ self & 0b10000000 != 0,
-          self & 0b01000000 != 0,
-          self & 0b00100000 != 0,
-          self & 0b00010000 != 0,
-          self & 0b00001000 != 0,
-          self & 0b00000100 != 0,
-          self & 0b00000010 != 0,
-          self & 0b00000001 != 0,
-        ]
+    /// Move every column `n` pixels left, columns scrolled in at the right are blank.
+    pub fn scroll_left(&mut self, n: usize) {
+        let width = self.width();
+        let height = self.height();
+
+        for y in 0..height {
+            for x in 0..width {
+                let src = if x + n < width { self.pixels[y * width + x + n] } else { 0 };
+                self.set_selected(y * width + x, src);
+            }
+        }
+    }
+
+
+    /// Copy the selected planes of `src` into pixel `i`, leaving the others.
+    #[inline]
+    fn set_selected(&mut self, i: usize, src: u8) {
+        let mask = self.selected_planes;
+        self.pixels[i] = (self.pixels[i] & !mask) | (src & mask);
+    }
+
+
+    /// The visible pixels as `height()` rows of `width()` pixels. Each pixel
+    /// is a colour index with one bit per plane, 0 is the background.
+    pub fn read_pixels(&self) -> &[u8] {
+        &self.pixels[..self.width() * self.height()]
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+
 pub struct Sprite {
-    pub data: [u8; 15],
+    /// One byte per row, or two bytes per row for wide sprites.
+    pub data: [u8; 32],
     pub length: u8,
+    /// A 16x16 SUPER-CHIP sprite.
+    pub wide: bool,
+    /// The bit of the plane to draw to.
+    pub plane: u8,
     pub x: usize,
     pub y: usize
 }
+
+impl Sprite {
+
+    #[inline]
+    pub fn width(&self) -> usize {
+        if self.wide { 16 } else { 8 }
+    }
+
+    /// The pixels of `row`, leftmost pixel in the highest bit used.
+    fn row(&self, row: usize) -> u16 {
+        if self.wide {
+            ((self.data[row * 2] as u16) << 8) | self.data[row * 2 + 1] as u16
+        }
+        else {
+            self.data[row] as u16
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sprite(data: &[u8], wide: bool, x: usize, y: usize) -> Sprite {
+        let mut sprite = Sprite {
+            data: [0; 32],
+            length: if wide { data.len() / 2 } else { data.len() } as u8,
+            wide,
+            plane: 1,
+            x,
+            y
+        };
+        sprite.data[..data.len()].copy_from_slice(data);
+        sprite
+    }
+
+    #[test]
+    fn wide_sprite_in_high_resolution() {
+        let mut display = Display::new();
+        display.set_resolution(Resolution::High);
+
+        let s = sprite(&[0x80, 0x01, 0xFF, 0xFF], true, 100, 10);
+        assert_eq!(display.draw_sprite(&s, &Quirks::schip()), 0);
+
+        let pixels = display.read_pixels();
+        assert_eq!(pixels.len(), 128 * 64);
+        assert_eq!(pixels[10 * 128 + 100], 1);
+        assert_eq!(pixels[10 * 128 + 101], 0);
+        assert_eq!(pixels[10 * 128 + 115], 1);
+        assert_eq!(pixels[11 * 128 + 108], 1);
+    }
+
+    #[test]
+    fn high_resolution_counts_collided_rows() {
+        let mut display = Display::new();
+        display.set_resolution(Resolution::High);
+
+        let s = sprite(&[0xFF, 0x00, 0xFF, 0xFF], false, 0, 62);
+        display.draw_sprite(&s, &Quirks::schip());
+
+        // row 0 collides, rows 2 and 3 are clipped at the bottom
+        assert_eq!(display.draw_sprite(&s, &Quirks::schip()), 3);
+    }
+
+    #[test]
+    fn scroll() {
+        let mut display = Display::new();
+
+        display.draw_sprite(&sprite(&[0x80], false, 0, 0), &Quirks::default());
+
+        display.scroll_down(2);
+        display.scroll_right(4);
+        assert_eq!(display.read_pixels()[2 * 64 + 4], 1);
+
+        display.scroll_left(4);
+        display.scroll_up(1);
+        assert_eq!(display.read_pixels()[64], 1);
+        assert_eq!(display.read_pixels().iter().filter(|p| **p != 0).count(), 1);
+    }
+
+    #[test]
+    fn planes_are_drawn_cleared_and_scrolled_separately() {
+        let mut display = Display::new();
+
+        let mut s = sprite(&[0xC0], false, 0, 0);
+        display.draw_sprite(&s, &Quirks::default());
+        s.plane = 2;
+        s.x = 1;
+        display.draw_sprite(&s, &Quirks::default());
+
+        assert_eq!(&display.read_pixels()[..3], &[1, 3, 2]);
+
+        display.select_planes(2);
+        display.scroll_right(1);
+        assert_eq!(&display.read_pixels()[..4], &[1, 1, 2, 2]);
+
+        display.clear();
+        assert_eq!(&display.read_pixels()[..4], &[1, 1, 0, 0]);
+    }
+}
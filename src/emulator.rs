@@ -1,546 +1,624 @@
-use crate::instructions;
-use crate::instructions::{Instruction};
-use crate::chip::*;
-use crate::display::Sprite;
-use rand::Rng;
-use sdl2::{Sdl};
+use crate::audio::{AudioSettings, Beeper};
+use chip8::chip::*;
+use chip8::cpu::Fault;
+use chip8::debugger::{self, Command, Debugger, Stop};
+use chip8::gamepad::{PadMap, PLAYERS};
+use chip8::keyboard::LatchPolicy;
+use chip8::keymap::KeyMap;
+use chip8::movie::{Movie, Player};
+use chip8::rewind::Rewind;
+use sdl2::{GameControllerSubsystem, Sdl};
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
 use std::time::{Duration, Instant};
 
+/// Frames per second, the delay and sound timers tick once per frame.
+const FRAME_RATE: f64 = 60.0;
+
+/// How far back rewinding goes, 10 seconds or this many bytes of history.
+const REWIND_FRAMES: usize = 600;
+const REWIND_BYTES: usize = 32 << 20;
+
+/// Colours for the XO-CHIP plane combinations, CHIP-8 only uses the first two.
+const PALETTE: [Color; 4] = [
+    Color::RGB(0, 0, 0),
+    Color::RGB(255, 210, 0),
+    Color::RGB(255, 90, 0),
+    Color::RGB(90, 40, 0),
+];
+
+/// 64x32 pixels scaled by 13.
+pub const DEFAULT_WINDOW_SIZE: (u32, u32) = (832, 416);
+
 pub struct Emulator {
     chip: Chip8,
+    /// Save states are stored next to the rom.
+    rom_path: PathBuf,
+    /// Window caption, the game's title when the rom database knows it.
+    title: String,
+    /// Colours for the XO-CHIP plane combinations, see `PALETTE`.
+    palette: [Color; 4],
     sdl_context: Sdl,
-    frequency: u32,
+    /// Instructions executed per frame, 13 is roughly 800 Hz.
+    cycles_per_frame: u32,
     canvas: sdl2::render::Canvas<sdl2::video::Window>,
+    /// None when no audio device could be opened.
+    beeper: Option<Beeper>,
+    /// Host keys for the hex keys, matched by their SDL names.
+    key_map: KeyMap,
+    /// None when SDL has no controller support.
+    controller_subsystem: Option<GameControllerSubsystem>,
+    /// The controller of each player, in the order they were plugged in.
+    controllers: [Option<GameController>; PLAYERS],
+    pad_map: PadMap,
+    /// Applied to every machine loaded or rewound to as well.
+    latch: LatchPolicy,
+    /// The keys and controller inputs holding down hex keys, a hex key is
+    /// up once none hold it.
+    held: Vec<(String, u8)>,
+    rewind: Rewind,
+    /// Backspace is held, frames play backwards.
+    rewinding: bool,
+    movie: Option<MovieMode>,
+    debugger: Option<Debugger>,
+    /// Debugger command lines read from the terminal.
+    commands: Option<Receiver<String>>,
+}
+
+/// While a movie records or plays the run must stay deterministic, so
+/// rewinding and loading states are disabled.
+enum MovieMode {
+    /// Written to the path when the emulator stops.
+    Recording(Movie, PathBuf),
+    /// The keyboard is ignored, input comes from the movie.
+    Playing(Player),
 }
 
 impl Emulator {
-    pub fn new() -> Self {
+    /// `window_size` in pixels, `DEFAULT_WINDOW_SIZE` keeps the pixels square.
+    pub fn new(chip: Chip8, rom_path: PathBuf, cycles_per_frame: u32, audio_settings: AudioSettings, window_size: (u32, u32)) -> Self {
 
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
 
-        // square pixel
-        let width = 832;  // 64 * 13
-        let height = 416; // 32 * 13
+        let (width, height) = window_size;
         let window = video_subsystem.window("Chip8", width, height)
             .position_centered()
             .build()
             .unwrap();
 
-        let mut canvas = window.into_canvas().build().unwrap();
+        let canvas = window.into_canvas().build().unwrap();
+
+        let beeper = match sdl_context.audio().and_then(|audio| Beeper::new(&audio, audio_settings)) {
+            Ok(beeper) => Some(beeper),
+            Err(err) => {
+                eprintln!("No sound: {}", err);
+                None
+            }
+        };
+
+        let controller_subsystem = match sdl_context.game_controller() {
+            Ok(subsystem) => Some(subsystem),
+            Err(err) => {
+                eprintln!("No controllers: {}", err);
+                None
+            }
+        };
+
+        let latch = chip.keyboard.latch();
 
         Self {
-            chip: Chip8::new(),
+            chip,
+            rom_path,
+            title: "Chip8".to_string(),
+            palette: PALETTE,
             sdl_context,
             canvas,
-            frequency: 800,
+            beeper,
+            cycles_per_frame,
+            key_map: KeyMap::default(),
+            controller_subsystem,
+            controllers: [None, None],
+            pad_map: PadMap::default(),
+            latch,
+            held: Vec::new(),
+            rewind: Rewind::new(REWIND_FRAMES, REWIND_BYTES),
+            rewinding: false,
+            movie: None,
+            debugger: None,
+            commands: None,
+        }
+    }
+
+    /// Show the game's title in the window caption.
+    pub fn set_title(&mut self, title: &str) {
+        self.title = format!("Chip8 - {}", title);
+        let _ = self.canvas.window_mut().set_title(&self.title);
+    }
+
+    /// Replace the colours from the background on, the rest stay.
+    pub fn set_colors(&mut self, colors: &[(u8, u8, u8)]) {
+        for (color, (r, g, b)) in self.palette.iter_mut().zip(colors) {
+            *color = Color::RGB(*r, *g, *b);
+        }
+    }
+
+    /// Use other host keys for the hex keys, every name has to be one SDL
+    /// knows.
+    pub fn set_key_map(&mut self, key_map: KeyMap) -> Result<(), String> {
+        if let Some((name, key)) = key_map.iter().find(|(name, _)| Keycode::from_name(name).is_none()) {
+            return Err(format!("Unknown key name '{}' for key {:X}", name, key));
+        }
+        self.key_map = key_map;
+        Ok(())
+    }
+
+    /// Use other controller inputs for the hex keys, every name has to be a
+    /// button or axis direction SDL knows.
+    pub fn set_pad_map(&mut self, pad_map: PadMap) -> Result<(), String> {
+        for player in 0..PLAYERS {
+            let known = |input: &str| match input.strip_suffix(|c| c == '-' || c == '+') {
+                Some(axis) => Axis::from_string(axis).is_some(),
+                None => Button::from_string(input).is_some(),
+            };
+            if let Some((input, key)) = pad_map.player(player).iter().find(|(input, _)| !known(input)) {
+                return Err(format!("Unknown controller input '{}' for key {:X} of player {}", input, key, player + 1));
+            }
         }
+        self.pad_map = pad_map;
+        Ok(())
+    }
+
+    /// How long the program sees a key press.
+    pub fn set_latch(&mut self, latch: LatchPolicy) {
+        self.latch = latch;
+        self.chip.keyboard.set_latch(latch);
+    }
+
+    /// Start paused with the debugger reading commands from the terminal.
+    pub fn debug(&mut self) {
+        let (sender, receiver) = mpsc::channel();
+
+        // reading stdin blocks, so it gets its own thread and the window keeps
+        // drawing while waiting for a command
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                match line {
+                    Ok(line) => if sender.send(line).is_err() { break },
+                    Err(_) => break,
+                }
+            }
+        });
+
+        self.debugger = Some(Debugger::new(self.cycles_per_frame));
+        self.commands = Some(receiver);
+
+        println!("Debugger paused at {:03X}, type help for the commands", self.chip.pc);
+        prompt();
+    }
+
+    /// Record the input of this run, the chip must not have run yet.
+    pub fn record(&mut self, rom: &[u8], path: PathBuf) {
+        let movie = Movie::new(rom, &self.chip, self.cycles_per_frame);
+        self.movie = Some(MovieMode::Recording(movie, path));
+    }
+
+    /// Play back a movie, the chip must come from `Movie::new_chip`.
+    pub fn play(&mut self, movie: Movie) {
+        self.cycles_per_frame = movie.cycles_per_frame;
+        self.movie = Some(MovieMode::Playing(Player::new(movie)));
     }
 
-    pub fn load_program(&mut self, program: &Program) {
+    pub fn load_program(&mut self, program: &Program) -> Result<(), LoadError> {
 
-        self.chip.load_program(program);
+        self.chip.load_program(program)
 
     }
 
+    /// Run at 60 frames a second: handle input, execute `cycles_per_frame`
+    /// instructions, tick the timers, draw, then sleep until the next frame.
     pub fn run(&mut self) {
+        self.run_frames();
+        self.stop_recording();
+    }
 
-        let mut event_pump = self.sdl_context.event_pump().unwrap();
+    fn run_frames(&mut self) {
 
-        let mut clock_last_instant = Instant::now();
-        let clock_rate_millis =  (1.0/(self.frequency as f64) * 1000.0) as u128;
+        let mut event_pump = self.sdl_context.event_pump().unwrap();
 
-        let mut delay_sound_last_instant = Instant::now();
-        let delay_sound_rate_millis = (1.0/60.0 * 1000.0) as u128;
+        let frame_duration = Duration::from_secs_f64(1.0 / FRAME_RATE);
+        let mut next_frame = Instant::now();
 
         loop {
 
-            // run a cycle and update display
-            let elapsed_clock = clock_last_instant.elapsed();
-            if elapsed_clock.as_millis() > clock_rate_millis {
-                clock_last_instant = Instant::now();
-                cycle(&mut self.chip);
-                self.update_display();
-            }
-
             // update keyboard
 
             for event in event_pump.poll_iter() {
                 use sdl2::event::Event;
                 match event {
                     Event::Quit {..} => return,
+                    Event::KeyDown { keycode: Some(Keycode::M), repeat: false, ..} => {
+                        if let Some(beeper) = self.beeper.as_mut() {
+                            let muted = beeper.toggle_mute();
+                            println!("Sound {}", if muted { "muted" } else { "on" });
+                        }
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::Backspace), ..} if self.movie.is_none() => {
+                        self.rewinding = true;
+                    },
+                    Event::KeyUp { keycode: Some(Keycode::Backspace), ..} => {
+                        self.rewinding = false;
+                    },
+                    Event::KeyDown { keycode: Some(code), keymod, repeat: false, ..} if state_slot(code).is_some() => {
+                        let slot = state_slot(code).unwrap();
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            self.save_state(slot);
+                        }
+                        else if self.movie.is_some() {
+                            eprintln!("Can't load a state while a movie records or plays");
+                        }
+                        else {
+                            self.load_state(slot);
+                        }
+                    },
                     Event::KeyDown { keycode: Some(code), ..} => {
-                        self.chip.keyboard.check_key(code, true);
+                        self.check_key(code, true);
                     }
                     Event::KeyUp { keycode: Some(code), ..} => {
-                        self.chip.keyboard.check_key(code, false);
+                        self.check_key(code, false);
+                    },
+                    Event::ControllerDeviceAdded { which, .. } => self.add_controller(which),
+                    Event::ControllerDeviceRemoved { which, .. } => self.remove_controller(which),
+                    Event::ControllerButtonDown { which, button, .. } => {
+                        self.check_pad(which, button.string(), true);
+                    },
+                    Event::ControllerButtonUp { which, button, .. } => {
+                        self.check_pad(which, button.string(), false);
+                    },
+                    Event::ControllerAxisMotion { which, axis, value, .. } => {
+                        for (input, pushed) in self.pad_map.axis_directions(&axis.string(), value) {
+                            self.check_pad(which, input, pushed);
+                        }
                     },
                     _ => {}
                 };
             }
 
-
-
-            // update timers (delay and sound)
-            let elapsed_delay_sound = delay_sound_last_instant.elapsed();
-
-            if elapsed_delay_sound.as_millis() > delay_sound_rate_millis {
-                delay_sound_last_instant = Instant::now();
-                self.chip.registers.tick();
+            if self.debug_commands() {
+                return;
             }
 
-            // if sound is 1 play a tone we specify
-
-        }
-    }
-
-
-    fn update_display(&mut self) {
-
-
-        // UPDATE THE DISPLAY
-        // set on "pixel" color
-        self.canvas.set_draw_color(Color::RGB(255, 210, 0));
-
-
-
-        for (i,pixel) in self.chip.display.read_pixels().iter().enumerate() {
-
-            if *pixel {
-                self.canvas.set_draw_color(Color::RGB(255, 210, 0));
-            }
-            else {
-                self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+            // run the frame, a halted chip keeps its last frame on screen
+            // but can still be rewound to before the fault
+            if self.rewinding {
+                if let Some(chip) = self.rewind.rewind() {
+                    self.restore(chip);
+                }
             }
+            else if let Some(debugger) = self.debugger.as_mut() {
+                if !debugger.paused && !self.chip.is_halted() {
+                    match debugger.run_frame(&mut self.chip) {
+                        Ok(Some(Stop::Breakpoint(addr))) => {
+                            println!("\nBreakpoint at {:03X}\n{}", addr, debugger::disassemble(&self.chip, addr, 1));
+                            prompt();
+                        },
+                        Ok(Some(Stop::Step)) => {
+                            println!("{}", debugger::disassemble(&self.chip, self.chip.pc, 1));
+                            prompt();
+                        },
+                        Ok(None) => {},
+                        Err(fault) => {
+                            self.report_fault(&fault);
+                            prompt();
+                        },
+                    }
 
-            let x = (i % 64) as i32;
-            let y = (i / 64) as i32;
-
-            //calc pixel size from canvas resolution
-
-            let width = (self.canvas.window().size().0  / 64) as i32;
-            let height = (self.canvas.window().size().1 / 32)  as i32;
-
-            self.canvas.fill_rect(Rect::new(x * width, y * height, width as u32, height as u32));
-
-        }
-
-        // set default to black
-
-        self.canvas.present();
-    }
-}
-
-
-
-fn cycle(chip: &mut Chip8) {
-
-
-    let upper = chip.memory[chip.pc as usize];
-    let lower = chip.memory[(chip.pc + 1) as usize];
-
-
-    let instr = instructions::parse(upper, lower);
-
-    match execute(instr, chip) {
-        ExecuteRes::SetPc(addr) => {
-            chip.pc = addr;
-        },
-        ExecuteRes::Wait => {}
-    };
-
-}
+                    if self.chip.exited {
+                        return;
+                    }
+                }
+            }
+            else if !self.chip.is_halted() {
+                self.movie_input();
 
+                if let Err(fault) = self.chip.run_frame(self.cycles_per_frame) {
+                    self.report_fault(&fault);
+                }
 
-enum ExecuteRes {
-    SetPc(u16),
-    Wait
-}
+                self.movie_frame();
 
+                // SUPER-CHIP 00FD exits the interpreter
+                if self.chip.exited {
+                    return;
+                }
 
-fn execute(instr: Instruction, chip: &mut Chip8) -> ExecuteRes {
-    use ExecuteRes::*;
-    let mut new_pc = chip.pc + 2;
-    match instr {
-        Instruction::Cls => {
-            chip.display.clear();
-            SetPc(new_pc)
-        },
-        Instruction::Ret => {
-            chip.sp -= 1;
-            new_pc = chip.stack[chip.sp as usize];
-            SetPc(new_pc)
-        },
-        Instruction::Jump(addr) => {
-            new_pc = addr;
-            SetPc(new_pc)
-        },
-        Instruction::Call(addr) => {
-
-            chip.stack[chip.sp as usize] = new_pc;
-            chip.sp += 1;
-            new_pc = addr;
-            SetPc(new_pc)
-        },
-        Instruction::SkipEqConst(reg, byte) => {
-
-            // get reg value by reg
-            let reg_val = chip.registers.get_value(reg);
-            if reg_val == byte {
-                new_pc += 2;
+                if self.movie.is_none() {
+                    self.rewind.push(&self.chip);
+                }
             }
 
-            SetPc(new_pc)
-        },
-        Instruction::SkipNotEqConst(reg, byte) => {
-
-            // get reg value by reg
-            let reg_val = chip.registers.get_value(reg);
-            if reg_val != byte {
-                new_pc += 2;
+            if let Some(beeper) = self.beeper.as_mut() {
+                beeper.set_pattern(self.chip.audio_pattern, self.chip.audio_rate());
+                beeper.set_beeping(self.chip.is_beeping());
             }
 
-            SetPc(new_pc)
-        },
-        Instruction::SkipEqReg(reg_x, reg_y) => {
-
-            // get reg value by reg
-            let x_val = chip.registers.get_value(reg_x);
-            let y_val = chip.registers.get_value(reg_y);
+            self.update_display();
 
-            if x_val == y_val {
-                new_pc += 2;
-            }
-            SetPc(new_pc)
-        },
-
-        Instruction::LoadConst(reg, byte) => {
-            chip.registers.set_value(reg, byte);
-            SetPc(new_pc)
-        },
-        Instruction::AddConst(reg, byte) => {
-            let cur = chip.registers.get_value(reg) as u16;
-            let res = (byte as u16 + cur) as u8;
-            //println!("cur={:?} byte ={}, res={}", cur, byte, res);
-            chip.registers.set_value(reg, res);
-            SetPc(new_pc)
-        },
-
-        Instruction::LoadReg(reg_x, reg_y) => {
-            let y_val = chip.registers.get_value(reg_y);
-            chip.registers.set_value(reg_x, y_val);
-            SetPc(new_pc)
-        },
-
-        Instruction::Or(reg_x, reg_y) => {
-            chip.registers.bitwise(reg_x, reg_y, |x,y| x | y);
-            SetPc(new_pc)
-        },
-
-        Instruction::And(reg_x, reg_y) => {
-            chip.registers.bitwise(reg_x, reg_y, |x,y| x & y);
-            SetPc(new_pc)
-        },
-
-        Instruction::Xor(reg_x, reg_y) => {
-            chip.registers.bitwise(reg_x, reg_y, |x,y| x ^ y);
-            SetPc(new_pc)
-        },
-
-        Instruction::Add(reg_x, reg_y) => {
-            let x = chip.registers.get_value(reg_x);
-            let y = chip.registers.get_value(reg_y);
-
-            let val = (x  as u16) + (y as u16);
-            // CHeck val > 255 then set v_f
-
-
-            if val > 255 {
-                chip.registers.set_value(0xF, 1);
+            // sleep for what is left of the frame, if we fell more than a frame
+            // behind (window dragged, machine suspended) don't try to catch up
+            next_frame += frame_duration;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
             }
-            else {
-                chip.registers.set_value(0xF, 0);
+            else if now - next_frame > frame_duration {
+                next_frame = now;
             }
+        }
+    }
 
-            chip.registers.set_value(reg_x, val as u8);
-            SetPc(new_pc)
-        },
-
-
-        Instruction::Sub(reg_x, reg_y) => {
-            let mut x = chip.registers.get_value(reg_x) as u16;
-            let y = chip.registers.get_value(reg_y) as u16;
 
+    /// Carry out the debugger commands typed since the last frame. Returns
+    /// true when the emulator should quit.
+    fn debug_commands(&mut self) -> bool {
+        let (debugger, commands) = match (self.debugger.as_mut(), self.commands.as_ref()) {
+            (Some(debugger), Some(commands)) => (debugger, commands),
+            _ => return false,
+        };
 
-            // CHeck val > 255 then set v_f
+        loop {
+            let line = match commands.try_recv() {
+                Ok(line) => line,
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => return true,
+            };
 
-            if x > y {
-                chip.registers.set_value(0xF, 1);
-            }
-            else {
-                chip.registers.set_value(0xF, 0);
-                x += 256;
+            if line.trim().is_empty() {
+                prompt();
+                continue;
             }
 
-            let val = x - y;
-            chip.registers.set_value(reg_x, val as u8);
-            SetPc(new_pc)
-        },
-
-        Instruction::ShiftRight(reg_x, reg_y) => {
-            let mut x = chip.registers.get_value(reg_x);
-
-            chip.registers.set_value(0xF, x & 1);
-
-            chip.registers.set_value(reg_x, x >> 1);
-            SetPc(new_pc)
-        },
-
-        Instruction::SkipEqReg(reg_x, reg_y) => {
-            let x = chip.registers.get_value(reg_x);
-            let y = chip.registers.get_value(reg_y);
-
-            if x == y {
-                new_pc += 2;
+            match Command::parse(&line) {
+                Ok(Command::Quit) => return true,
+                Ok(command) => {
+                    let out = debugger.execute(&mut self.chip, &command);
+                    if !out.is_empty() {
+                        println!("{}", out);
+                    }
+                },
+                Err(err) => println!("{}", err),
             }
-            SetPc(new_pc)
-        },
 
-        Instruction::SubN(reg_x, reg_y) => {
-            let x = chip.registers.get_value(reg_x) as u16;
-            let mut y = chip.registers.get_value(reg_y) as u16;
-
-
-            // CHeck val > 255 then set v_f
-
-            if y > x {
-                chip.registers.set_value(0xF, 1);
-            }
-            else {
-                chip.registers.set_value(0xF, 0);
-                y += 256;
+            if debugger.paused {
+                prompt();
             }
+        }
+    }
 
-            let val = y - x;
-            chip.registers.set_value(reg_x, val as u8);
-            SetPc(new_pc)
-        },
 
-        Instruction::ShiftLeft(reg_x, reg_y) => {
-            let x = chip.registers.get_value(reg_x);
+    /// Set the keys from the movie before a frame runs, when it has ended
+    /// the keyboard takes over.
+    fn movie_input(&mut self) {
+        if let Some(MovieMode::Playing(player)) = self.movie.as_ref() {
+            if !player.apply_input(&mut self.chip) {
+                println!("Movie finished after {} frames", player.frame());
+                self.movie = None;
+            }
+        }
+    }
 
-            chip.registers.set_value(0xF, (x & 0x80) >> 7);
 
-            chip.registers.set_value(reg_x, x << 1);
-            SetPc(new_pc)
-        },
+    /// Record or check the frame that just ran.
+    fn movie_frame(&mut self) {
+        match self.movie.as_mut() {
+            Some(MovieMode::Recording(movie, _)) => movie.record(&self.chip),
+            Some(MovieMode::Playing(player)) => {
+                if let Err(desync) = player.check(&self.chip) {
+                    eprintln!("Movie stopped: {}", desync);
+                    let _ = self.canvas.window_mut().set_title(&format!("{} - {}", self.title, desync));
+                    self.movie = None;
+                }
+            },
+            None => {},
+        }
+    }
 
-        Instruction::SkipNotEqReg(reg_x, reg_y) => {
-            let x = chip.registers.get_value(reg_x);
-            let y = chip.registers.get_value(reg_y);
 
-            if x != y {
-                new_pc = new_pc + 2;
+    fn stop_recording(&mut self) {
+        if let Some(MovieMode::Recording(movie, path)) = self.movie.take() {
+            match fs::write(&path, movie.to_bytes()) {
+                Ok(()) => println!("Recorded {} frames to {}", movie.frames.len(), path.display()),
+                Err(err) => eprintln!("Could not write movie: {}", err),
             }
+        }
+    }
 
-            SetPc(new_pc)
-        },
 
-        Instruction::LoadAddr(addr) => {
-            chip.registers.set_i(addr);
-            SetPc(new_pc)
-        },
+    fn state_path(&self, slot: u8) -> PathBuf {
+        let mut path = self.rom_path.clone().into_os_string();
+        path.push(format!(".state{}", slot));
+        path.into()
+    }
 
-        Instruction::JumpOffset(addr) => {
 
-            let v0 = chip.registers.get_value(0) as u16;
+    fn save_state(&mut self, slot: u8) {
+        let path = self.state_path(slot);
+        match fs::write(&path, self.chip.save_state()) {
+            Ok(()) => println!("Saved state {} to {}", slot, path.display()),
+            Err(err) => eprintln!("Could not save state {}: {}", slot, err),
+        }
+    }
 
-            new_pc = v0 + addr;
-            SetPc(new_pc)
-        },
 
-        Instruction::Rand(reg_x, data) => {
+    fn load_state(&mut self, slot: u8) {
+        let path = self.state_path(slot);
+        let loaded = fs::read(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|data| Chip8::load_state(&data).map_err(|err| err.to_string()));
 
-            let mut rng = rand::thread_rng();
-            let val = data & rng.gen_range(0..=255);
+        match loaded {
+            Ok(chip) => {
+                self.restore(chip);
+                self.rewind.clear();
+                println!("Loaded state {} from {}", slot, path.display());
+            },
+            Err(err) => eprintln!("Could not load state {}: {}", slot, err),
+        }
+    }
 
-            chip.registers.set_value(reg_x, val);
 
-            SetPc(new_pc)
-        },
+    fn report_fault(&mut self, fault: &Fault) {
+        eprintln!("Emulation stopped: {}", fault);
 
-        Instruction::Draw(reg_x, reg_y, n) => {
-            // get the data and send that to the display to draw
+        let _ = self.canvas.window_mut().set_title(&format!("{} - {}", self.title, fault));
+    }
 
 
-            let x = chip.registers.get_value(reg_x) as usize;
-            let y = chip.registers.get_value(reg_y) as usize;
+    fn check_key(&mut self, code: Keycode, down: bool) {
+        let name = code.name();
+        if let Some(key) = self.key_map.hex_key(&name) {
+            self.hold(format!("key {}", name), key, down);
+        }
+    }
 
-            let mut sprite = Sprite {
-                data: [0; 15],
-                length: n,
-                x,
-                y
-            };
 
+    /// An input of the controller with SDL instance id `which`.
+    fn check_pad(&mut self, which: u32, input: String, down: bool) {
+        let player = self.controllers.iter()
+            .position(|controller| controller.as_ref().map(GameController::instance_id) == Some(which));
 
-            for i in 0..(n as usize) {
-                //println!("{:?}, {}, {}, {}",reg_x, reg_y, n, chip.registers.get_i() as usize + i);
-                let addr = (chip.registers.get_i() as usize + i);
-                sprite.data[i] = chip.memory[addr];
+        if let Some(player) = player {
+            if let Some(key) = self.pad_map.hex_key(player, &input) {
+                self.hold(format!("player{} {}", player + 1, input), key, down);
             }
+        }
+    }
 
-            let vf = chip.display.draw_sprite(&sprite);
-
-            chip.registers.set_value(0xf, vf);
 
-            SetPc(new_pc)
+    /// Press or release a hex key for one input, it stays down while another
+    /// input holds it.
+    fn hold(&mut self, input: String, key: u8, down: bool) {
+        if let Some(MovieMode::Playing(_)) = self.movie {
+            return;
+        }
 
-        },
+        self.held.retain(|(held, _)| *held != input);
+        if down {
+            self.held.push((input, key));
+        }
+        self.chip.set_key(key, self.held.iter().any(|(_, held)| *held == key));
+    }
 
+    /// Swap in a restored machine. It has the keys held when it was saved,
+    /// the ones held now replace them.
+    fn restore(&mut self, chip: Chip8) {
+        self.chip = chip;
+        self.chip.keyboard.set_latch(self.latch);
+        for key in 0..16 {
+            self.chip.set_key(key, self.held.iter().any(|(_, held)| *held == key));
+        }
+    }
 
-        Instruction::SkipOnKeyPressed(reg_x) => {
 
-            let x = chip.registers.get_value(reg_x);
-            if chip.keyboard.key_pressed(x) {
-                new_pc += 2;
+    /// Give a newly plugged in controller to the first player without one,
+    /// SDL reports the ones already plugged in on start too.
+    fn add_controller(&mut self, joystick_index: u32) {
+        let subsystem = match self.controller_subsystem.as_ref() {
+            Some(subsystem) => subsystem,
+            None => return,
+        };
+        let player = match self.controllers.iter().position(Option::is_none) {
+            Some(player) => player,
+            None => {
+                println!("Only {} controllers can play, ignoring the new one", PLAYERS);
+                return;
             }
+        };
 
-            SetPc(new_pc)
-        },
-
-        Instruction::SkipKeyNotPressed(reg_x) => {
-            let x = chip.registers.get_value(reg_x);
-            if !chip.keyboard.key_pressed(x) {
-                new_pc += 2;
-            }
-            SetPc(new_pc)
-        },
-
-        Instruction::LoadDelay(reg_x) => {
-            chip.registers.set_value(reg_x, chip.registers.get_delay() );
-            SetPc(new_pc)
-        },
-
-        Instruction::WaitKeyPress(reg_x) =>
-            match chip.keyboard.next_key() {
-                None => Wait,
-                Some(key) => {
-                    chip.registers.set_value(reg_x, key);
-                    SetPc(new_pc)
-                }
+        match subsystem.open(joystick_index) {
+            Ok(controller) => {
+                println!("Player {} controller: {}", player + 1, controller.name());
+                self.controllers[player] = Some(controller);
             },
+            Err(err) => eprintln!("Can't open controller: {}", err),
+        }
+    }
 
 
-        Instruction::SetDelay(reg_x) => {
-            let x = chip.registers.get_value(reg_x);
-            chip.registers.set_delay(x);
-            SetPc(new_pc)
-        },
-
-        Instruction::SetSound(reg_x) => {
-            let x = chip.registers.get_value(reg_x);
-            chip.registers.set_sound(x);
-            SetPc(new_pc)
-        },
-
-        Instruction::AddAddr(reg_x) => {
-            let x = chip.registers.get_value(reg_x);
-            chip.registers.increment_i(x as u16);
-            SetPc(new_pc)
-        },
-
-        Instruction::SetSpriteAddr(reg_x) => {
-            let x = chip.registers.get_value(reg_x);
-            chip.registers.set_i((x*5) as u16);
-
-            SetPc(new_pc)
-        },
-
-        Instruction::BCD(reg_x) => {
-            let x = chip.registers.get_value(reg_x);
-
-            let i_0 = x / 100;
-            let i_1 = (x % 100) / 10;
-            let i_2 = x % 10;
-
-            let addr = chip.registers.get_i() as usize;
-
-            chip.memory[addr] = i_0;
-            chip.memory[addr + 1] = i_1;
-            chip.memory[addr + 2] = i_2;
-
-            SetPc(new_pc)
-        },
-
-        Instruction::Store(reg_x) => {
-
-            let addr = chip.registers.get_i() as usize;
-            for i in 0..=reg_x {
-                chip.memory[addr + i as usize] = chip.registers.get_value(i);
+    /// Unplugging releases the keys the controller held, the other player
+    /// keeps their controller.
+    fn remove_controller(&mut self, which: u32) {
+        for player in 0..PLAYERS {
+            if self.controllers[player].as_ref().map(GameController::instance_id) != Some(which) {
+                continue;
             }
 
-            SetPc(new_pc)
-        },
+            println!("Player {} controller unplugged", player + 1);
+            self.controllers[player] = None;
 
-        Instruction::Load(reg_x) => {
-
-            let addr = chip.registers.get_i() as usize;
-            for i in 0..=reg_x {
-                chip.registers.set_value(i, chip.memory[addr + i as usize]);
+            let prefix = format!("player{} ", player + 1);
+            let released: Vec<_> = self.held.iter()
+                .filter(|(input, _)| input.starts_with(&prefix))
+                .cloned()
+                .collect();
+            for (input, key) in released {
+                self.hold(input, key, false);
             }
-            SetPc(new_pc)
-        },
+        }
     }
-}
 
 
+    fn update_display(&mut self) {
 
-#[cfg(test)]
-mod tests {
+        // scale the display to the window, computing each edge separately so
+        // 128x64 also fills the 64x32 sized window without gaps
+        let (window_width, window_height) = self.canvas.window().size();
+        let width = self.chip.display.width();
+        let height = self.chip.display.height();
 
-    use crate::chip::*;
-    use super::*;
+        let edge_x = |x: usize| (x as u32 * window_width / width as u32) as i32;
+        let edge_y = |y: usize| (y as u32 * window_height / height as u32) as i32;
 
+        for (i,pixel) in self.chip.framebuffer().iter().enumerate() {
 
-    #[test]
-    fn sub_underflow() {
+            self.canvas.set_draw_color(self.palette[*pixel as usize]);
 
-        let mut chip = Chip8::new();
+            let x = i % width;
+            let y = i / width;
 
-        // set register 1 to 1
-        chip.registers.set_value(1,1);
+            let left = edge_x(x);
+            let top = edge_y(y);
+            let w = (edge_x(x + 1) - left) as u32;
+            let h = (edge_y(y + 1) - top) as u32;
 
+            let _ = self.canvas.fill_rect(Rect::new(left, top, w, h));
 
-        chip.set_instruction(0x200, 0x8015);
-        cycle(&mut chip);
+        }
 
-        // check that register 0 has the value of 255, since that is 0 - 1 with borrow
-        assert_eq!(chip.registers.get_value(0), 255);
-        assert_eq!(chip.registers.get_value(0xF), 0);
+        self.canvas.present();
     }
+}
 
 
-    #[test]
-    fn add_overflow() {
-
-        let mut chip = Chip8::new();
-
-        // set register 0 and 1 to 200
-        chip.registers.set_value(0,200);
-        chip.registers.set_value(1,200);
-
-        chip.set_instruction(0x200, 0x8014);
-
-        cycle(&mut chip);
-
-        assert_eq!(chip.registers.get_value(0), 0x90);
-        assert_eq!(chip.registers.get_value(0xF), 1);
-
+fn prompt() {
+    print!("(chip8) ");
+    let _ = io::stdout().flush();
+}
 
 
+/// Save state slot for F1 to F10, shift saves and without shift loads.
+fn state_slot(code: Keycode) -> Option<u8> {
+    let slots = [
+        Keycode::F1, Keycode::F2, Keycode::F3, Keycode::F4, Keycode::F5,
+        Keycode::F6, Keycode::F7, Keycode::F8, Keycode::F9, Keycode::F10,
+    ];
 
-    }
+    slots.iter().position(|k| *k == code).map(|slot| slot as u8)
 }
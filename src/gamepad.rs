@@ -0,0 +1,153 @@
+//! Which gamepad buttons and stick directions press which hex keys, for up
+//! to two players.
+//!
+//! Inputs are named the way SDL's game controller API names them: buttons
+//! like `"a"`, `"start"` or `"dpup"`, and stick or trigger directions as the
+//! axis name with a sign, `"leftx-"` for the left stick pushed left and
+//! `"lefty+"` for down.
+
+use crate::keymap::KeyMap;
+
+/// Controllers played at once, the first plugged in is player 1.
+pub const PLAYERS: usize = 2;
+
+/// How far a stick has to be pushed to press its direction, in percent.
+pub const DEFAULT_DEADZONE: u8 = 30;
+
+/// The rom database's button names for each player and the inputs they
+/// stand for.
+const BUTTONS: [(&str, &str, &[&str]); 6] = [
+    ("up", "player2Up", &["dpup", "lefty-"]),
+    ("down", "player2Down", &["dpdown", "lefty+"]),
+    ("left", "player2Left", &["dpleft", "leftx-"]),
+    ("right", "player2Right", &["dpright", "leftx+"]),
+    ("a", "player2A", &["a"]),
+    ("b", "player2B", &["b"]),
+];
+
+
+/// The inputs of each player's controller pressing hex keys.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PadMap {
+    players: [KeyMap; PLAYERS],
+    /// Percent of the way a stick is pushed before it counts.
+    pub deadzone: u8,
+}
+
+impl PadMap {
+
+    /// No inputs mapped.
+    pub fn empty() -> Self {
+        Self {
+            players: [KeyMap::empty(), KeyMap::empty()],
+            deadzone: DEFAULT_DEADZONE,
+        }
+    }
+
+    /// The inputs for buttons named like in the rom database, `("up", 1)`
+    /// or `("player2Down", 0xD)`. Names it doesn't know are left out.
+    pub fn from_buttons(buttons: &[(String, u8)]) -> Self {
+        let mut map = Self::empty();
+        for (button, key) in buttons {
+            let found = BUTTONS.iter().find_map(|(one, two, inputs)| match button.as_str() {
+                name if name == *one => Some((0, inputs)),
+                name if name == *two => Some((1, inputs)),
+                _ => None,
+            });
+            if let Some((player, inputs)) = found {
+                let mut names = map.players[player].host_keys(*key).to_vec();
+                names.extend(inputs.iter().map(|input| input.to_string()));
+                map.set(player, *key, &names);
+            }
+        }
+        map
+    }
+
+    /// Replace the inputs of a hex key for a player, 0 or 1.
+    pub fn set(&mut self, player: usize, key: u8, inputs: &[String]) {
+        self.players[player].set(key, inputs);
+    }
+
+    /// The hex key an input of a player's controller presses.
+    pub fn hex_key(&self, player: usize, input: &str) -> Option<u8> {
+        self.players.get(player)?.hex_key(input)
+    }
+
+    /// The inputs of a player's controller.
+    pub fn player(&self, player: usize) -> &KeyMap {
+        &self.players[player]
+    }
+
+    /// The two directions of an axis with whether each is pushed past the
+    /// deadzone, for an SDL axis value from -32768 to 32767.
+    pub fn axis_directions(&self, axis: &str, value: i16) -> [(String, bool); 2] {
+        let threshold = i32::from(self.deadzone.min(100)) * i32::from(i16::MAX) / 100;
+        let value = i32::from(value);
+        [
+            (format!("{}-", axis), value < -threshold),
+            (format!("{}+", axis), value > threshold),
+        ]
+    }
+}
+
+/// Player 1 on the d-pad and left stick as the `W`, `A`, `S`, `D` block
+/// of the keypad, 5, 7, 8 and 9, with `a` for 6 and `b` for 4.
+impl Default for PadMap {
+    fn default() -> Self {
+        let buttons = [("up", 0x5), ("down", 0x8), ("left", 0x7), ("right", 0x9), ("a", 0x6), ("b", 0x4)];
+        Self::from_buttons(&buttons.iter().map(|(name, key)| (name.to_string(), *key)).collect::<Vec<_>>())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_plays_the_wasd_block() {
+        let map = PadMap::default();
+
+        assert_eq!(map.hex_key(0, "dpup"), Some(0x5));
+        assert_eq!(map.hex_key(0, "lefty-"), Some(0x5));
+        assert_eq!(map.hex_key(0, "leftx+"), Some(0x9));
+        assert_eq!(map.hex_key(0, "a"), Some(0x6));
+        assert_eq!(map.hex_key(1, "dpup"), None);
+        assert_eq!(map.hex_key(2, "dpup"), None);
+    }
+
+    #[test]
+    fn two_players_from_rom_buttons() {
+        let buttons = vec![
+            ("up".to_string(), 0x1),
+            ("down".to_string(), 0x4),
+            ("player2Up".to_string(), 0xC),
+            ("player2Down".to_string(), 0xD),
+            ("jump".to_string(), 0x5),
+        ];
+        let map = PadMap::from_buttons(&buttons);
+
+        assert_eq!(map.hex_key(0, "dpdown"), Some(0x4));
+        assert_eq!(map.hex_key(1, "lefty-"), Some(0xC));
+        assert_eq!(map.hex_key(1, "dpdown"), Some(0xD));
+        assert_eq!(map.hex_key(0, "dpup"), Some(0x1));
+        assert_eq!(map.player(0).iter().count(), 4);
+    }
+
+    #[test]
+    fn sticks_have_a_deadzone() {
+        let mut map = PadMap::default();
+        let pushed = |map: &PadMap, value| {
+            let [(_, left), (_, right)] = map.axis_directions("leftx", value);
+            (left, right)
+        };
+
+        assert_eq!(map.axis_directions("leftx", -20000)[0], ("leftx-".to_string(), true));
+        assert_eq!(pushed(&map, 5000), (false, false));
+        assert_eq!(pushed(&map, 20000), (false, true));
+        assert_eq!(pushed(&map, i16::MIN), (true, false));
+
+        map.deadzone = 10;
+        assert_eq!(pushed(&map, 5000), (false, true));
+    }
+}
@@ -1,24 +1,79 @@
+use std::fmt;
+
 type Addr = u16;
 type Register = u8;
-type Key = u8;
 
-pub fn parse(upper: u8, lower: u8) -> Instruction {
+/// An opcode that does not decode to any known instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DecodeError {
+    pub opcode: u16,
+    pub addr: Addr,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid opcode {:04X} at {:#05X}", self.opcode, self.addr)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decode the instruction at `addr` in `memory`. Unlike `parse` this also
+/// handles the 4 byte XO-CHIP F000 NNNN instruction. An instruction cut off
+/// by the end of memory is an error.
+pub fn decode(memory: &[u8], addr: usize) -> Result<Instruction, DecodeError> {
+    let (upper, lower) = match memory.get(addr..addr + 2) {
+        Some(bytes) => (bytes[0], bytes[1]),
+        None => {
+            let upper = memory.get(addr).copied().unwrap_or(0);
+            return Err(DecodeError { opcode: (upper as u16) << 8, addr: addr as Addr });
+        },
+    };
+
+    if upper == 0xF0 && lower == 0x00 {
+        return match memory.get(addr + 2..addr + 4) {
+            Some(long) => Ok(Instruction::LoadLongAddr(((long[0] as u16) << 8) | long[1] as u16)),
+            None => Err(DecodeError { opcode: 0xF000, addr: addr as Addr }),
+        };
+    }
+
+    parse(addr as Addr, upper, lower)
+}
+
+/// Decode the 2 byte instruction made up of `upper` and `lower`, read from `addr`.
+pub fn parse(addr: Addr, upper: u8, lower: u8) -> Result<Instruction, DecodeError> {
 
     let start = upper >> 4;
+    let invalid = DecodeError {
+        opcode: ((upper as u16) << 8) | lower as u16,
+        addr,
+    };
 
     use Instruction::*;
-    match start {
-        0 => match lower {
-            0xE0 => Cls,
-            0xEE => Ret,
-            _ => panic!("Invalid instruciton"),
+    let instr = match start {
+        0 => match (upper, lower) {
+            (0x00, 0xE0) => Cls,
+            (0x00, 0xEE) => Ret,
+            (0x00, 0xC0..=0xCF) => ScrollDown(lower & 0x0F),
+            (0x00, 0xD0..=0xDF) => ScrollUp(lower & 0x0F),
+            (0x00, 0xFB) => ScrollRight,
+            (0x00, 0xFC) => ScrollLeft,
+            (0x00, 0xFD) => Exit,
+            (0x00, 0xFE) => LowRes,
+            (0x00, 0xFF) => HighRes,
+            _ => return Err(invalid),
         },
 
         1 => Jump(to_address(upper, lower)),
         2 => Call(to_address(upper, lower)),
         3 => SkipEqConst(to_reg_upper(upper), lower),
         4 => SkipNotEqConst(to_reg_upper(upper), lower),
-        5 => SkipEqReg(to_reg_upper(upper), to_reg_lower(lower)),
+        5 => match lower & 0x0F {
+            0 => SkipEqReg(to_reg_upper(upper), to_reg_lower(lower)),
+            2 => SaveRange(to_reg_upper(upper), to_reg_lower(lower)),
+            3 => LoadRange(to_reg_upper(upper), to_reg_lower(lower)),
+            _ => return Err(invalid),
+        },
         6 => LoadConst(to_reg_upper(upper), lower),
         7 => AddConst(to_reg_upper(upper), lower),
         8 => match lower & 0x0F {
@@ -31,10 +86,10 @@ pub fn parse(upper: u8, lower: u8) -> Instruction {
             6 => ShiftRight(to_reg_upper(upper), to_reg_lower(lower)),
             7 => SubN(to_reg_upper(upper), to_reg_lower(lower)),
             0xE => ShiftLeft(to_reg_upper(upper), to_reg_lower(lower)),
-            _ => panic!("Not a valid instruciton {:#01x}, {:#01x}", upper, lower)
+            _ => return Err(invalid),
         },
 
-        9 => SkipNotEqReg(to_reg_upper(upper), to_reg_lower(lower)), // TODO: Not 100% corect we match 9XY_ and not only 9XY0, we should also check that the lower 4 bits of lower is
+        9 if lower & 0x0F == 0 => SkipNotEqReg(to_reg_upper(upper), to_reg_lower(lower)),
         0xA => LoadAddr(to_address(upper, lower)),
         0xB => JumpOffset(to_address(upper, lower)),
         0xC => Rand(to_reg_upper(upper), lower),
@@ -42,23 +97,31 @@ pub fn parse(upper: u8, lower: u8) -> Instruction {
         0xE => match lower {
             0x9E => SkipOnKeyPressed(to_reg_upper(upper)),
             0xA1 => SkipKeyNotPressed(to_reg_upper(upper)),
-            _ => panic!("Not a valid instruciton {:#02x}, {:#02x}", upper, lower)
+            _ => return Err(invalid),
         },
 
         0xF => match lower {
+            0x01 => SelectPlanes(upper & 0x0F),
+            0x02 if upper == 0xF0 => LoadAudio,
             0x07 => LoadDelay(to_reg_upper(upper)),
             0x0A => WaitKeyPress(to_reg_upper(upper)),
             0x15 => SetDelay(to_reg_upper(upper)),
             0x18 => SetSound(to_reg_upper(upper)),
             0x1e => AddAddr(to_reg_upper(upper)),
             0x29 => SetSpriteAddr(to_reg_upper(upper)),
+            0x30 => SetBigSpriteAddr(to_reg_upper(upper)),
             0x33 => BCD(to_reg_upper(upper)),
+            0x3A => SetPitch(to_reg_upper(upper)),
             0x55 => Store(to_reg_upper(upper)),
             0x65 => Load(to_reg_upper(upper)),
-            _ => panic!("Not a valid instruciton {:#02x}, {:#02x}", upper, lower)
+            0x75 => StoreFlags(to_reg_upper(upper)),
+            0x85 => LoadFlags(to_reg_upper(upper)),
+            _ => return Err(invalid),
         },
-        _ => panic!("Not implemented"),
-    }
+        _ => return Err(invalid),
+    };
+
+    Ok(instr)
 }
 
 
@@ -86,6 +149,16 @@ fn to_address( upper: u8, lower: u8) -> Addr {
 pub enum Instruction {
     Cls,
     Ret,
+    // SUPER-CHIP
+    ScrollDown(u8),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    LowRes,
+    HighRes,
+    // XO-CHIP
+    ScrollUp(u8),
+
     Jump(Addr),
     Call(Addr),
     SkipEqConst(Register, u8),
@@ -117,11 +190,172 @@ pub enum Instruction {
     SetSpriteAddr(Register),
     BCD(Register),
     Store(Register),
-    Load(Register)
+    Load(Register),
+    // SUPER-CHIP
+    SetBigSpriteAddr(Register),
+    StoreFlags(Register),
+    LoadFlags(Register),
+    // XO-CHIP
+    SaveRange(Register, Register),
+    LoadRange(Register, Register),
+    LoadLongAddr(Addr),
+    SelectPlanes(u8),
+    LoadAudio,
+    SetPitch(Register),
+}
+
+impl Instruction {
+    /// Number of bytes the instruction takes up in memory.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LoadLongAddr(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// The bytes of the instruction, the inverse of `decode`.
+    pub fn encode(&self) -> Vec<u8> {
+        use Instruction::*;
+
+        let xy = |op: u16, x: Register, y: Register, n: u16| op | (x as u16) << 8 | (y as u16) << 4 | n;
+        let xnn = |op: u16, x: Register, nn: u8| op | (x as u16) << 8 | nn as u16;
+        let nnn = |op: u16, addr: Addr| op | (addr & 0x0FFF);
+
+        let opcode = match *self {
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            ScrollDown(n) => 0x00C0 | (n & 0xF) as u16,
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            LowRes => 0x00FE,
+            HighRes => 0x00FF,
+            ScrollUp(n) => 0x00D0 | (n & 0xF) as u16,
+
+            Jump(addr) => nnn(0x1000, addr),
+            Call(addr) => nnn(0x2000, addr),
+            SkipEqConst(x, nn) => xnn(0x3000, x, nn),
+            SkipNotEqConst(x, nn) => xnn(0x4000, x, nn),
+            SkipEqReg(x, y) => xy(0x5000, x, y, 0),
+            SkipNotEqReg(x, y) => xy(0x9000, x, y, 0),
+            LoadConst(x, nn) => xnn(0x6000, x, nn),
+            AddConst(x, nn) => xnn(0x7000, x, nn),
+            LoadReg(x, y) => xy(0x8000, x, y, 0),
+            Or(x, y) => xy(0x8000, x, y, 1),
+            And(x, y) => xy(0x8000, x, y, 2),
+            Xor(x, y) => xy(0x8000, x, y, 3),
+            Add(x, y) => xy(0x8000, x, y, 4),
+            Sub(x, y) => xy(0x8000, x, y, 5),
+            ShiftRight(x, y) => xy(0x8000, x, y, 6),
+            SubN(x, y) => xy(0x8000, x, y, 7),
+            ShiftLeft(x, y) => xy(0x8000, x, y, 0xE),
+            LoadAddr(addr) => nnn(0xA000, addr),
+            JumpOffset(addr) => nnn(0xB000, addr),
+            Rand(x, nn) => xnn(0xC000, x, nn),
+            Draw(x, y, n) => xy(0xD000, x, y, (n & 0xF) as u16),
+            SkipOnKeyPressed(x) => xnn(0xE000, x, 0x9E),
+            SkipKeyNotPressed(x) => xnn(0xE000, x, 0xA1),
+            LoadDelay(x) => xnn(0xF000, x, 0x07),
+            WaitKeyPress(x) => xnn(0xF000, x, 0x0A),
+            SetDelay(x) => xnn(0xF000, x, 0x15),
+            SetSound(x) => xnn(0xF000, x, 0x18),
+            AddAddr(x) => xnn(0xF000, x, 0x1E),
+            SetSpriteAddr(x) => xnn(0xF000, x, 0x29),
+            BCD(x) => xnn(0xF000, x, 0x33),
+            Store(x) => xnn(0xF000, x, 0x55),
+            Load(x) => xnn(0xF000, x, 0x65),
+            SetBigSpriteAddr(x) => xnn(0xF000, x, 0x30),
+            StoreFlags(x) => xnn(0xF000, x, 0x75),
+            LoadFlags(x) => xnn(0xF000, x, 0x85),
+            SaveRange(x, y) => xy(0x5000, x, y, 2),
+            LoadRange(x, y) => xy(0x5000, x, y, 3),
+            LoadLongAddr(addr) => return vec![0xF0, 0x00, (addr >> 8) as u8, addr as u8],
+            SelectPlanes(n) => xnn(0xF000, n & 0xF, 0x01),
+            LoadAudio => 0xF002,
+            SetPitch(x) => xnn(0xF000, x, 0x3A),
+        };
+
+        vec![(opcode >> 8) as u8, opcode as u8]
+    }
+
+    /// The mnemonic with addresses replaced by the names `labels` returns,
+    /// addresses without a name are written as hex.
+    pub fn with_labels<F: Fn(Addr) -> Option<String>>(&self, labels: F) -> String {
+        let mut out = String::new();
+        let _ = self.write(&mut out, &|addr| labels(addr).unwrap_or_else(|| format!("0x{:03X}", addr)));
+        out
+    }
+
+    fn write(&self, f: &mut dyn fmt::Write, addr: &dyn Fn(Addr) -> String) -> fmt::Result {
+        use Instruction::*;
+        match *self {
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            ScrollDown(n) => write!(f, "SCD {}", n),
+            ScrollRight => write!(f, "SCR"),
+            ScrollLeft => write!(f, "SCL"),
+            Exit => write!(f, "EXIT"),
+            LowRes => write!(f, "LOW"),
+            HighRes => write!(f, "HIGH"),
+            ScrollUp(n) => write!(f, "SCU {}", n),
+
+            Jump(a) => write!(f, "JP {}", addr(a)),
+            Call(a) => write!(f, "CALL {}", addr(a)),
+            SkipEqConst(x, byte) => write!(f, "SE V{:X}, 0x{:02X}", x, byte),
+            SkipNotEqConst(x, byte) => write!(f, "SNE V{:X}, 0x{:02X}", x, byte),
+            SkipEqReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            SkipNotEqReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LoadConst(x, byte) => write!(f, "LD V{:X}, 0x{:02X}", x, byte),
+            AddConst(x, byte) => write!(f, "ADD V{:X}, 0x{:02X}", x, byte),
+            LoadReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Add(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            SubN(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            LoadAddr(a) => write!(f, "LD I, {}", addr(a)),
+            JumpOffset(a) => write!(f, "JP V0, {}", addr(a)),
+            Rand(x, byte) => write!(f, "RND V{:X}, 0x{:02X}", x, byte),
+            Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            SkipOnKeyPressed(x) => write!(f, "SKP V{:X}", x),
+            SkipKeyNotPressed(x) => write!(f, "SKNP V{:X}", x),
+            LoadDelay(x) => write!(f, "LD V{:X}, DT", x),
+            WaitKeyPress(x) => write!(f, "LD V{:X}, K", x),
+            SetDelay(x) => write!(f, "LD DT, V{:X}", x),
+            SetSound(x) => write!(f, "LD ST, V{:X}", x),
+            AddAddr(x) => write!(f, "ADD I, V{:X}", x),
+            SetSpriteAddr(x) => write!(f, "LD F, V{:X}", x),
+            BCD(x) => write!(f, "LD B, V{:X}", x),
+            Store(x) => write!(f, "LD [I], V{:X}", x),
+            Load(x) => write!(f, "LD V{:X}, [I]", x),
+            SetBigSpriteAddr(x) => write!(f, "LD HF, V{:X}", x),
+            StoreFlags(x) => write!(f, "LD R, V{:X}", x),
+            LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+            SaveRange(x, y) => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            LoadRange(x, y) => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            LoadLongAddr(a) => write!(f, "LD I, LONG 0x{:04X}", a),
+            SelectPlanes(n) => write!(f, "PLANE {}", n),
+            LoadAudio => write!(f, "AUDIO"),
+            SetPitch(x) => write!(f, "PITCH V{:X}", x),
+        }
+    }
+}
+
+/// Mnemonics in the usual CHIP-8 assembler syntax, `LD V1, 0x20`, with the
+/// SUPER-CHIP and XO-CHIP additions.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, &|addr| format!("0x{:03X}", addr))
+    }
 }
 
 
 #[cfg(test)]
+// the rstest cases keep the hex casing they were written with
+#[allow(clippy::mixed_case_hex_literals)]
 mod tests {
 
     use rstest::*;
@@ -141,13 +375,90 @@ mod tests {
     #[case(0x7250, AddConst(2, 0x50))]
     #[case(0x80a0, LoadReg(0, 0xa))]
 
+    #[case(0x00C4, ScrollDown(4))]
+    #[case(0x00FB, ScrollRight)]
+    #[case(0x00FC, ScrollLeft)]
+    #[case(0x00FD, Exit)]
+    #[case(0x00FE, LowRes)]
+    #[case(0x00FF, HighRes)]
+    #[case(0xD120, Draw(1, 2, 0))]
+    #[case(0xF330, SetBigSpriteAddr(3))]
+    #[case(0xF775, StoreFlags(7))]
+    #[case(0xF285, LoadFlags(2))]
+
+    #[case(0x00D3, ScrollUp(3))]
+    #[case(0x5122, SaveRange(1, 2))]
+    #[case(0x5E43, LoadRange(0xE, 4))]
+    #[case(0xF301, SelectPlanes(3))]
+    #[case(0xF002, LoadAudio)]
+    #[case(0xF53A, SetPitch(5))]
+
     fn parse_test(#[case] data: u16, #[case] expected: Instruction) {
 
         println!("{:#04x}", data);
-        let instr = parse(((data & 0xFF00) >> 8) as u8, (data & 0x00FF) as u8);
+        let instr = parse(0x200, ((data & 0xFF00) >> 8) as u8, (data & 0x00FF) as u8);
 
         println!("{:?}", instr);
-        assert_eq!(instr,expected);
+        assert_eq!(instr, Ok(expected));
+    }
+
+    #[rstest]
+    #[case(0x0123)]
+    #[case(0x00E1)]
+    #[case(0x5121)]
+    #[case(0x8128)]
+    #[case(0x9121)]
+    #[case(0xE1A2)]
+    #[case(0xF1FF)]
+    #[case(0xF102)]
+    fn parse_invalid(#[case] data: u16) {
+        let instr = parse(0x3A4, ((data & 0xFF00) >> 8) as u8, (data & 0x00FF) as u8);
+
+        assert_eq!(instr, Err(DecodeError { opcode: data, addr: 0x3A4 }));
+    }
+
+    #[rstest]
+    #[case(Cls, "CLS")]
+    #[case(ScrollDown(4), "SCD 4")]
+    #[case(Jump(0x2A4), "JP 0x2A4")]
+    #[case(SkipEqConst(3, 0xE), "SE V3, 0x0E")]
+    #[case(Sub(0xA, 0xF), "SUB VA, VF")]
+    #[case(JumpOffset(0x300), "JP V0, 0x300")]
+    #[case(Draw(1, 2, 15), "DRW V1, V2, 15")]
+    #[case(WaitKeyPress(7), "LD V7, K")]
+    #[case(Store(5), "LD [I], V5")]
+    #[case(SetBigSpriteAddr(3), "LD HF, V3")]
+    #[case(LoadRange(0xE, 4), "LOAD VE, V4")]
+    #[case(LoadLongAddr(0x1234), "LD I, LONG 0x1234")]
+    fn display_test(#[case] instr: Instruction, #[case] expected: &str) {
+        assert_eq!(instr.to_string(), expected);
+    }
+
+    #[test]
+    fn encode_reverses_decode() {
+        for opcode in 0..=0xFFFFu16 {
+            let bytes = [(opcode >> 8) as u8, opcode as u8, 0x12, 0x34];
+
+            if let Ok(instr) = decode(&bytes, 0) {
+                assert_eq!(instr.encode(), bytes[..instr.size() as usize], "{:04X}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn labels_replace_addresses() {
+        let labels = |addr| if addr == 0x204 { Some("loop".to_string()) } else { None };
+
+        assert_eq!(Call(0x204).with_labels(labels), "CALL loop");
+        assert_eq!(LoadAddr(0x300).with_labels(labels), "LD I, 0x300");
+    }
+
+    #[test]
+    fn decode_long_load() {
+        let memory = [0xF0, 0x00, 0x12, 0x34, 0xF0, 0x00];
+
+        assert_eq!(decode(&memory, 0), Ok(LoadLongAddr(0x1234)));
+        assert_eq!(decode(&memory, 4), Err(DecodeError { opcode: 0xF000, addr: 4 }));
     }
 
     #[rstest]
@@ -1,61 +1,289 @@
-use sdl2::keyboard::Keycode;
+/// How long the program sees a key press. Input arrives between frames, so
+/// with `Off` a key pressed and released before the next frame is missed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LatchPolicy {
+    /// Keys read as they are right now.
+    Off,
+    /// A key pressed before a frame starts reads as held for all of that
+    /// frame, even when released before it.
+    #[default]
+    Frame,
+}
+
+impl LatchPolicy {
+
+    /// Look up a policy by the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(LatchPolicy::Off),
+            "frame" => Some(LatchPolicy::Frame),
+            _ => None,
+        }
+    }
+}
+
 
+/// The 16 key hex keypad. Frontends translate their own input events into
+/// hex key numbers and report them with `set_key`, which also tracks when
+/// keys go down and up for FX0A. Call `begin_frame` before running each
+/// frame for the latch.
+#[derive(Clone)]
 pub struct Keyboard {
     data: [bool; 16],
-    mapping: [(Keycode, usize); 16]
+    latch: LatchPolicy,
+    /// Bit n set when key n went down since the current frame started.
+    pressed_since_frame: u16,
+    /// The keys that went down before the current frame started, held for
+    /// all of it.
+    latched: u16,
+    /// While FX0A waits for a key, see `wait_release`.
+    wait: Option<KeyWait>,
+}
+
+/// An FX0A waiting the way the COSMAC VIP does.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KeyWait {
+    /// Bit n set when key n went down after the wait started.
+    pub pressed: u16,
+    /// The first of those keys that went up again, it ends the wait.
+    pub released: Option<u8>,
 }
 
 impl Keyboard {
 
     pub fn new() -> Self {
-
         Self {
             data: [false; 16],
-            mapping: [
-                (Keycode::Kp0, 0),
-                (Keycode::Kp1, 1),
-                (Keycode::Kp2, 2),
-                (Keycode::Kp3, 3),
+            latch: LatchPolicy::default(),
+            pressed_since_frame: 0,
+            latched: 0,
+            wait: None,
+        }
+    }
+
+    /// Held, or latched by the policy.
+    #[inline]
+    pub fn key_pressed(&self, key: u8) -> bool {
+        self.held_keys() & (1 << (key & 0xF)) != 0
+    }
 
-                (Keycode::Kp4, 4),
-                (Keycode::Kp5, 5),
-                (Keycode::Kp6, 6),
-                (Keycode::Kp7, 7),
+    /// The lowest key held, FX0A with the `wait_key_on_press` quirk.
+    pub fn next_key(&self) -> Option<u8> {
+        (0..16).find(|key| self.key_pressed(*key))
+    }
 
-                (Keycode::Kp8, 8),
-                (Keycode::Kp9, 9),
-                (Keycode::A, 10),
-                (Keycode::B, 11),
+    /// FX0A on the COSMAC VIP: the first call starts waiting, keys already
+    /// held don't count, and a key pressed after that ends the wait when it
+    /// is released. Returns that key once it has been.
+    pub fn wait_release(&mut self) -> Option<u8> {
+        let key = self.wait.get_or_insert_with(KeyWait::default).released;
+        if key.is_some() {
+            self.wait = None;
+        }
+        key
+    }
 
-                (Keycode::C, 12),
-                (Keycode::D, 13),
-                (Keycode::E, 14),
-                (Keycode::F, 15),
-            ]
+    /// FX0A is waiting and a key pressed since is held, the VIP beeps then.
+    pub fn wait_key_held(&self) -> bool {
+        match self.wait {
+            Some(wait) => wait.pressed & self.held_keys() != 0,
+            None => false,
         }
     }
 
+    /// The state of a wait, for save states.
+    pub fn key_wait(&self) -> Option<KeyWait> {
+        self.wait
+    }
+
+    pub fn set_key_wait(&mut self, wait: Option<KeyWait>) {
+        self.wait = wait;
+    }
+
+    pub fn latch(&self) -> LatchPolicy {
+        self.latch
+    }
+
+    pub fn set_latch(&mut self, latch: LatchPolicy) {
+        let before = self.held_keys();
+        self.latch = latch;
+        if latch == LatchPolicy::Off {
+            self.pressed_since_frame = 0;
+            self.latched = 0;
+        }
+        self.changed(before);
+    }
+
     #[inline]
-    pub fn key_pressed(&self, key: u8) -> bool {
-        self.data[key as usize]
+    pub fn set_key(&mut self, key: u8, down: bool) {
+        let key = key & 0xF;
+        let before = self.held_keys();
+
+        if down && !self.data[key as usize] && self.latch == LatchPolicy::Frame {
+            self.pressed_since_frame |= 1 << key;
+        }
+        self.data[key as usize] = down;
+
+        self.changed(before);
     }
 
-    pub fn next_key(&self) -> Option<u8> {
+    /// Bit n set when key n is physically down, latches left out.
+    pub fn keys_down(&self) -> u16 {
+        self.data.iter()
+            .enumerate()
+            .fold(0, |keys, (key, down)| keys | ((*down as u16) << key))
+    }
 
-        for i in 0..16 {
-            if self.data[i] {
-                return Some(i as u8);
-            }
+    /// Bit n set when key n went down since the current frame started.
+    pub fn pressed_since_frame(&self) -> u16 {
+        self.pressed_since_frame
+    }
+
+    /// Bit n set when key n is latched for the current frame.
+    pub fn latched_keys(&self) -> u16 {
+        self.latched
+    }
+
+    /// Bit n set when key n reads as pressed, held or latched.
+    pub fn held_keys(&self) -> u16 {
+        self.keys_down() | self.pressed_since_frame | self.latched
+    }
+
+    /// Set the keys down and the latches as `keys_down`, `pressed_since_frame`
+    /// and `latched_keys` returned them. For restoring a save state, FX0A
+    /// doesn't see it.
+    pub fn restore_keys(&mut self, down: u16, pressed_since_frame: u16, latched: u16) {
+        for (key, key_down) in self.data.iter_mut().enumerate() {
+            *key_down = down & (1 << key) != 0;
         }
-        None
+        self.pressed_since_frame = pressed_since_frame;
+        self.latched = latched;
+    }
+
+    /// A frame starts: the keys pressed since the last one are latched for
+    /// it and the ones latched for the last frame are let go.
+    pub fn begin_frame(&mut self) {
+        let before = self.held_keys();
+        self.latched = std::mem::take(&mut self.pressed_since_frame);
+        self.changed(before);
     }
 
-    pub fn check_key(&mut self, code: Keycode, down: bool) {
-        for (k,i) in self.mapping.iter() {
-            if *k == code {
-                self.data[*i] = down;
-                return;
+    /// Keys reading as pressed changed from `before`, track them for FX0A.
+    fn changed(&mut self, before: u16) {
+        let after = self.held_keys();
+
+        if let Some(wait) = self.wait.as_mut() {
+            wait.pressed |= after & !before;
+
+            let released = before & !after & wait.pressed;
+            if released != 0 && wait.released.is_none() {
+                wait.released = Some(released.trailing_zeros() as u8);
             }
         }
     }
 }
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_ends_on_release_of_a_new_press() {
+        let mut keyboard = Keyboard::new();
+        keyboard.set_key(0x5, true);
+
+        // 5 was already held when the wait started
+        assert_eq!(keyboard.wait_release(), None);
+        keyboard.set_key(0x5, false);
+        keyboard.begin_frame();
+        assert_eq!(keyboard.wait_release(), None);
+
+        keyboard.set_key(0xA, true);
+        assert!(keyboard.wait_key_held());
+        assert_eq!(keyboard.wait_release(), None);
+
+        keyboard.set_key(0xA, false);
+        keyboard.begin_frame();
+        assert!(keyboard.wait_key_held());
+        assert_eq!(keyboard.wait_release(), None);
+
+        keyboard.begin_frame();
+        assert!(!keyboard.wait_key_held());
+        assert_eq!(keyboard.wait_release(), Some(0xA));
+
+        // the next wait starts over
+        assert_eq!(keyboard.key_wait(), None);
+        assert_eq!(keyboard.wait_release(), None);
+    }
+
+    #[test]
+    fn next_key_is_the_lowest_held() {
+        let mut keyboard = Keyboard::new();
+        assert_eq!(keyboard.next_key(), None);
+
+        keyboard.set_key(0x9, true);
+        keyboard.set_key(0x3, true);
+        assert_eq!(keyboard.next_key(), Some(0x3));
+    }
+
+    #[test]
+    fn tap_between_frames_lasts_a_frame() {
+        let mut keyboard = Keyboard::new();
+
+        keyboard.set_key(0x4, true);
+        keyboard.set_key(0x4, false);
+        assert!(keyboard.key_pressed(0x4));
+
+        keyboard.begin_frame();
+        assert!(keyboard.key_pressed(0x4));
+
+        keyboard.begin_frame();
+        assert!(!keyboard.key_pressed(0x4));
+    }
+
+    #[test]
+    fn held_keys_go_up_when_released() {
+        let mut keyboard = Keyboard::new();
+
+        keyboard.set_key(0x2, true);
+        keyboard.begin_frame();
+        keyboard.begin_frame();
+        assert!(keyboard.key_pressed(0x2));
+
+        // pressed before the frame before, no latch left
+        keyboard.set_key(0x2, false);
+        assert!(!keyboard.key_pressed(0x2));
+    }
+
+    #[test]
+    fn latch_off_reads_keys_as_they_are() {
+        let mut keyboard = Keyboard::new();
+        keyboard.set_latch(LatchPolicy::from_name("off").unwrap());
+
+        keyboard.set_key(0x4, true);
+        keyboard.set_key(0x4, false);
+        keyboard.begin_frame();
+        assert!(!keyboard.key_pressed(0x4));
+
+        keyboard.set_key(0x4, true);
+        assert!(keyboard.key_pressed(0x4));
+        assert_eq!(LatchPolicy::from_name("sometimes"), None);
+    }
+
+    #[test]
+    fn turning_the_latch_off_lets_go() {
+        let mut keyboard = Keyboard::new();
+        keyboard.set_key(0x8, true);
+        keyboard.set_key(0x8, false);
+
+        keyboard.set_latch(LatchPolicy::Off);
+        assert!(!keyboard.key_pressed(0x8));
+    }
+}
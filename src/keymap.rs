@@ -0,0 +1,184 @@
+//! Which host keys press which hex keys. Host keys are named the way SDL
+//! names them, `"Q"` or `"Keypad 7"`, so the mapping doesn't depend on the
+//! frontend's key codes.
+
+/// The hex keypad, row by row as printed on the COSMAC VIP.
+const HEX_KEYPAD: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC,
+    0x4, 0x5, 0x6, 0xD,
+    0x7, 0x8, 0x9, 0xE,
+    0xA, 0x0, 0xB, 0xF,
+];
+
+
+/// The host keys standing in for the hex keypad.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Layout {
+    /// The 4x4 block from `1` to `V`, the layout games' manuals assume.
+    #[default]
+    Qwerty,
+    /// The same block on a French keyboard, `1` to `V` again.
+    Azerty,
+    Dvorak,
+    /// The digits on the numeric keypad and `A` to `F` on the letters, each
+    /// key its own hex digit.
+    Numpad,
+}
+
+impl Layout {
+
+    /// Look up a layout by the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "qwerty" => Some(Layout::Qwerty),
+            "azerty" => Some(Layout::Azerty),
+            "dvorak" => Some(Layout::Dvorak),
+            "numpad" => Some(Layout::Numpad),
+            _ => None,
+        }
+    }
+
+    /// The host key for each hex key, from 0 to F.
+    fn keys(self) -> [&'static str; 16] {
+        let block = match self {
+            Layout::Qwerty => [
+                "1", "2", "3", "4",
+                "Q", "W", "E", "R",
+                "A", "S", "D", "F",
+                "Z", "X", "C", "V",
+            ],
+            Layout::Azerty => [
+                "1", "2", "3", "4",
+                "A", "Z", "E", "R",
+                "Q", "S", "D", "F",
+                "W", "X", "C", "V",
+            ],
+            Layout::Dvorak => [
+                "1", "2", "3", "4",
+                "'", ",", ".", "P",
+                "A", "O", "E", "U",
+                ";", "Q", "J", "K",
+            ],
+            Layout::Numpad => return [
+                "Keypad 0", "Keypad 1", "Keypad 2", "Keypad 3",
+                "Keypad 4", "Keypad 5", "Keypad 6", "Keypad 7",
+                "Keypad 8", "Keypad 9", "A", "B",
+                "C", "D", "E", "F",
+            ],
+        };
+
+        let mut keys = [""; 16];
+        for (name, key) in block.iter().zip(HEX_KEYPAD.iter()) {
+            keys[*key as usize] = name;
+        }
+        keys
+    }
+}
+
+/// Host key names for each hex key, any number of them per key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyMap {
+    keys: [Vec<String>; 16],
+}
+
+impl KeyMap {
+
+    pub fn new(layout: Layout) -> Self {
+        let mut keys: [Vec<String>; 16] = Default::default();
+        for (names, name) in keys.iter_mut().zip(layout.keys().iter()) {
+            names.push(name.to_string());
+        }
+        Self { keys }
+    }
+
+    /// No host keys for any hex key.
+    pub fn empty() -> Self {
+        Self { keys: Default::default() }
+    }
+
+    /// Replace the host keys of a hex key. A host key presses one hex key,
+    /// so the names are taken from the keys that had them.
+    pub fn set(&mut self, key: u8, names: &[String]) {
+        for names_of_key in self.keys.iter_mut() {
+            names_of_key.retain(|name| !names.iter().any(|new| new.eq_ignore_ascii_case(name)));
+        }
+        self.keys[(key & 0xF) as usize] = names.to_vec();
+    }
+
+    /// The hex key a host key presses, names compare ignoring case.
+    pub fn hex_key(&self, name: &str) -> Option<u8> {
+        self.keys.iter()
+            .position(|names| names.iter().any(|known| known.eq_ignore_ascii_case(name)))
+            .map(|key| key as u8)
+    }
+
+    /// The host keys pressing a hex key.
+    pub fn host_keys(&self, key: u8) -> &[String] {
+        &self.keys[(key & 0xF) as usize]
+    }
+
+    /// Every host key name mapped, with its hex key.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u8)> {
+        self.keys.iter()
+            .enumerate()
+            .flat_map(|(key, names)| names.iter().map(move |name| (name.as_str(), key as u8)))
+    }
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self::new(Layout::default())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qwerty_is_the_hex_keypad() {
+        let map = KeyMap::default();
+        let row = |names: &str| names.split(' ').map(|name| map.hex_key(name).unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(row("1 2 3 4"), [0x1, 0x2, 0x3, 0xC]);
+        assert_eq!(row("q w e r"), [0x4, 0x5, 0x6, 0xD]);
+        assert_eq!(row("A S D F"), [0x7, 0x8, 0x9, 0xE]);
+        assert_eq!(row("Z X C V"), [0xA, 0x0, 0xB, 0xF]);
+        assert_eq!(map.hex_key("Keypad 0"), None);
+    }
+
+    #[test]
+    fn presets() {
+        let azerty = KeyMap::new(Layout::from_name("azerty").unwrap());
+        assert_eq!(azerty.hex_key("A"), Some(0x4));
+        assert_eq!(azerty.hex_key("W"), Some(0xA));
+
+        let dvorak = KeyMap::new(Layout::Dvorak);
+        assert_eq!(dvorak.hex_key("'"), Some(0x4));
+        assert_eq!(dvorak.hex_key(";"), Some(0xA));
+
+        let numpad = KeyMap::new(Layout::Numpad);
+        assert_eq!(numpad.hex_key("Keypad 7"), Some(0x7));
+        assert_eq!(numpad.hex_key("B"), Some(0xB));
+        assert_eq!(numpad.host_keys(0x0), ["Keypad 0"]);
+
+        assert_eq!(Layout::from_name("colemak"), None);
+    }
+
+    #[test]
+    fn several_host_keys_per_hex_key() {
+        let mut map = KeyMap::default();
+        map.set(0x5, &["Up".to_string(), "W".to_string(), "Keypad 8".to_string()]);
+
+        assert_eq!(map.hex_key("Up"), Some(0x5));
+        assert_eq!(map.hex_key("Keypad 8"), Some(0x5));
+        assert_eq!(map.hex_key("w"), Some(0x5));
+
+        // W moves from 5 to 4
+        map.set(0x4, &["Q".to_string(), "W".to_string()]);
+        assert_eq!(map.hex_key("W"), Some(0x4));
+        assert_eq!(map.host_keys(0x5), ["Up", "Keypad 8"]);
+        assert_eq!(map.iter().count(), 18);
+    }
+}
@@ -0,0 +1,31 @@
+//! Headless CHIP-8 interpreter core.
+//!
+//! Everything needed to run a program lives here and none of it depends on
+//! SDL: create a `chip::Chip8`, load a `chip::Program`, then drive it with
+//! `step` or `run_frame` and read the result back from the framebuffer.
+
+pub mod assembler;
+pub mod ch8t;
+pub mod chip;
+pub mod config;
+pub mod cpu;
+pub mod database;
+#[cfg(feature = "dap")]
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod display;
+pub mod gamepad;
+pub mod instructions;
+pub mod keyboard;
+pub mod keymap;
+pub mod movie;
+pub mod octo;
+pub mod quirks;
+pub mod random;
+pub mod registers;
+pub mod rewind;
+pub mod savestate;
+
+pub use chip::{Chip8, Platform, Program};
+pub use quirks::Quirks;
@@ -1,45 +1,180 @@
 use std::env;
 use std::io;
 use std::io::prelude::*;
-use std::fs::{self, File};
-use sdl2;
-use sdl2::pixels::Color;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use std::time::Duration;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use chip8::assembler;
+use chip8::ch8t;
+use chip8::chip::{Chip8, Platform, Program};
+use chip8::config::{Config, Settings};
+use chip8::database::{self, RomDatabase, RomInfo};
+use chip8::disasm::Disassembly;
+use chip8::gamepad::PadMap;
+use chip8::keyboard::LatchPolicy;
+use chip8::keymap::Layout;
+use chip8::movie::Movie;
+use chip8::octo;
+use chip8::quirks::Quirks;
 
-mod chip;
-mod instructions;
+mod audio;
 mod emulator;
-mod registers;
-mod keyboard;
-mod display;
+
+use audio::AudioSettings;
+
+const USAGE: &str = "Usage: rust-chip-8 <rom|-> [--platform chip8|schip|xochip] [--quirks default|vip|schip|xochip]
+                   [--seed <n>] [--cycles <per frame>] [--pitch <hz>] [--volume <0-100>] [--mute]
+                   [--record <movie> | --play <movie>] [--debug] [--load-address <addr>] [--config <file>]
+                   [--layout qwerty|azerty|dvorak|numpad] [--latch frame|off]
+       rust-chip-8 disasm <rom>
+       rust-chip-8 compile <source> <rom|text.ch8t>";
+
+/// Instructions per 60 Hz frame when not given, roughly 800 Hz.
+const DEFAULT_CYCLES_PER_FRAME: u32 = 13;
 
 fn main() -> io::Result<()> {
 
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.first().map(String::as_str) == Some("disasm") {
+        return match args.get(1) {
+            Some(rom) => disassemble(rom),
+            None => {
+                println!("disasm needs a rom\n{}", USAGE);
+                Ok(())
+            },
+        };
+    }
+
+    if args.first().map(String::as_str) == Some("compile") {
+        return match (args.get(1), args.get(2)) {
+            (Some(source), Some(out)) if out.ends_with(".ch8t") => fs::write(out, ch8t::write(&read_rom(source)?)),
+            (Some(source), Some(out)) => fs::write(out, read_rom(source)?),
+            _ => {
+                println!("compile needs a source and an output rom\n{}", USAGE);
+                Ok(())
+            },
+        };
+    }
+
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(msg) => {
+            println!("{}\n{}", msg, USAGE);
+            return Ok(());
+        }
+    };
 
-    if args.len() == 1 {
 
-        println!("Specify a input file to read as rom");
+    let mut program = load_program(&options.rom)?;
+    if let Some(addr) = options.load_address {
+        match &mut program {
+            Program::Binary { load_address, .. } => *load_address = addr,
+            // text programs are written for 0x200
+            Program::Text(_) => return Err(invalid_data(&options.rom, " --load-address only moves binary roms")),
+        }
+    }
+
+
+    let rom = program.get_binary_data().map_err(|err| invalid_data(&options.rom, err))?;
+
+    let config = match options.config.as_ref().map(PathBuf::from).or_else(Config::default_path) {
+        Some(path) => Config::load(&path).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        None => Config::default(),
+    };
 
-        return Ok(());
+    // what a known rom needs, the command line still wins
+    let info = RomDatabase::builtin().lookup(&rom);
+    if let Some(info) = info.as_ref() {
+        println!("{}", info.title);
+        if !info.keys.is_empty() {
+            println!("Keys: {}", info.key_hints());
+        }
     }
 
+    // the config file, then the rom database, then the config file's rom
+    // sections, then the command line
+    let file_name = Path::new(&options.rom).file_name().and_then(|name| name.to_str()).unwrap_or("");
+    let mut overrides = config.rom(file_name, &database::sha1(&rom));
+    overrides.merge(&options.settings);
+
+    let mut settings = config.global().clone();
+    if let Some(info) = info.as_ref() {
+        settings.merge(&known_settings(info, overrides.platform));
+    }
+    settings.merge(&overrides);
+
+    let platform = settings.platform.unwrap_or(Platform::Chip8);
+    let quirks = settings.quirks.resolve(platform.quirks());
+    let cycles_per_frame = settings.timing.cycles_per_frame.unwrap_or(DEFAULT_CYCLES_PER_FRAME);
+
+    let default_audio = AudioSettings::default();
+    let audio = AudioSettings {
+        pitch: settings.audio.pitch.unwrap_or(default_audio.pitch),
+        volume: settings.audio.volume.map_or(default_audio.volume, |volume| volume.clamp(0.0, 100.0) / 100.0),
+        muted: settings.audio.muted.unwrap_or(default_audio.muted),
+    };
+    let window_size = (
+        settings.video.width.unwrap_or(emulator::DEFAULT_WINDOW_SIZE.0),
+        settings.video.height.unwrap_or(emulator::DEFAULT_WINDOW_SIZE.1),
+    );
 
-    let program;
-    if args[1].ends_with(".ch8t") {
-        program = load_program_text(&args)?;
+    // a movie brings the settings it was recorded with
+    let movie = match options.play.as_ref() {
+        Some(path) => {
+            let movie = Movie::from_bytes(&fs::read(path)?)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            Some(movie)
+        },
+        None => None,
+    };
+
+    let chip = match movie.as_ref() {
+        Some(movie) => movie.new_chip(&rom).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        None => {
+            let mut chip = Chip8::for_platform(platform, quirks);
+
+            // print the seed, running again with it gives the same random numbers
+            let seed = settings.timing.seed.unwrap_or_else(time_seed);
+            println!("Random seed: {}", seed);
+            chip.seed_rng(seed);
+            chip
+        },
+    };
+
+    // save states go next to the rom, for stdin in the working directory
+    let rom_path = if options.rom == "-" { "stdin" } else { &options.rom };
+    let mut emulator = emulator::Emulator::new(chip, rom_path.into(), cycles_per_frame, audio, window_size);
+
+    if let Some(info) = info.as_ref() {
+        emulator.set_title(&info.title);
     }
-    else{
-        program = load_program_binary(&args)?;
+    // a movie plays with the latch it was recorded with
+    if movie.is_none() {
+        emulator.set_latch(settings.input.latch.unwrap_or_default());
     }
+    emulator.set_colors(settings.video.colors.as_deref().unwrap_or(&[]));
+    emulator.set_key_map(settings.input.key_map()).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
 
+    // controllers play the buttons the rom database lists for a rom
+    let pads = match info.as_ref().filter(|info| !info.keys.is_empty()) {
+        Some(info) => PadMap::from_buttons(&info.keys),
+        None => PadMap::default(),
+    };
+    emulator.set_pad_map(settings.input.gamepad.pad_map(pads)).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
 
-    let mut emulator = emulator::Emulator::new();
+    emulator.load_program(&program).map_err(|err| invalid_data(&options.rom, err))?;
 
-    emulator.load_program(&program);
+    if options.debug {
+        emulator.debug();
+    }
+    else if let Some(movie) = movie {
+        emulator.play(movie);
+    }
+    else if let Some(path) = options.record {
+        emulator.record(&rom, path.into());
+    }
 
 
     emulator.run();
@@ -48,25 +183,204 @@ fn main() -> io::Result<()> {
 
 }
 
-fn load_program_text(args: &Vec<String>) -> Result<chip::Program, io::Error> {
-    // if ends with chip8t load as text, otherwise binary
-    let path = &args[1];
 
-    let program_text = fs::read_to_string(path)?;
+struct Options {
+    rom: String,
+    /// What the command line sets, over the config file and rom database.
+    settings: Settings,
+    /// Config file instead of the one in the config directory.
+    config: Option<String>,
+    /// Movie file to record the input to.
+    record: Option<String>,
+    /// Movie file to play back, its settings replace the ones given.
+    play: Option<String>,
+    /// Start paused in the terminal debugger.
+    debug: bool,
+    /// Where a binary rom is loaded and starts, 0x200 when not given.
+    load_address: Option<u16>,
+}
+
+impl Options {
+
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut rom = None;
+        let mut settings = Settings::default();
+        let mut config = None;
+        let mut record = None;
+        let mut play = None;
+        let mut debug = false;
+        let mut load_address = None;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--quirks" => {
+                    let name = args.next().ok_or("--quirks needs a profile name")?;
+                    settings.quirks.profile = Some(Quirks::from_name(name)
+                        .ok_or_else(|| format!("Unknown quirks profile '{}'", name))?);
+                },
+                "--platform" => {
+                    let name = args.next().ok_or("--platform needs a platform name")?;
+                    settings.platform = Some(Platform::from_name(name)
+                        .ok_or_else(|| format!("Unknown platform '{}'", name))?);
+                },
+                "--seed" => {
+                    settings.timing.seed = Some(parse_number(args.next(), "--seed")?);
+                },
+                "--layout" => {
+                    let name = args.next().ok_or("--layout needs a layout name")?;
+                    settings.input.layout = Some(Layout::from_name(name)
+                        .ok_or_else(|| format!("Unknown keyboard layout '{}'", name))?);
+                },
+                "--latch" => {
+                    let name = args.next().ok_or("--latch needs a policy name")?;
+                    settings.input.latch = Some(LatchPolicy::from_name(name)
+                        .ok_or_else(|| format!("Unknown latch policy '{}'", name))?);
+                },
+                "--cycles" => {
+                    settings.timing.cycles_per_frame = Some(parse_number(args.next(), "--cycles")?);
+                },
+                "--pitch" => {
+                    settings.audio.pitch = Some(parse_number(args.next(), "--pitch")?);
+                },
+                "--volume" => {
+                    settings.audio.volume = Some(parse_number(args.next(), "--volume")?);
+                },
+                "--mute" => settings.audio.muted = Some(true),
+                "--config" => {
+                    config = Some(args.next().ok_or("--config needs a file name")?.clone());
+                },
+                "--debug" => debug = true,
+                "--record" => {
+                    record = Some(args.next().ok_or("--record needs a file name")?.clone());
+                },
+                "--play" => {
+                    play = Some(args.next().ok_or("--play needs a file name")?.clone());
+                },
+                "--load-address" => {
+                    let arg = args.next().ok_or("--load-address needs an address")?;
+                    let addr = u16::from_str_radix(arg.trim_start_matches("0x"), 16)
+                        .map_err(|_| format!("Invalid address '{}' for --load-address", arg))?;
+                    load_address = Some(addr);
+                },
+                _ if arg.starts_with("--") => return Err(format!("Unknown option '{}'", arg)),
+                _ => rom = Some(arg.clone()),
+            }
+        }
+
+        let rom = rom.ok_or("Specify a input file to read as rom")?;
+
+        if record.is_some() && play.is_some() {
+            return Err("--record and --play can't be used together".to_string());
+        }
+
+        if debug && (record.is_some() || play.is_some()) {
+            return Err("--debug can't be used with a movie".to_string());
+        }
+
+        Ok(Self { rom, settings, config, record, play, debug, load_address })
+    }
+}
+
+
+/// The settings the rom database has for a rom. Its quirks are for its own
+/// platform, so they are left out when `platform` picks another.
+fn known_settings(info: &RomInfo, platform: Option<Platform>) -> Settings {
+    let mut settings = Settings::default();
+    settings.platform = info.platform;
+    if platform.is_none() || platform == info.platform {
+        settings.quirks.profile = info.quirks;
+    }
+    settings.timing.cycles_per_frame = info.cycles_per_frame;
+    if !info.colors.is_empty() {
+        settings.video.colors = Some(info.colors.clone());
+    }
+    settings
+}
+
+
+fn time_seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|t| t.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+
+fn parse_number<T: std::str::FromStr>(arg: Option<&String>, option: &str) -> Result<T, String> {
+    let arg = arg.ok_or_else(|| format!("{} needs a value", option))?;
+    arg.parse().map_err(|_| format!("Invalid value '{}' for {}", arg, option))
+}
+
+
+/// Print the listing of a rom.
+fn disassemble(path: &str) -> io::Result<()> {
+    let program = load_program(path)?;
+    let rom = program.get_binary_data().map_err(|err| invalid_data(path, err))?;
+
+    print!("{}", Disassembly::new(&rom, program.load_address()).listing());
+    Ok(())
+}
+
+
+/// The bytes of a rom in any of the formats.
+fn read_rom(path: &str) -> io::Result<Vec<u8>> {
+    load_program(path)?.get_binary_data().map_err(|err| invalid_data(path, err))
+}
+
+
+fn invalid_data(path: &str, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}", path, err))
+}
+
+
+/// Read a program from a file, or stdin for `-`. `.asm` and `.8o` sources
+/// are compiled, anything else is a `.ch8t` text or binary rom told apart by
+/// what's in it.
+fn load_program(path: &str) -> io::Result<Program> {
+    if path.ends_with(".asm") || path.ends_with(".8o") {
+        return Ok(Program::binary(compile_source(path)?));
+    }
 
+    let data = if path == "-" {
+        let mut data = Vec::new();
+        io::stdin().read_to_end(&mut data)?;
+        data
+    }
+    else {
+        fs::read(path)?
+    };
 
-    Ok(chip::Program::Text(program_text))
+    Ok(Program::detect(data))
 }
 
 
-fn load_program_binary(args: &Vec<String>) -> Result<chip::Program, io::Error> {
-    // if ends with chip8t load as text, otherwise binary
-    let path = &args[1];
-    let mut buffer = [0; 0xDFF];
+/// Assemble a source file, printing every error found.
+fn assemble(path: &str) -> Result<Vec<u8>, io::Error> {
+    assembler::assemble_file(path.as_ref()).map_err(|errors| {
+        for err in errors.iter() {
+            eprintln!("{}", err);
+        }
+        io::Error::new(io::ErrorKind::InvalidData, format!("{} errors assembling {}", errors.len(), path))
+    })
+}
+
 
-    let mut f = File::open(path)?;
+/// Compile an Octo source file, printing the error.
+fn compile_octo(path: &str) -> Result<Vec<u8>, io::Error> {
+    let source = fs::read_to_string(path)?;
+    octo::compile(&source).map_err(|err| {
+        eprintln!("{}:{}", path, err);
+        io::Error::new(io::ErrorKind::InvalidData, format!("error compiling {}", path))
+    })
+}
 
-    f.read(&mut buffer)?;
 
-    Ok(chip::Program::Binary(buffer))
+/// The rom image of an `.asm` or `.8o` source.
+fn compile_source(path: &str) -> Result<Vec<u8>, io::Error> {
+    if path.ends_with(".8o") {
+        compile_octo(path)
+    }
+    else {
+        assemble(path)
+    }
 }
@@ -0,0 +1,389 @@
+//! Input movies: everything needed to replay a run frame for frame.
+//!
+//! A movie holds the settings the machine was started with and, for every
+//! frame, the keys that were held and a hash of the machine at the end of the
+//! frame. Playing it back on the same rom must give the same hashes, the first
+//! frame where it doesn't is reported as a desync.
+//!
+//! Layout, all numbers little endian:
+//!
+//! ```text
+//! "C8MV" magic, u16 version
+//! u32 rom hash, u8 platform, u8 quirks, u64 seed, u32 cycles per frame,
+//! u8 key latch policy
+//! u32 frame count, then per frame u16 keys (bit n is key n) and u32 state hash
+//! ```
+
+use crate::chip::{Chip8, Platform};
+use crate::keyboard::LatchPolicy;
+use crate::quirks::Quirks;
+use crate::savestate::{self, fnv1a, Reader, SaveStateError};
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"C8MV";
+pub const VERSION: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    /// Bit n set when hex key n was held during the frame.
+    pub keys: u16,
+    /// Hash of the save state at the end of the frame.
+    pub state_hash: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    pub rom_hash: u32,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub seed: u64,
+    pub cycles_per_frame: u32,
+    /// How long key presses were seen, taps play back differently without
+    /// the same policy.
+    pub latch: LatchPolicy,
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MovieError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    Invalid(&'static str),
+    /// The movie was recorded with a different rom.
+    WrongRom { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::BadMagic => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(v) => write!(f, "unsupported movie version {}", v),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::Invalid(what) => write!(f, "invalid movie: {}", what),
+            MovieError::WrongRom { expected, actual } =>
+                write!(f, "movie was recorded with rom {:08x}, this rom is {:08x}", expected, actual),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<SaveStateError> for MovieError {
+    fn from(err: SaveStateError) -> Self {
+        match err {
+            SaveStateError::Invalid(what) => MovieError::Invalid(what),
+            _ => MovieError::Truncated,
+        }
+    }
+}
+
+/// The first frame where playback did not match the recording.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Desync {
+    pub frame: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "desync at frame {}: state hash {:08x}, recorded {:08x}", self.frame, self.actual, self.expected)
+    }
+}
+
+impl std::error::Error for Desync {}
+
+
+/// Hash identifying a rom image.
+pub fn rom_hash(rom: &[u8]) -> u32 {
+    fnv1a(rom)
+}
+
+/// Hash of the whole machine, compared frame by frame. The keys count as
+/// they read, a tap recorded between frames plays back as a key held for
+/// the frame.
+pub fn state_hash(chip: &Chip8) -> u32 {
+    let mut chip = chip.clone();
+    let keys = chip.keyboard.held_keys();
+    chip.keyboard.restore_keys(keys, 0, 0);
+    fnv1a(&chip.save_state())
+}
+
+
+impl Movie {
+
+    /// Start recording `chip`, which must not have run any frames yet so its
+    /// random generator is still at its seed.
+    pub fn new(rom: &[u8], chip: &Chip8, cycles_per_frame: u32) -> Self {
+        Self {
+            rom_hash: rom_hash(rom),
+            platform: chip.platform,
+            quirks: chip.quirks,
+            seed: chip.rng.state,
+            cycles_per_frame,
+            latch: chip.keyboard.latch(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Record a frame, call at the end of every frame with the keys that
+    /// were held while it ran.
+    pub fn record(&mut self, chip: &Chip8) {
+        self.frames.push(Frame {
+            keys: chip.keyboard.held_keys(),
+            state_hash: state_hash(chip),
+        });
+    }
+
+    /// A fresh machine set up the way the recording started, `rom` must be
+    /// the image the movie was recorded with.
+    pub fn new_chip(&self, rom: &[u8]) -> Result<Chip8, MovieError> {
+        let actual = rom_hash(rom);
+        if actual != self.rom_hash {
+            return Err(MovieError::WrongRom { expected: self.rom_hash, actual });
+        }
+
+        let mut chip = Chip8::for_platform(self.platform, self.quirks);
+        chip.seed_rng(self.seed);
+        chip.keyboard.set_latch(self.latch);
+        Ok(chip)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + self.frames.len() * 6);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+
+        out.extend_from_slice(&self.rom_hash.to_le_bytes());
+        out.push(savestate::platform_id(self.platform));
+        out.push(savestate::quirks_to_bits(&self.quirks));
+        out.extend_from_slice(&self.seed.to_le_bytes());
+        out.extend_from_slice(&self.cycles_per_frame.to_le_bytes());
+        out.push(match self.latch {
+            LatchPolicy::Off => 0,
+            LatchPolicy::Frame => 1,
+        });
+
+        out.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        for frame in self.frames.iter() {
+            out.extend_from_slice(&frame.keys.to_le_bytes());
+            out.extend_from_slice(&frame.state_hash.to_le_bytes());
+        }
+
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MovieError> {
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+
+        let mut r = Reader { data: &data[MAGIC.len()..] };
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let rom_hash = r.u32()?;
+        let platform = savestate::read_platform(&mut r)?;
+        let quirks = savestate::quirks_from_bits(r.u8()?);
+        let seed = r.u64()?;
+        let cycles_per_frame = r.u32()?;
+        let latch = match r.u8()? {
+            0 => LatchPolicy::Off,
+            1 => LatchPolicy::Frame,
+            _ => return Err(MovieError::Invalid("latch policy")),
+        };
+
+        let count = r.u32()? as usize;
+        if r.data.len() != count * 6 {
+            return Err(if r.data.len() < count * 6 { MovieError::Truncated } else { MovieError::Invalid("trailing data") });
+        }
+
+        let mut frames = Vec::with_capacity(count);
+        for _ in 0..count {
+            let keys = r.u16()?;
+            let state_hash = r.u32()?;
+            frames.push(Frame { keys, state_hash });
+        }
+
+        Ok(Self { rom_hash, platform, quirks, seed, cycles_per_frame, latch, frames })
+    }
+}
+
+
+/// Feeds a movie's input into a machine one frame at a time.
+pub struct Player {
+    movie: Movie,
+    frame: usize,
+}
+
+impl Player {
+
+    pub fn new(movie: Movie) -> Self {
+        Self { movie, frame: 0 }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Frames played so far.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    /// Set the keys for the next frame, call before running it. Returns false
+    /// when the movie has ended.
+    pub fn apply_input(&self, chip: &mut Chip8) -> bool {
+        match self.movie.frames.get(self.frame) {
+            Some(frame) => {
+                for key in 0..16 {
+                    chip.set_key(key, frame.keys & (1 << key) != 0);
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Compare the machine with the recording, call after running the frame.
+    pub fn check(&mut self, chip: &Chip8) -> Result<(), Desync> {
+        let expected = match self.movie.frames.get(self.frame) {
+            Some(frame) => frame.state_hash,
+            None => return Ok(()),
+        };
+
+        let frame = self.frame;
+        self.frame += 1;
+
+        let actual = state_hash(chip);
+        if actual != expected {
+            return Err(Desync { frame, expected, actual });
+        }
+
+        Ok(())
+    }
+
+    /// Play the whole movie without a frontend, stopping at the first desync.
+    pub fn play_to_end(&mut self, chip: &mut Chip8) -> Result<(), Desync> {
+        let cycles = self.movie.cycles_per_frame;
+        while self.apply_input(chip) {
+            // a fault is part of the recording, the hash of the halted machine shows it
+            let _ = chip.run_frame(cycles);
+            self.check(chip)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::Program;
+
+    // wait for a key, V1 = rand, draw digit V0 at V1,V1, loop
+    const ROM: &str = "F00A C13F F029 D115 1200";
+
+    fn record() -> (Vec<u8>, Movie) {
+        let rom = Program::Text(ROM.to_string()).get_binary_data().unwrap();
+
+        let mut chip = Chip8::with_quirks(Quirks::vip());
+        chip.seed_rng(99);
+        chip.load_program(&Program::Text(ROM.to_string())).unwrap();
+
+        let mut movie = Movie::new(&rom, &chip, 4);
+        for frame in 0..30 {
+            chip.set_key(frame % 16, frame % 3 == 0);
+            chip.run_frame(4).unwrap();
+            movie.record(&chip);
+        }
+
+        (rom, movie)
+    }
+
+    #[test]
+    fn plays_back_without_desync() {
+        let (rom, movie) = record();
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+
+        let mut chip = movie.new_chip(&rom).unwrap();
+        chip.load_program(&Program::Text(ROM.to_string())).unwrap();
+
+        let mut player = Player::new(movie);
+        assert_eq!(player.play_to_end(&mut chip), Ok(()));
+        assert!(player.is_finished());
+    }
+
+    #[test]
+    fn taps_between_frames_play_back() {
+        let rom = Program::Text(ROM.to_string()).get_binary_data().unwrap();
+        let mut chip = Chip8::with_quirks(Quirks::vip());
+        chip.load_program(&Program::Text(ROM.to_string())).unwrap();
+
+        // pressed and released before the frame, only the latch sees them
+        let mut movie = Movie::new(&rom, &chip, 4);
+        for frame in 0..30 {
+            if frame % 4 == 0 {
+                chip.set_key(frame % 16, true);
+                chip.set_key(frame % 16, false);
+            }
+            chip.run_frame(4).unwrap();
+            movie.record(&chip);
+        }
+        assert_ne!(chip.registers.get_value(0), 0);
+
+        let mut chip = movie.new_chip(&rom).unwrap();
+        chip.load_program(&Program::Text(ROM.to_string())).unwrap();
+        assert_eq!(Player::new(movie).play_to_end(&mut chip), Ok(()));
+    }
+
+    #[test]
+    fn plays_back_with_the_recorded_latch() {
+        let rom = Program::Text(ROM.to_string()).get_binary_data().unwrap();
+        let mut chip = Chip8::with_quirks(Quirks::vip());
+        chip.keyboard.set_latch(LatchPolicy::Off);
+        chip.load_program(&Program::Text(ROM.to_string())).unwrap();
+
+        let mut movie = Movie::new(&rom, &chip, 4);
+        for frame in 0..30 {
+            chip.set_key(frame % 16, frame % 3 == 0);
+            chip.run_frame(4).unwrap();
+            movie.record(&chip);
+        }
+        assert_ne!(chip.registers.get_value(0), 0);
+
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(movie.latch, LatchPolicy::Off);
+
+        let mut chip = movie.new_chip(&rom).unwrap();
+        assert_eq!(chip.keyboard.latch(), LatchPolicy::Off);
+        chip.load_program(&Program::Text(ROM.to_string())).unwrap();
+        assert_eq!(Player::new(movie).play_to_end(&mut chip), Ok(()));
+    }
+
+    #[test]
+    fn reports_desync() {
+        let (rom, mut movie) = record();
+        movie.frames[10].keys ^= 0xFFFF;
+
+        let mut chip = movie.new_chip(&rom).unwrap();
+        chip.load_program(&Program::Text(ROM.to_string())).unwrap();
+
+        let err = Player::new(movie).play_to_end(&mut chip).unwrap_err();
+        assert_eq!(err.frame, 10);
+    }
+
+    #[test]
+    fn rejects_other_rom() {
+        let (_, movie) = record();
+
+        assert!(matches!(movie.new_chip(&[0x12, 0x00]), Err(MovieError::WrongRom { .. })));
+    }
+}
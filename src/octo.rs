@@ -0,0 +1,1064 @@
+//! Compiler for Octo, the high level CHIP-8 assembly language most homebrew
+//! is written in.
+//!
+//! Like Octo it compiles in a single pass, references to labels defined later
+//! are patched once the label is reached. The output follows Octo's layout:
+//! 0x200 holds a `jump main` unless `: main` is the first thing in the
+//! program, in which case the jump is left out.
+//!
+//! Supported: `: label`, `:alias`, `:const`, `:macro`, `:calc`, `:byte`,
+//! `:pointer`, `:org`, `:call`, `:unpack`, `:next`, `:assert`, number literals
+//! as data (sprites), `loop`/`while`/`again`, `if ... then` and
+//! `if ... begin ... else ... end`, and every instruction including the
+//! SUPER-CHIP and XO-CHIP ones. `:breakpoint` and `:monitor` are accepted and
+//! ignored. The `<`, `>`, `<=` and `>=` comparisons use vf as a temporary.
+
+use crate::instructions::Instruction;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+const ORIGIN: u32 = 0x200;
+const MEMORY_END: u32 = 0x10000;
+
+/// Macro expansions allowed in one program, more is taken as a macro that
+/// expands itself forever.
+const MAX_EXPANSIONS: usize = 100_000;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OctoError {
+    /// 1 based.
+    pub line: usize,
+    /// 1 based.
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for OctoError {}
+
+
+/// Compile Octo source to the rom image loaded at 0x200.
+pub fn compile(source: &str) -> Result<Vec<u8>, OctoError> {
+    let mut compiler = Compiler::new(tokenize(source)?);
+    compiler.run()?;
+    Ok(compiler.image)
+}
+
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    /// A "quoted" string, `text` is without the quotes.
+    string: bool,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: String) -> OctoError {
+        OctoError { line: self.line, column: self.column, message }
+    }
+}
+
+fn tokenize(source: &str) -> Result<VecDeque<Token>, OctoError> {
+    let mut tokens = VecDeque::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let mut chars = line.char_indices().peekable();
+
+        while let Some((start, c)) = chars.next() {
+            let column = start + 1;
+
+            if c == '#' {
+                break;
+            }
+            if c.is_whitespace() {
+                continue;
+            }
+
+            if c == '"' {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => text.push(c),
+                        None => return Err(OctoError { line: i + 1, column, message: "unterminated string".to_string() }),
+                    }
+                }
+                tokens.push_back(Token { text, string: true, line: i + 1, column });
+                continue;
+            }
+
+            let mut text = c.to_string();
+            while let Some((_, c)) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                text.push(*c);
+                chars.next();
+            }
+            tokens.push_back(Token { text, string: false, line: i + 1, column });
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    }
+    else if let Some(bin) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()? as f64
+    }
+    else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse::<f64>().ok()?
+    }
+    else {
+        return None;
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v' | 'V'), Some(digit), None) => digit.to_digit(16).map(|r| r as u8),
+        _ => None,
+    }
+}
+
+
+/// A value patched in once a label defined later is reached.
+#[derive(Clone, Copy, Debug)]
+enum Fixup {
+    /// The 12 bit address of the instruction at the address.
+    Address,
+    /// The 16 bit word at the address, `i := long` and `:pointer`.
+    Long,
+    /// The two `vX := NN` of an `:unpack` with this high nibble.
+    Unpack(u8),
+}
+
+enum Flow {
+    /// `loop`, with the `while` jumps to patch at `again`.
+    Loop { start: u32, exits: Vec<u32> },
+    /// `if ... begin` or `else`, with the jump to patch at `else` or `end`.
+    Branch { jump: u32, has_else: bool },
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    /// Position of the last token, for errors at the end of the file.
+    last: (usize, usize),
+    image: Vec<u8>,
+    used: Vec<bool>,
+    here: u32,
+    constants: HashMap<String, f64>,
+    labels: HashMap<String, u32>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<(u32, Token, Fixup)>,
+    flow: Vec<(Flow, Token)>,
+    /// 0x200 holds a jump to main.
+    jump_to_main: bool,
+    expansions: usize,
+}
+
+impl Compiler {
+
+    fn new(tokens: VecDeque<Token>) -> Self {
+        Self {
+            tokens,
+            last: (1, 1),
+            image: Vec::new(),
+            used: Vec::new(),
+            // the jump to main goes in front
+            here: ORIGIN + 2,
+            constants: HashMap::new(),
+            labels: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            flow: Vec::new(),
+            jump_to_main: true,
+            expansions: 0,
+        }
+    }
+
+    fn run(&mut self) -> Result<(), OctoError> {
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+
+        if let Some((_, token)) = self.flow.last() {
+            return Err(token.error(format!("'{}' is never closed", token.text)));
+        }
+
+        if let Some((_, token, _)) = self.fixups.first() {
+            return Err(token.error(format!("undefined name '{}'", token.text)));
+        }
+
+        if self.jump_to_main {
+            let main = match self.labels.get("main") {
+                Some(main) => *main,
+                None => return Err(OctoError { line: 1, column: 1, message: "the program is missing a 'main' label".to_string() }),
+            };
+            self.write(ORIGIN, &Instruction::Jump(main as u16).encode(), &Token {
+                text: "main".to_string(), string: false, line: 1, column: 1,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Token, OctoError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.last = (token.line, token.column);
+                Ok(token)
+            },
+            None => Err(OctoError { line: self.last.0, column: self.last.1, message: "unexpected end of file".to_string() }),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().filter(|t| !t.string).map(|t| t.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, OctoError> {
+        let token = self.next()?;
+        if token.text != text || token.string {
+            return Err(token.error(format!("expected '{}', got '{}'", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn name(&mut self) -> Result<Token, OctoError> {
+        let token = self.next()?;
+        if token.string || parse_number(&token.text).is_some() || parse_register(&token.text).is_some() {
+            return Err(token.error(format!("'{}' can't be used as a name", token.text)));
+        }
+        Ok(token)
+    }
+
+    fn register(&mut self) -> Result<u8, OctoError> {
+        let token = self.next()?;
+        self.register_of(&token).ok_or_else(|| token.error(format!("expected a register, got '{}'", token.text)))
+    }
+
+    fn register_of(&self, token: &Token) -> Option<u8> {
+        if token.string {
+            return None;
+        }
+        parse_register(&token.text).or_else(|| self.aliases.get(&token.text).copied())
+    }
+
+    fn is_register(&self) -> bool {
+        self.tokens.front().is_some_and(|token| self.register_of(token).is_some())
+    }
+
+    /// The value of a number, constant or label that is already known.
+    fn known_value(&self, token: &Token) -> Option<f64> {
+        if token.string {
+            return None;
+        }
+        parse_number(&token.text)
+            .or_else(|| self.constants.get(&token.text).copied())
+            .or_else(|| self.labels.get(&token.text).map(|addr| *addr as f64))
+    }
+
+    fn value(&mut self, min: i64, max: i64, what: &str) -> Result<i64, OctoError> {
+        let token = self.next()?;
+        let value = self.known_value(&token)
+            .ok_or_else(|| token.error(format!("undefined name '{}'", token.text)))?
+            .floor() as i64;
+
+        if value < min || value > max {
+            return Err(token.error(format!("{} '{}' does not fit in {}", what, token.text, describe_range(max))));
+        }
+        Ok(value)
+    }
+
+    fn byte(&mut self) -> Result<u8, OctoError> {
+        self.value(-128, 255, "value").map(|v| v as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, OctoError> {
+        self.value(0, 15, "value").map(|v| v as u8)
+    }
+
+    /// An address, or 0 and a fixup when it is a label defined later.
+    fn address(&mut self, at: u32, fixup: Fixup, max: i64) -> Result<u16, OctoError> {
+        let token = self.next()?;
+
+        match self.known_value(&token) {
+            Some(value) => {
+                let value = value.floor() as i64;
+                if value < 0 || value > max {
+                    return Err(token.error(format!("address '{}' does not fit in {}", token.text, describe_range(max))));
+                }
+                Ok(value as u16)
+            },
+            None if !token.string && parse_register(&token.text).is_none() => {
+                self.fixups.push((at, token, fixup));
+                Ok(0)
+            },
+            None => Err(token.error(format!("expected an address, got '{}'", token.text))),
+        }
+    }
+
+    fn write(&mut self, addr: u32, bytes: &[u8], token: &Token) -> Result<(), OctoError> {
+        let start = (addr - ORIGIN) as usize;
+        let end = start + bytes.len();
+
+        if addr + bytes.len() as u32 > MEMORY_END {
+            return Err(token.error("the program does not fit in memory".to_string()));
+        }
+        if self.image.len() < end {
+            self.image.resize(end, 0);
+            self.used.resize(end, false);
+        }
+        if self.used[start..end].iter().any(|used| *used) {
+            return Err(token.error(format!("data overlap at {:#05X}", addr)));
+        }
+
+        self.image[start..end].copy_from_slice(bytes);
+        self.used[start..end].iter_mut().for_each(|used| *used = true);
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8], token: &Token) -> Result<(), OctoError> {
+        self.write(self.here, bytes, token)?;
+        self.here += bytes.len() as u32;
+        Ok(())
+    }
+
+    fn instruction(&mut self, instr: Instruction, token: &Token) -> Result<(), OctoError> {
+        self.emit(&instr.encode(), token)
+    }
+
+    /// Patch the byte at `addr`, which was written earlier.
+    fn patch(&mut self, addr: u32, value: u8) {
+        self.image[(addr - ORIGIN) as usize] = value;
+    }
+
+    fn patch_jump(&mut self, at: u32, target: u32) {
+        let [hi, lo] = (target as u16 & 0x0FFF).to_be_bytes();
+        self.patch(at, 0x10 | hi);
+        self.patch(at + 1, lo);
+    }
+
+    fn define_label(&mut self, name: Token, addr: u32) -> Result<(), OctoError> {
+        if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) {
+            return Err(name.error(format!("'{}' is already defined", name.text)));
+        }
+
+        // fill in everything that referred to it before
+        let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.fixups)
+            .into_iter()
+            .partition(|(_, token, _)| token.text == name.text);
+        self.fixups = waiting;
+
+        for (at, token, fixup) in ready {
+            match fixup {
+                Fixup::Address => {
+                    if addr > 0xFFF {
+                        return Err(token.error(format!("'{}' is above 0xFFF, use 'i := long'", token.text)));
+                    }
+                    let index = (at - ORIGIN) as usize;
+                    self.image[index] = (self.image[index] & 0xF0) | (addr >> 8) as u8;
+                    self.patch(at + 1, addr as u8);
+                },
+                Fixup::Long => {
+                    self.patch(at, (addr >> 8) as u8);
+                    self.patch(at + 1, addr as u8);
+                },
+                Fixup::Unpack(nibble) => {
+                    self.patch(at + 1, (nibble << 4) | ((addr >> 8) as u8 & 0xF));
+                    self.patch(at + 3, addr as u8);
+                },
+            }
+        }
+
+        self.labels.insert(name.text, addr);
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), OctoError> {
+        let token = self.next()?;
+
+        if token.string {
+            return Err(token.error("unexpected string".to_string()));
+        }
+
+        if let Some(reg) = self.register_of(&token) {
+            return self.assignment(reg, &token);
+        }
+
+        use Instruction::*;
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+
+                // main right at the start doesn't need the jump to it
+                if name.text == "main" && self.here == ORIGIN + 2 && self.image.is_empty() {
+                    self.here = ORIGIN;
+                    self.jump_to_main = false;
+                }
+                self.define_label(name, self.here)?;
+            },
+            ":alias" => {
+                let name = self.name()?;
+                let reg = self.register()?;
+                self.aliases.insert(name.text, reg);
+            },
+            ":const" => {
+                let name = self.name()?;
+                let value = self.next()?;
+                let value = self.known_value(&value).ok_or_else(|| value.error(format!("undefined name '{}'", value.text)))?;
+                if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) {
+                    return Err(name.error(format!("'{}' is already defined", name.text)));
+                }
+                self.constants.insert(name.text, value);
+            },
+            ":calc" => {
+                let name = self.name()?;
+                if self.labels.contains_key(&name.text) {
+                    return Err(name.error(format!("'{}' is already defined", name.text)));
+                }
+                let value = self.calc_block()?;
+                self.constants.insert(name.text, value);
+            },
+            ":macro" => self.define_macro()?,
+            ":byte" => {
+                let byte = if self.peek() == Some("{") {
+                    let value = self.calc_block()?.floor() as i64;
+                    if !(-128..=255).contains(&value) {
+                        return Err(token.error(format!("value {} does not fit in a byte", value)));
+                    }
+                    value as u8
+                }
+                else {
+                    self.byte()?
+                };
+                self.emit(&[byte], &token)?;
+            },
+            ":pointer" => {
+                let addr = self.address(self.here, Fixup::Long, 0xFFFF)?;
+                self.emit(&addr.to_be_bytes(), &token)?;
+            },
+            ":org" => {
+                let addr = self.value(ORIGIN as i64, MEMORY_END as i64 - 1, "address")?;
+                self.here = addr as u32;
+            },
+            ":call" => {
+                let addr = self.address(self.here, Fixup::Address, 0xFFF)?;
+                self.instruction(Call(addr), &token)?;
+            },
+            ":unpack" => {
+                let nibble = self.nibble()?;
+                let addr = self.address(self.here, Fixup::Unpack(nibble), 0xFFF)?;
+                self.instruction(LoadConst(0, (nibble << 4) | (addr >> 8) as u8), &token)?;
+                self.instruction(LoadConst(1, addr as u8), &token)?;
+            },
+            ":next" => {
+                let name = self.name()?;
+                self.define_label(name, self.here + 1)?;
+            },
+            ":assert" => {
+                let message = match self.tokens.front() {
+                    Some(t) if t.string => Some(self.next()?.text),
+                    _ => None,
+                };
+                if self.calc_block()? == 0.0 {
+                    let message = message.unwrap_or_else(|| "assertion failed".to_string());
+                    return Err(token.error(message));
+                }
+            },
+            ":breakpoint" => {
+                self.name()?;
+            },
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            },
+
+            "return" | ";" => self.instruction(Ret, &token)?,
+            "clear" => self.instruction(Cls, &token)?,
+            "bcd" => {
+                let x = self.register()?;
+                self.instruction(BCD(x), &token)?;
+            },
+            "save" | "load" => {
+                let x = self.register()?;
+                let instr = if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    if token.text == "save" { SaveRange(x, y) } else { LoadRange(x, y) }
+                }
+                else if token.text == "save" { Store(x) } else { Load(x) };
+                self.instruction(instr, &token)?;
+            },
+            "saveflags" => {
+                let x = self.register()?;
+                self.instruction(StoreFlags(x), &token)?;
+            },
+            "loadflags" => {
+                let x = self.register()?;
+                self.instruction(LoadFlags(x), &token)?;
+            },
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.instruction(Draw(x, y, n), &token)?;
+            },
+            "jump" => {
+                let addr = self.address(self.here, Fixup::Address, 0xFFF)?;
+                self.instruction(Jump(addr), &token)?;
+            },
+            "jump0" => {
+                let addr = self.address(self.here, Fixup::Address, 0xFFF)?;
+                self.instruction(JumpOffset(addr), &token)?;
+            },
+            "native" => {
+                // 0NNN machine code call, never executed here but part of the rom
+                let addr = self.address(self.here, Fixup::Address, 0xFFF)?;
+                self.emit(&addr.to_be_bytes(), &token)?;
+            },
+            "hires" => self.instruction(HighRes, &token)?,
+            "lores" => self.instruction(LowRes, &token)?,
+            "exit" => self.instruction(Exit, &token)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.instruction(ScrollDown(n), &token)?;
+            },
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.instruction(ScrollUp(n), &token)?;
+            },
+            "scroll-left" => self.instruction(ScrollLeft, &token)?,
+            "scroll-right" => self.instruction(ScrollRight, &token)?,
+            "audio" => self.instruction(LoadAudio, &token)?,
+            "plane" => {
+                let n = self.value(0, 3, "plane")? as u8;
+                self.instruction(SelectPlanes(n), &token)?;
+            },
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let instr = match token.text.as_str() {
+                    "delay" => SetDelay(x),
+                    "buzzer" => SetSound(x),
+                    _ => SetPitch(x),
+                };
+                self.instruction(instr, &token)?;
+            },
+            "i" => self.i_assignment(&token)?,
+
+            "if" => {
+                let condition = self.condition(&token)?;
+                let then = self.next()?;
+                match then.text.as_str() {
+                    // skip the next statement when the condition is false
+                    "then" => self.instruction(condition.skip_when(false), &token)?,
+                    // skip the jump past the block when the condition is true
+                    "begin" => {
+                        self.instruction(condition.skip_when(true), &token)?;
+                        let jump = self.here;
+                        self.instruction(Jump(0), &token)?;
+                        self.flow.push((Flow::Branch { jump, has_else: false }, token));
+                    },
+                    _ => return Err(then.error(format!("expected 'then' or 'begin', got '{}'", then.text))),
+                }
+            },
+            "else" => {
+                let (jump, opened) = match self.flow.pop() {
+                    Some((Flow::Branch { jump, has_else: false }, opened)) => (jump, opened),
+                    _ => return Err(token.error("'else' without 'if ... begin'".to_string())),
+                };
+                let end_jump = self.here;
+                self.instruction(Jump(0), &token)?;
+                self.patch_jump(jump, self.here);
+                self.flow.push((Flow::Branch { jump: end_jump, has_else: true }, opened));
+            },
+            "end" => {
+                match self.flow.pop() {
+                    Some((Flow::Branch { jump, .. }, _)) => self.patch_jump(jump, self.here),
+                    _ => return Err(token.error("'end' without 'if ... begin'".to_string())),
+                }
+            },
+            "loop" => self.flow.push((Flow::Loop { start: self.here, exits: Vec::new() }, token)),
+            "while" => {
+                let condition = self.condition(&token)?;
+                // leave the loop unless the condition is true
+                self.instruction(condition.skip_when(true), &token)?;
+                let exit = self.here;
+                self.instruction(Jump(0), &token)?;
+
+                match self.flow.iter_mut().rev().find(|(flow, _)| matches!(flow, Flow::Loop { .. })) {
+                    Some((Flow::Loop { exits, .. }, _)) => exits.push(exit),
+                    _ => return Err(token.error("'while' outside a loop".to_string())),
+                }
+            },
+            "again" => {
+                let (start, exits) = match self.flow.pop() {
+                    Some((Flow::Loop { start, exits }, _)) => (start, exits),
+                    _ => return Err(token.error("'again' without 'loop'".to_string())),
+                };
+                self.instruction(Jump(start as u16), &token)?;
+                for exit in exits {
+                    self.patch_jump(exit, self.here);
+                }
+            },
+
+            text => {
+                if self.macros.contains_key(text) {
+                    return self.expand_macro(&token);
+                }
+
+                if let Some(value) = parse_number(text) {
+                    let value = value.floor() as i64;
+                    if !(-128..=255).contains(&value) {
+                        return Err(token.error(format!("value '{}' does not fit in a byte", text)));
+                    }
+                    return self.emit(&[value as u8], &token);
+                }
+
+                if text.starts_with(':') {
+                    return Err(token.error(format!("unknown directive '{}'", text)));
+                }
+
+                // any other name calls the subroutine
+                self.tokens.push_front(token.clone());
+                let addr = self.address(self.here, Fixup::Address, 0xFFF)?;
+                self.instruction(Call(addr), &token)?;
+            },
+        }
+
+        Ok(())
+    }
+
+    fn assignment(&mut self, x: u8, token: &Token) -> Result<(), OctoError> {
+        use Instruction::*;
+
+        let op = self.next()?;
+        let instr = match op.text.as_str() {
+            ":=" => match self.peek() {
+                Some("key") => { self.next()?; WaitKeyPress(x) },
+                Some("delay") => { self.next()?; LoadDelay(x) },
+                Some("random") => { self.next()?; Rand(x, self.byte()?) },
+                _ if self.is_register() => LoadReg(x, self.register()?),
+                _ => LoadConst(x, self.byte()?),
+            },
+            "+=" if self.is_register() => Add(x, self.register()?),
+            "+=" => AddConst(x, self.byte()?),
+            "-=" if self.is_register() => Sub(x, self.register()?),
+            "-=" => AddConst(x, self.byte()?.wrapping_neg()),
+            "=-" => SubN(x, self.register()?),
+            "|=" => Or(x, self.register()?),
+            "&=" => And(x, self.register()?),
+            "^=" => Xor(x, self.register()?),
+            ">>=" => ShiftRight(x, self.register()?),
+            "<<=" => ShiftLeft(x, self.register()?),
+            _ => return Err(op.error(format!("unknown operator '{}'", op.text))),
+        };
+
+        self.instruction(instr, token)
+    }
+
+    fn i_assignment(&mut self, token: &Token) -> Result<(), OctoError> {
+        use Instruction::*;
+
+        let op = self.next()?;
+        match op.text.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.instruction(SetSpriteAddr(x), token)
+                },
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.instruction(SetBigSpriteAddr(x), token)
+                },
+                Some("long") => {
+                    self.next()?;
+                    let addr = self.address(self.here + 2, Fixup::Long, 0xFFFF)?;
+                    self.instruction(LoadLongAddr(addr), token)
+                },
+                _ => {
+                    let addr = self.address(self.here, Fixup::Address, 0xFFF)?;
+                    self.instruction(LoadAddr(addr), token)
+                },
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.instruction(AddAddr(x), token)
+            },
+            _ => Err(op.error(format!("unknown operator '{}' for i", op.text))),
+        }
+    }
+
+    /// Parse a condition, emitting the instructions ordering comparisons
+    /// need before their skip.
+    fn condition(&mut self, token: &Token) -> Result<Condition, OctoError> {
+        let x = self.register()?;
+        let op = self.next()?;
+
+        if op.text == "key" || op.text == "-key" {
+            return Ok(Condition { x, op: op.text, rhs: Rhs::None });
+        }
+
+        let rhs = if self.is_register() { Rhs::Register(self.register()?) } else { Rhs::Byte(self.byte()?) };
+
+        if !matches!(op.text.as_str(), "==" | "!=" | "<" | ">" | "<=" | ">=") {
+            return Err(op.error(format!("unknown comparison '{}'", op.text)));
+        }
+
+        let condition = Condition { x, op: op.text, rhs };
+        if let Some((flag, _)) = condition.flag() {
+            for instr in flag.iter() {
+                self.instruction(*instr, token)?;
+            }
+        }
+        Ok(condition)
+    }
+
+    fn define_macro(&mut self) -> Result<(), OctoError> {
+        let name = self.name()?;
+
+        let mut args = Vec::new();
+        while self.peek() != Some("{") {
+            args.push(self.name()?.text);
+        }
+        self.expect("{")?;
+
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.next()?;
+            if !token.string {
+                match token.text.as_str() {
+                    "{" => depth += 1,
+                    "}" => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    },
+                    _ => {},
+                }
+            }
+            body.push(token);
+        }
+
+        self.macros.insert(name.text, Macro { args, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, call: &Token) -> Result<(), OctoError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(call.error(format!("macro '{}' expands forever", call.text)));
+        }
+
+        let count = self.macros[&call.text].args.len();
+        let mut values = Vec::with_capacity(count);
+        for _ in 0..count {
+            values.push(self.next()?);
+        }
+
+        let m = &self.macros[&call.text];
+        let expanded: Vec<Token> = m.body.iter()
+            .map(|token| match m.args.iter().position(|arg| !token.string && *arg == token.text) {
+                Some(i) => values[i].clone(),
+                None => token.clone(),
+            })
+            .collect();
+
+        for token in expanded.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    /// `{ expression }` of `:calc`, evaluated right to left without
+    /// precedence as in Octo.
+    fn calc_block(&mut self) -> Result<f64, OctoError> {
+        self.expect("{")?;
+        let value = self.calc_expr()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    fn calc_expr(&mut self) -> Result<f64, OctoError> {
+        let lhs = self.calc_term()?;
+
+        let op = match self.peek() {
+            Some(op @ ("+" | "-" | "*" | "/" | "%" | "&" | "|" | "^" | "<<" | ">>" | "pow" | "min" | "max"
+                | "<" | ">" | "<=" | ">=" | "==" | "!=")) => op.to_string(),
+            _ => return Ok(lhs),
+        };
+        self.next()?;
+        let rhs = self.calc_expr()?;
+
+        let int = |v: f64| v as i64;
+        let flag = |b: bool| if b { 1.0 } else { 0.0 };
+        Ok(match op.as_str() {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => (int(lhs) & int(rhs)) as f64,
+            "|" => (int(lhs) | int(rhs)) as f64,
+            "^" => (int(lhs) ^ int(rhs)) as f64,
+            "<<" => int(lhs).wrapping_shl(int(rhs) as u32) as f64,
+            ">>" => int(lhs).wrapping_shr(int(rhs) as u32) as f64,
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "<" => flag(lhs < rhs),
+            ">" => flag(lhs > rhs),
+            "<=" => flag(lhs <= rhs),
+            ">=" => flag(lhs >= rhs),
+            "==" => flag(lhs == rhs),
+            _ => flag(lhs != rhs),
+        })
+    }
+
+    fn calc_term(&mut self) -> Result<f64, OctoError> {
+        let token = self.next()?;
+
+        let unary: Option<fn(f64) -> f64> = match token.text.as_str() {
+            "-" => Some(|v| -v),
+            "~" => Some(|v| !(v as i64) as f64),
+            "!" => Some(|v| if v == 0.0 { 1.0 } else { 0.0 }),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(|v| if v == 0.0 { 0.0 } else { v.signum() }),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.calc_term()?));
+        }
+
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expr()?;
+                self.expect(")")?;
+                Ok(value)
+            },
+            "@" => {
+                let addr = self.calc_term()? as i64;
+                let index = addr - ORIGIN as i64;
+                Ok(if index >= 0 { self.image.get(index as usize).copied().unwrap_or(0) as f64 } else { 0.0 })
+            },
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ => self.known_value(&token).ok_or_else(|| token.error(format!("undefined name '{}'", token.text))),
+        }
+    }
+}
+
+
+fn describe_range(max: i64) -> &'static str {
+    match max {
+        3 => "2 bits",
+        15 => "4 bits",
+        255 => "8 bits",
+        0xFFF => "12 bits",
+        _ => "16 bits",
+    }
+}
+
+
+enum Rhs {
+    None,
+    Register(u8),
+    Byte(u8),
+}
+
+struct Condition {
+    x: u8,
+    op: String,
+    rhs: Rhs,
+}
+
+impl Condition {
+
+    /// For `<`, `>`, `<=` and `>=`, the `vf :=` and subtraction leaving the
+    /// borrow flag in vf, with the value vf has when the comparison holds.
+    fn flag(&self) -> Option<([Instruction; 2], u8)> {
+        use Instruction::*;
+
+        let x = self.x;
+        // vf := x; vf -= rhs sets vf to x >= rhs, vf := rhs; vf -= x to rhs >= x
+        let x_ge = match self.rhs {
+            Rhs::Register(y) => [LoadReg(0xF, x), Sub(0xF, y)],
+            Rhs::Byte(n) => [LoadConst(0xF, n), SubN(0xF, x)],
+            Rhs::None => return None,
+        };
+        let rhs_ge = match self.rhs {
+            Rhs::Register(y) => [LoadReg(0xF, y), Sub(0xF, x)],
+            Rhs::Byte(n) => [LoadConst(0xF, n), Sub(0xF, x)],
+            Rhs::None => return None,
+        };
+
+        match self.op.as_str() {
+            ">=" => Some((x_ge, 1)),
+            "<" => Some((x_ge, 0)),
+            "<=" => Some((rhs_ge, 1)),
+            ">" => Some((rhs_ge, 0)),
+            _ => None,
+        }
+    }
+
+    /// The skip instruction that skips when the condition is `when`, after
+    /// the `flag` instructions for ordering comparisons.
+    fn skip_when(&self, when: bool) -> Instruction {
+        use Instruction::*;
+
+        if let Some((_, holds)) = self.flag() {
+            return SkipEqConst(0xF, if when { holds } else { 1 - holds });
+        }
+
+        let x = self.x;
+        match (self.op.as_str(), &self.rhs, when) {
+            ("key", _, true) | ("-key", _, false) => SkipOnKeyPressed(x),
+            ("key", _, false) | ("-key", _, true) => SkipKeyNotPressed(x),
+            ("==", Rhs::Register(y), true) | ("!=", Rhs::Register(y), false) => SkipEqReg(x, *y),
+            ("==", Rhs::Register(y), false) | ("!=", Rhs::Register(y), true) => SkipNotEqReg(x, *y),
+            ("==", Rhs::Byte(n), true) | ("!=", Rhs::Byte(n), false) => SkipEqConst(x, *n),
+            (_, Rhs::Byte(n), _) => SkipNotEqConst(x, *n),
+            _ => unreachable!("conditions are checked when parsed"),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(source: &str) -> String {
+        compile(source).unwrap().iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ")
+    }
+
+    fn error(source: &str) -> String {
+        compile(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn main_first_needs_no_jump() {
+        assert_eq!(hex(": main v0 := 5 loop again"), "60 05 12 02");
+    }
+
+    #[test]
+    fn jump_to_main_when_not_first() {
+        assert_eq!(hex(": draw sprite v0 v1 5 ; : main draw"), "12 06 D0 15 00 EE 22 02");
+    }
+
+    #[test]
+    fn forward_references_are_patched() {
+        assert_eq!(hex(": main i := smile jump main : smile 0b00111100 0x42"), "A2 04 12 00 3C 42");
+    }
+
+    #[test]
+    fn aliases_constants_and_calc() {
+        let source = "
+            :alias x v3
+            :const SPEED 2
+            :calc DOUBLE { SPEED * 2 + 1 }
+            : main
+                x := DOUBLE
+                x += SPEED
+                x -= 1
+        ";
+        // right to left: 2 * (2 + 1)
+        assert_eq!(hex(source), "63 06 73 02 73 FF");
+    }
+
+    #[test]
+    fn calc_shifts_wrap_the_shift() {
+        assert_eq!(hex(":calc X { 1 << 64 } : main v0 := X :byte { 1 << 70 } :byte { 256 >> -1 }"), "60 01 40 00");
+    }
+
+    #[test]
+    fn macros_expand_with_arguments() {
+        let source = "
+            :macro twice reg { reg += 1 reg += 1 }
+            : main twice v2 twice v5
+        ";
+        assert_eq!(hex(source), "72 01 72 01 75 01 75 01");
+    }
+
+    #[test]
+    fn if_then_skips_on_the_opposite() {
+        assert_eq!(hex(": main if v0 == 3 then v1 := 1 if v0 key then clear"), "40 03 61 01 E0 A1 00 E0");
+    }
+
+    #[test]
+    fn if_begin_else_end() {
+        let source = ": main if v0 != v1 begin v2 := 1 else v2 := 2 end exit";
+        // 200 skip if v0 != v1, 202 jump else, 204 then, 206 jump end, 208 else, 20A end
+        assert_eq!(hex(source), "90 10 12 08 62 01 12 0A 62 02 00 FD");
+    }
+
+    #[test]
+    fn loop_while_again() {
+        let source = ": main loop v0 += 1 while v0 != 10 again exit";
+        // 200 add, 202 skip unless it should stay, 204 jump out, 206 jump back
+        assert_eq!(hex(source), "70 01 40 0A 12 08 12 00 00 FD");
+    }
+
+    #[test]
+    fn ordering_comparisons_use_vf() {
+        // vf := v0; vf -= v1 leaves v0 >= v1 in vf, v0 < v1 holds when it is 0
+        assert_eq!(hex(": main if v0 < v1 then exit"), "8F 00 8F 15 3F 01 00 FD");
+        // vf := 5; vf -= v0 leaves 5 >= v0, v0 <= 5 holds when it is 1
+        assert_eq!(hex(": main if v0 <= 5 begin exit end"), "6F 05 8F 05 3F 01 12 0A 00 FD");
+    }
+
+    #[test]
+    fn long_addresses_and_unpack() {
+        let source = ": main i := long data :unpack 0xA data :org 0x1000 : data 1";
+        assert_eq!(&compile(source).unwrap()[..8], [0xF0, 0x00, 0x10, 0x00, 0x60, 0xA0, 0x61, 0x00]);
+    }
+
+    #[test]
+    fn errors_have_line_and_column() {
+        assert_eq!(error(": main\n  v0 := 300"), "2:9: value '300' does not fit in 8 bits");
+        assert_eq!(error(": main\n  jump nowhere"), "2:8: undefined name 'nowhere'");
+        assert_eq!(error("v0 := 1"), "1:1: the program is missing a 'main' label");
+        assert_eq!(error(": main loop"), "1:8: 'loop' is never closed");
+    }
+}
@@ -0,0 +1,88 @@
+/// Behaviour that differs between CHIP-8 interpreters. Each flag picks the
+/// interpretation a ROM was written against.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    /// 8XY6/8XYE shift VY into VX, instead of shifting VX in place.
+    pub shift_uses_vy: bool,
+    /// FX55/FX65 leave I pointing past the last register stored or loaded.
+    pub load_store_increments_i: bool,
+    /// BNNN jumps to XNN + VX, instead of NNN + V0.
+    pub jump_uses_vx: bool,
+    /// 8XY1/8XY2/8XY3 reset VF to 0.
+    pub logic_resets_vf: bool,
+    /// Sprites are cut off at the edges of the screen, instead of wrapping around.
+    pub clip_sprites: bool,
+    /// DXYN stalls the cpu until the next 60 Hz frame after drawing.
+    pub draw_waits_for_vblank: bool,
+    /// FX0A takes a key already held when it runs, instead of waiting for a
+    /// newly pressed key to be released.
+    pub wait_key_on_press: bool,
+}
+
+impl Quirks {
+
+    /// The original COSMAC VIP interpreter.
+    pub fn vip() -> Self {
+        Self {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            clip_sprites: true,
+            draw_waits_for_vblank: true,
+            wait_key_on_press: false,
+        }
+    }
+
+    /// CHIP-48 and SUPER-CHIP on the HP48 calculators.
+    pub fn schip() -> Self {
+        Self {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            draw_waits_for_vblank: false,
+            wait_key_on_press: false,
+        }
+    }
+
+    /// XO-CHIP as implemented by Octo.
+    pub fn xo_chip() -> Self {
+        Self {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+            draw_waits_for_vblank: false,
+            wait_key_on_press: false,
+        }
+    }
+
+    /// Look up a profile by the name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(Self::default()),
+            "vip" | "chip8" => Some(Self::vip()),
+            "schip" | "chip48" => Some(Self::schip()),
+            "xochip" | "octo" => Some(Self::xo_chip()),
+            _ => None,
+        }
+    }
+}
+
+/// What most modern interpreters do, which is what most roms found online expect.
+impl Default for Quirks {
+    fn default() -> Self {
+        Self {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+            draw_waits_for_vblank: false,
+            wait_key_on_press: false,
+        }
+    }
+}
@@ -0,0 +1,53 @@
+/// Seedable random number generator owned by `Chip8`, a splitmix64. All of
+/// its state is `state`, so two generators with the same value produce the
+/// same numbers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rng {
+    pub state: u64,
+}
+
+impl Rng {
+
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// The next random byte, for CXNN.
+    pub fn next_byte(&mut self) -> u8 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        (mix(self.state) >> 56) as u8
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+/// The splitmix64 output function.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_numbers() {
+        let mut a = Rng::new(1234);
+        let mut b = Rng::new(1234);
+
+        let a: Vec<u8> = (0..32).map(|_| a.next_byte()).collect();
+        let b: Vec<u8> = (0..32).map(|_| b.next_byte()).collect();
+        assert_eq!(a, b);
+
+        let mut c = Rng::new(1235);
+        let c: Vec<u8> = (0..32).map(|_| c.next_byte()).collect();
+        assert_ne!(a, c);
+    }
+}
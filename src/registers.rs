@@ -1,6 +1,6 @@
 #[derive(Clone)]
 pub struct Registers {
-    i: u16, // only lower 12 bit used, since we index into 4096 bytes;
+    i: u16, // only lower 12 bit used on 4096 byte machines, all 16 on XO-CHIP
 
     delay: u8,
     sound: u8,
@@ -26,9 +26,10 @@ impl Registers {
         self.i = addr;
     }
 
+    /// Add `val` to I, wrapping around with `mask` (see `Chip8::address_mask`).
     #[inline]
-    pub fn increment_i(&mut self, val: u16) {
-        self.i = (self.i + val) & 0xFFF;
+    pub fn increment_i(&mut self, val: u16, mask: u16) {
+        self.i = self.i.wrapping_add(val) & mask;
     }
 
     #[inline]
@@ -47,6 +48,11 @@ impl Registers {
         self.registers[reg as usize] = val;
     }
 
+    #[inline]
+    pub fn get_sound(&self) -> u8 {
+        self.sound
+    }
+
     #[inline]
     pub fn get_delay(&self) -> u8 {
         self.delay
@@ -83,3 +89,9 @@ impl Registers {
         self.registers[r_x] = f(self.registers[r_x], self.registers[r_y]);
     }
 }
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,218 @@
+//! Frame by frame history for rewinding.
+//!
+//! Only the newest snapshot is kept whole, older frames are stored as the
+//! difference to the frame after them: the two save states xor'ed together
+//! and run length encoded. Most of memory and the display stay the same from
+//! one frame to the next, so a frame usually costs a few bytes.
+
+use crate::chip::Chip8;
+use std::collections::VecDeque;
+
+pub struct Rewind {
+    /// Save state of the newest frame.
+    newest: Option<Vec<u8>>,
+    /// Oldest first, each one turns the frame after it into its own frame.
+    deltas: VecDeque<Vec<u8>>,
+    max_frames: usize,
+    max_bytes: usize,
+    bytes: usize,
+}
+
+impl Rewind {
+
+    /// Keep at most `max_frames` frames using at most about `max_bytes` for the
+    /// older ones, the oldest frames are dropped first.
+    pub fn new(max_frames: usize, max_bytes: usize) -> Self {
+        Self {
+            newest: None,
+            deltas: VecDeque::new(),
+            max_frames,
+            max_bytes,
+            bytes: 0,
+        }
+    }
+
+    /// Record the state at the end of a frame.
+    pub fn push(&mut self, chip: &Chip8) {
+        let state = chip.save_state();
+
+        if let Some(newest) = self.newest.take() {
+            if newest.len() == state.len() {
+                let delta = encode_delta(&state, &newest);
+                self.bytes += delta.len();
+                self.deltas.push_back(delta);
+            }
+            else {
+                // a different machine was loaded, the old frames can't be restored onto it
+                self.deltas.clear();
+                self.bytes = 0;
+            }
+        }
+        self.newest = Some(state);
+
+        while self.deltas.len() > self.max_frames || self.bytes > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(delta) => self.bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Go back one frame, returns the machine as it was at the end of that
+    /// frame or None when there is no older frame.
+    pub fn rewind(&mut self) -> Option<Chip8> {
+        let delta = self.deltas.pop_back()?;
+        self.bytes -= delta.len();
+
+        let newest = self.newest.as_mut()?;
+        apply_delta(newest, &delta);
+
+        Chip8::load_state(newest).ok()
+    }
+
+    /// Number of frames that can be rewound.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.bytes = 0;
+    }
+}
+
+
+/// Xor of `a` and `b`, encoded as pairs of a zero run length and a literal
+/// run, each length a LEB128 number and the literal bytes following it.
+fn encode_delta(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < a.len() {
+        let zeros_start = i;
+        while i < a.len() && a[i] == b[i] {
+            i += 1;
+        }
+
+        let literal_start = i;
+        while i < a.len() && a[i] != b[i] {
+            i += 1;
+        }
+
+        write_len(&mut out, literal_start - zeros_start);
+        write_len(&mut out, i - literal_start);
+        out.extend((literal_start..i).map(|j| a[j] ^ b[j]));
+    }
+
+    out
+}
+
+
+/// Xor `delta` made by `encode_delta` into `state`.
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut pos = 0;
+    let mut i = 0;
+
+    while i < delta.len() {
+        pos += read_len(delta, &mut i);
+        let literal = read_len(delta, &mut i);
+
+        for byte in &delta[i..i + literal] {
+            state[pos] ^= byte;
+            pos += 1;
+        }
+        i += literal;
+    }
+}
+
+
+fn write_len(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 0x80 {
+        out.push((len as u8 & 0x7F) | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+}
+
+
+fn read_len(data: &[u8], i: &mut usize) -> usize {
+    let mut len = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*i];
+        *i += 1;
+        len |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return len;
+        }
+        shift += 7;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::Program;
+
+    fn chip() -> Chip8 {
+        let mut chip = Chip8::new();
+        // V0 += 1, draw the font sprite for 0 at V0,V0, loop
+        chip.load_program(&Program::Text("7001 D005 1200".to_string())).unwrap();
+        chip
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let a: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        let mut b = a.clone();
+        b[0] = 99;
+        b[500] = 1;
+        b[501] = 2;
+        b[999] = 3;
+
+        let delta = encode_delta(&a, &b);
+        assert!(delta.len() < 20);
+
+        apply_delta(&mut b, &delta);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn rewinds_frame_by_frame() {
+        let mut chip = chip();
+        let mut rewind = Rewind::new(100, 1 << 20);
+        let mut states = Vec::new();
+
+        for _ in 0..10 {
+            chip.run_frame(3).unwrap();
+            rewind.push(&chip);
+            states.push(chip.save_state());
+        }
+
+        assert_eq!(rewind.len(), 9);
+        for expected in states.iter().rev().skip(1) {
+            assert_eq!(&rewind.rewind().unwrap().save_state(), expected);
+        }
+        assert!(rewind.rewind().is_none());
+    }
+
+    #[test]
+    fn drops_oldest_frames() {
+        let mut chip = chip();
+        let mut rewind = Rewind::new(4, 1 << 20);
+
+        for _ in 0..10 {
+            chip.run_frame(3).unwrap();
+            rewind.push(&chip);
+        }
+
+        assert_eq!(rewind.len(), 4);
+        assert_eq!(rewind.rewind().unwrap().registers.get_value(0), 9);
+    }
+}
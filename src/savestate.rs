@@ -0,0 +1,447 @@
+//! Save states: the whole machine written to a versioned binary blob.
+//!
+//! Layout, all numbers little endian:
+//!
+//! ```text
+//! "C8SS" magic, u16 version
+//! payload (see `write_payload`)
+//! u32 FNV-1a checksum of everything before it
+//! ```
+
+use crate::chip::{Chip8, Platform};
+use crate::cpu::Fault;
+use crate::display::{Resolution, MAX_HEIGHT, MAX_WIDTH, PLANES};
+use crate::instructions::DecodeError;
+use crate::keyboard::KeyWait;
+use crate::quirks::Quirks;
+use crate::random::Rng;
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"C8SS";
+pub const VERSION: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SaveStateError {
+    /// Not a save state at all.
+    BadMagic,
+    /// Written by a newer version of the emulator.
+    UnsupportedVersion(u16),
+    /// The data ends before the state does.
+    Truncated,
+    /// The checksum does not match, the file was damaged.
+    ChecksumMismatch,
+    /// A value that can not be part of a valid machine.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::BadMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(v) => write!(f, "unsupported save state version {}", v),
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::ChecksumMismatch => write!(f, "save state checksum mismatch"),
+            SaveStateError::Invalid(what) => write!(f, "invalid save state: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+
+impl Chip8 {
+
+    /// Serialize the whole machine, restore it with `load_state`.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.memory.len() + MAX_WIDTH * MAX_HEIGHT + 256);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+
+        write_payload(self, &mut out);
+
+        let checksum = fnv1a(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// Restore a machine written by `save_state`, checking that every value
+    /// is one the machine could actually be in.
+    pub fn load_state(data: &[u8]) -> Result<Chip8, SaveStateError> {
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return Err(SaveStateError::BadMagic);
+        }
+
+        if data.len() < MAGIC.len() + 2 + 4 {
+            return Err(SaveStateError::Truncated);
+        }
+
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+
+        let (body, checksum) = data.split_at(data.len() - 4);
+        if fnv1a(body) != u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]) {
+            return Err(SaveStateError::ChecksumMismatch);
+        }
+
+        let mut reader = Reader { data: &body[MAGIC.len() + 2..] };
+        let chip = read_payload(&mut reader)?;
+
+        if !reader.data.is_empty() {
+            return Err(SaveStateError::Invalid("trailing data"));
+        }
+
+        Ok(chip)
+    }
+}
+
+
+fn write_payload(chip: &Chip8, out: &mut Vec<u8>) {
+    out.push(platform_id(chip.platform));
+    out.push(quirks_to_bits(&chip.quirks));
+    out.extend_from_slice(&chip.rng.state.to_le_bytes());
+
+    out.extend_from_slice(&chip.memory);
+
+    for addr in chip.stack.iter() {
+        out.extend_from_slice(&addr.to_le_bytes());
+    }
+    out.push(chip.sp);
+    out.extend_from_slice(&chip.pc.to_le_bytes());
+
+    for reg in 0..16 {
+        out.push(chip.registers.get_value(reg));
+    }
+    out.extend_from_slice(&chip.registers.get_i().to_le_bytes());
+    out.push(chip.registers.get_delay());
+    out.push(chip.registers.get_sound());
+
+    out.push(match chip.display.resolution {
+        Resolution::Low => 0,
+        Resolution::High => 1,
+    });
+    out.push(chip.display.selected_planes);
+    out.extend_from_slice(&chip.display.pixels);
+
+    // the keys down, then the ones pressed since the frame started and the
+    // ones latched for it
+    out.extend_from_slice(&chip.keyboard.keys_down().to_le_bytes());
+    out.extend_from_slice(&chip.keyboard.pressed_since_frame().to_le_bytes());
+    out.extend_from_slice(&chip.keyboard.latched_keys().to_le_bytes());
+
+    // the keys pressed and the one released during an FX0A, 0xFF for none
+    let wait = chip.keyboard.key_wait();
+    out.push(wait.is_some() as u8);
+    let wait = wait.unwrap_or_default();
+    out.extend_from_slice(&wait.pressed.to_le_bytes());
+    out.push(wait.released.unwrap_or(0xFF));
+
+    out.push(to_bits(&[chip.waiting_for_vblank, chip.exited, chip.audio_pattern.is_some()]) as u8);
+    write_fault(chip.fault, out);
+
+    out.extend_from_slice(&chip.rpl_flags);
+    out.extend_from_slice(&chip.audio_pattern.unwrap_or_default());
+    out.push(chip.audio_pitch);
+}
+
+
+fn read_payload(r: &mut Reader) -> Result<Chip8, SaveStateError> {
+    let platform = read_platform(r)?;
+    let quirks = quirks_from_bits(r.u8()?);
+
+    let mut chip = Chip8::for_platform(platform, quirks);
+
+    chip.rng = Rng::new(r.u64()?);
+
+    let memory_size = chip.memory.len();
+    chip.memory.copy_from_slice(r.bytes(memory_size)?);
+
+    for i in 0..chip.stack.len() {
+        chip.stack[i] = r.u16()?;
+    }
+    chip.sp = r.u8()?;
+    if chip.sp as usize > chip.stack.len() {
+        return Err(SaveStateError::Invalid("stack pointer"));
+    }
+    chip.pc = r.u16()?;
+    if chip.pc as usize >= memory_size {
+        return Err(SaveStateError::Invalid("program counter"));
+    }
+
+    for reg in 0..16 {
+        let val = r.u8()?;
+        chip.registers.set_value(reg, val);
+    }
+    let i = r.u16()?;
+    if i as usize >= memory_size {
+        return Err(SaveStateError::Invalid("I register"));
+    }
+    chip.registers.set_i(i);
+    let delay = r.u8()?;
+    chip.registers.set_delay(delay);
+    let sound = r.u8()?;
+    chip.registers.set_sound(sound);
+
+    chip.display.resolution = match r.u8()? {
+        0 => Resolution::Low,
+        1 => Resolution::High,
+        _ => return Err(SaveStateError::Invalid("resolution")),
+    };
+    chip.display.selected_planes = r.u8()?;
+    if chip.display.selected_planes >= 1 << PLANES {
+        return Err(SaveStateError::Invalid("selected planes"));
+    }
+    chip.display.pixels.copy_from_slice(r.bytes(MAX_WIDTH * MAX_HEIGHT)?);
+    if chip.display.pixels.iter().any(|p| *p >= 1 << PLANES) {
+        return Err(SaveStateError::Invalid("pixel"));
+    }
+
+    let keys = r.u16()?;
+    let pressed_since_frame = r.u16()?;
+    let latched = r.u16()?;
+    chip.keyboard.restore_keys(keys, pressed_since_frame, latched);
+    chip.keyboard.set_key_wait(read_key_wait(r)?);
+
+    let flags = r.u8()?;
+    chip.waiting_for_vblank = flags & 1 != 0;
+    chip.exited = flags & 2 != 0;
+    chip.fault = read_fault(r)?;
+
+    chip.rpl_flags.copy_from_slice(r.bytes(16)?);
+    // the pattern counts once F002 loaded one
+    let mut pattern = [0; 16];
+    pattern.copy_from_slice(r.bytes(16)?);
+    chip.audio_pattern = if flags & 4 != 0 { Some(pattern) } else { None };
+    chip.audio_pitch = r.u8()?;
+
+    Ok(chip)
+}
+
+
+fn read_key_wait(r: &mut Reader) -> Result<Option<KeyWait>, SaveStateError> {
+    let waiting = r.u8()?;
+    let pressed = r.u16()?;
+    let released = match r.u8()? {
+        0xFF => None,
+        key if key < 16 => Some(key),
+        _ => return Err(SaveStateError::Invalid("released key")),
+    };
+
+    match waiting {
+        0 => Ok(None),
+        1 => Ok(Some(KeyWait { pressed, released })),
+        _ => Err(SaveStateError::Invalid("key wait")),
+    }
+}
+
+
+fn write_fault(fault: Option<Fault>, out: &mut Vec<u8>) {
+    // tag, pc, then the memory address or opcode
+    let (tag, pc, extra) = match fault {
+        None => (0, 0, 0),
+        Some(Fault::StackOverflow { pc }) => (1, pc, 0),
+        Some(Fault::StackUnderflow { pc }) => (2, pc, 0),
+        Some(Fault::MemoryOutOfBounds { pc, addr }) => (3, pc, addr as u32),
+        Some(Fault::PcOutOfBounds { pc }) => (4, pc, 0),
+        Some(Fault::InvalidOpcode(err)) => (5, err.addr, err.opcode as u32),
+    };
+
+    out.push(tag);
+    out.extend_from_slice(&pc.to_le_bytes());
+    out.extend_from_slice(&extra.to_le_bytes());
+}
+
+
+fn read_fault(r: &mut Reader) -> Result<Option<Fault>, SaveStateError> {
+    let tag = r.u8()?;
+    let pc = r.u16()?;
+    let extra = r.u32()?;
+
+    Ok(match tag {
+        0 => None,
+        1 => Some(Fault::StackOverflow { pc }),
+        2 => Some(Fault::StackUnderflow { pc }),
+        3 => Some(Fault::MemoryOutOfBounds { pc, addr: extra as usize }),
+        4 => Some(Fault::PcOutOfBounds { pc }),
+        5 => Some(Fault::InvalidOpcode(DecodeError { opcode: extra as u16, addr: pc })),
+        _ => return Err(SaveStateError::Invalid("fault")),
+    })
+}
+
+
+pub(crate) fn platform_id(platform: Platform) -> u8 {
+    match platform {
+        Platform::Chip8 => 0,
+        Platform::SuperChip => 1,
+        Platform::XoChip => 2,
+    }
+}
+
+
+pub(crate) fn read_platform(r: &mut Reader) -> Result<Platform, SaveStateError> {
+    match r.u8()? {
+        0 => Ok(Platform::Chip8),
+        1 => Ok(Platform::SuperChip),
+        2 => Ok(Platform::XoChip),
+        _ => Err(SaveStateError::Invalid("platform")),
+    }
+}
+
+
+pub(crate) fn quirks_to_bits(q: &Quirks) -> u8 {
+    to_bits(&[q.shift_uses_vy, q.load_store_increments_i, q.jump_uses_vx,
+              q.logic_resets_vf, q.clip_sprites, q.draw_waits_for_vblank, q.wait_key_on_press]) as u8
+}
+
+
+pub(crate) fn quirks_from_bits(bits: u8) -> Quirks {
+    Quirks {
+        shift_uses_vy: bits & 1 != 0,
+        load_store_increments_i: bits & 2 != 0,
+        jump_uses_vx: bits & 4 != 0,
+        logic_resets_vf: bits & 8 != 0,
+        clip_sprites: bits & 16 != 0,
+        draw_waits_for_vblank: bits & 32 != 0,
+        wait_key_on_press: bits & 64 != 0,
+    }
+}
+
+
+fn to_bits(flags: &[bool]) -> u32 {
+    flags.iter().enumerate().fold(0, |bits, (i, on)| bits | ((*on as u32) << i))
+}
+
+
+/// 32 bit FNV-1a hash.
+pub(crate) fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811C_9DC5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
+
+
+pub(crate) struct Reader<'a> {
+    pub(crate) data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() < len {
+            return Err(SaveStateError::Truncated);
+        }
+
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, SaveStateError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::Program;
+
+    fn running_chip() -> Chip8 {
+        let platform = Platform::SuperChip;
+        let mut chip = Chip8::for_platform(platform, platform.quirks());
+        chip.seed_rng(7);
+
+        // hires, V0 = rand, V1 = 10, call 20A, draw the font sprite for 0, loop
+        chip.load_program(&Program::Text("00FF C0FF 610A 220A 1208 D015 00EE".to_string())).unwrap();
+        chip.set_key(0xA, true);
+        // stop inside the call, after the draw
+        chip.run_frame(5).unwrap();
+        chip
+    }
+
+    #[test]
+    fn round_trip() {
+        let chip = running_chip();
+        let data = chip.save_state();
+
+        let mut loaded = Chip8::load_state(&data).unwrap();
+        assert_eq!(loaded.save_state(), data);
+        assert_eq!(loaded.pc, chip.pc);
+        assert_eq!(loaded.sp, 1);
+        assert_eq!(loaded.framebuffer(), chip.framebuffer());
+        assert!(loaded.keyboard.key_pressed(0xA));
+
+        // and both keep running the same way
+        let mut chip = chip;
+        chip.run_frame(10).unwrap();
+        loaded.run_frame(10).unwrap();
+        assert_eq!(loaded.save_state(), chip.save_state());
+    }
+
+    #[test]
+    fn rejects_damaged_data() {
+        let mut data = running_chip().save_state();
+
+        assert_eq!(Chip8::load_state(b"PNG!").err(), Some(SaveStateError::BadMagic));
+        assert_eq!(Chip8::load_state(&data[..10]).err(), Some(SaveStateError::ChecksumMismatch));
+
+        data[100] ^= 1;
+        assert_eq!(Chip8::load_state(&data).err(), Some(SaveStateError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut data = running_chip().save_state();
+        data[4] = 99;
+
+        assert_eq!(Chip8::load_state(&data).err(), Some(SaveStateError::UnsupportedVersion(99)));
+    }
+
+    #[test]
+    fn latched_keys_are_let_go_after_loading() {
+        let mut chip = Chip8::with_quirks(Quirks::vip());
+        chip.load_program(&Program::Text("1200".to_string())).unwrap();
+
+        // tapped before the frame, latched while it runs
+        chip.set_key(0x3, true);
+        chip.set_key(0x3, false);
+        chip.run_frame(1).unwrap();
+
+        let mut loaded = Chip8::load_state(&chip.save_state()).unwrap();
+        assert!(loaded.keyboard.key_pressed(0x3));
+        assert_eq!(loaded.keyboard.keys_down(), 0);
+
+        loaded.run_frame(1).unwrap();
+        assert!(!loaded.keyboard.key_pressed(0x3));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let mut body = running_chip().save_state();
+        body.truncate(body.len() - 4);
+
+        // stack pointer, after header, platform, quirks, rng and memory and the stack
+        let sp = 6 + 1 + 1 + 8 + 0x1000 + 32;
+        body[sp] = 17;
+        let checksum = fnv1a(&body);
+        body.extend_from_slice(&checksum.to_le_bytes());
+
+        assert_eq!(Chip8::load_state(&body).err(), Some(SaveStateError::Invalid("stack pointer")));
+    }
+}